@@ -0,0 +1,5 @@
+pub mod oauth;
+pub mod sasl;
+#[cfg(test)]
+mod tests;
+pub mod tokens;
@@ -0,0 +1,417 @@
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chrono::{DateTime, Duration, Utc};
+use reqwest::Url;
+use serde::{de::DeserializeOwned, Deserialize};
+use sha2::{Digest, Sha256};
+use std::{
+    io::{BufRead, BufReader, Write},
+    net::TcpListener,
+    path::Path,
+    thread,
+};
+
+use super::{
+    sasl::OAuthMechanism,
+    tokens::{OAuthToken, TokenCache, CACHE_LOCK},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OAuthProvider {
+    Google,
+    Microsoft,
+}
+
+/// How the user grants access the first time.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OAuthFlow {
+    /// Enter a code on another device, nothing has to listen locally.
+    Device,
+    /// Log in with the browser, which redirects back to a one-off listener on 127.0.0.1.
+    Loopback,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct OAuth2Config {
+    /// Fills in the endpoints, scopes and flow below.
+    pub provider: Option<OAuthProvider>,
+    pub client_id: String,
+    pub client_secret: Option<String>,
+    /// Microsoft tenant, defaults to `common`.
+    pub tenant: Option<String>,
+    pub authorization_url: Option<String>,
+    pub device_authorization_url: Option<String>,
+    pub token_url: Option<String>,
+    pub scopes: Option<Vec<String>>,
+    pub flow: Option<OAuthFlow>,
+    #[serde(default)]
+    pub mechanism: OAuthMechanism,
+}
+
+/// Endpoints, scopes and flow with the provider defaults applied.
+#[derive(Debug, Clone, PartialEq)]
+pub struct OAuthEndpoints {
+    pub authorization_url: Option<String>,
+    pub device_authorization_url: Option<String>,
+    pub token_url: String,
+    pub scopes: Vec<String>,
+    pub flow: OAuthFlow,
+}
+
+impl OAuth2Config {
+    pub fn endpoints(&self) -> Result<OAuthEndpoints, String> {
+        let defaults = match self.provider {
+            // NOTE: Google does not grant the mail scope to the device flow
+            Some(OAuthProvider::Google) => Some(OAuthEndpoints {
+                authorization_url: Some("https://accounts.google.com/o/oauth2/v2/auth".to_string()),
+                device_authorization_url: Some(
+                    "https://oauth2.googleapis.com/device/code".to_string(),
+                ),
+                token_url: "https://oauth2.googleapis.com/token".to_string(),
+                scopes: vec!["https://mail.google.com/".to_string()],
+                flow: OAuthFlow::Loopback,
+            }),
+            Some(OAuthProvider::Microsoft) => {
+                let base = format!(
+                    "https://login.microsoftonline.com/{}/oauth2/v2.0",
+                    self.tenant.as_deref().unwrap_or("common")
+                );
+                Some(OAuthEndpoints {
+                    authorization_url: Some(format!("{}/authorize", base)),
+                    device_authorization_url: Some(format!("{}/devicecode", base)),
+                    token_url: format!("{}/token", base),
+                    scopes: [
+                        "https://outlook.office.com/IMAP.AccessAsUser.All",
+                        "https://outlook.office.com/SMTP.Send",
+                        "offline_access",
+                    ]
+                    .iter()
+                    .map(|scope| scope.to_string())
+                    .collect(),
+                    flow: OAuthFlow::Device,
+                })
+            }
+            None => None,
+        };
+        let token_url = self
+            .token_url
+            .clone()
+            .or_else(|| defaults.as_ref().map(|d| d.token_url.clone()))
+            .ok_or("oauth2 needs a provider or a token_url")?;
+        let authorization_url = self
+            .authorization_url
+            .clone()
+            .or_else(|| defaults.as_ref()?.authorization_url.clone());
+        let device_authorization_url = self
+            .device_authorization_url
+            .clone()
+            .or_else(|| defaults.as_ref()?.device_authorization_url.clone());
+        let flow = self.flow.or(defaults.as_ref().map(|d| d.flow)).unwrap_or(
+            match device_authorization_url {
+                Some(_) => OAuthFlow::Device,
+                None => OAuthFlow::Loopback,
+            },
+        );
+        match flow {
+            OAuthFlow::Device if device_authorization_url.is_none() => {
+                return Err("the device flow needs a device_authorization_url".to_string())
+            }
+            OAuthFlow::Loopback if authorization_url.is_none() => {
+                return Err("the loopback flow needs an authorization_url".to_string())
+            }
+            _ => {}
+        }
+        Ok(OAuthEndpoints {
+            authorization_url,
+            device_authorization_url,
+            token_url,
+            scopes: self
+                .scopes
+                .clone()
+                .or_else(|| defaults.map(|d| d.scopes))
+                .unwrap_or_default(),
+            flow,
+        })
+    }
+}
+
+/// Successful and failed token endpoint responses alike (RFC 6749, section 5).
+#[derive(Debug, Deserialize)]
+struct TokenResponse {
+    access_token: Option<String>,
+    refresh_token: Option<String>,
+    expires_in: Option<i64>,
+    error: Option<String>,
+    error_description: Option<String>,
+}
+
+impl TokenResponse {
+    fn error_message(&self) -> String {
+        format!(
+            "{}{}",
+            self.error
+                .as_deref()
+                .unwrap_or("no access token in the response"),
+            self.error_description
+                .as_ref()
+                .map(|description| format!(": {}", description))
+                .unwrap_or_default()
+        )
+    }
+
+    fn into_token(self, now: DateTime<Utc>) -> Result<OAuthToken, String> {
+        let error = self.error_message();
+        Ok(OAuthToken {
+            access_token: self.access_token.ok_or(error)?,
+            refresh_token: self.refresh_token,
+            expires_at: now + Duration::seconds(self.expires_in.unwrap_or(3600)),
+        })
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct DeviceAuthorization {
+    device_code: String,
+    user_code: String,
+    /// Google calls it `verification_url`.
+    #[serde(alias = "verification_url")]
+    verification_uri: String,
+    expires_in: i64,
+    interval: Option<u64>,
+    message: Option<String>,
+}
+
+/// POST a form and decode the JSON answer, whatever the status code.
+fn post_form<T: DeserializeOwned + Send + 'static>(
+    url: &str,
+    params: &[(&str, &str)],
+) -> Result<T, String> {
+    let url = url.to_string();
+    let params: Vec<(String, String)> = params
+        .iter()
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .collect();
+    // NOTE: reqwest's blocking client panics on a tokio worker thread, which is where the
+    // commands run, so the request gets a thread of its own
+    thread::spawn(move || {
+        reqwest::blocking::Client::new()
+            .post(&url)
+            .form(&params)
+            .send()
+            .and_then(|response| response.json::<T>())
+            .map_err(|e| format!("Request to {} failed: {}", url, e))
+    })
+    .join()
+    .map_err(|_| "Token request thread panicked".to_string())?
+}
+
+fn with_secret<'a>(
+    mut params: Vec<(&'a str, &'a str)>,
+    config: &'a OAuth2Config,
+) -> Vec<(&'a str, &'a str)> {
+    if let Some(secret) = &config.client_secret {
+        params.push(("client_secret", secret));
+    }
+    params
+}
+
+/// Trade a refresh token for a new access token. Providers that do not rotate refresh tokens
+/// keep the old one.
+pub fn refresh(config: &OAuth2Config, refresh_token: &str) -> Result<OAuthToken, String> {
+    let endpoints = config.endpoints()?;
+    let params = with_secret(
+        vec![
+            ("grant_type", "refresh_token"),
+            ("refresh_token", refresh_token),
+            ("client_id", &config.client_id),
+        ],
+        config,
+    );
+    let mut token = post_form::<TokenResponse>(&endpoints.token_url, &params)?
+        .into_token(Utc::now())
+        .map_err(|e| format!("Could not refresh the access token: {}", e))?;
+    if token.refresh_token.is_none() {
+        token.refresh_token = Some(refresh_token.to_string());
+    }
+    Ok(token)
+}
+
+/// RFC 8628: show a code to enter on another device and poll until the user has done so.
+pub fn device_flow(config: &OAuth2Config) -> Result<OAuthToken, String> {
+    let endpoints = config.endpoints()?;
+    let device_url = endpoints
+        .device_authorization_url
+        .as_deref()
+        .ok_or("no device_authorization_url")?;
+    let scope = endpoints.scopes.join(" ");
+    let params = with_secret(
+        vec![("client_id", config.client_id.as_str()), ("scope", &scope)],
+        config,
+    );
+    let authorization = post_form::<DeviceAuthorization>(device_url, &params)?;
+    println!(
+        "{}",
+        authorization.message.clone().unwrap_or_else(|| format!(
+            "To sign in, open {} and enter the code {}",
+            authorization.verification_uri, authorization.user_code
+        ))
+    );
+    let deadline = Utc::now() + Duration::seconds(authorization.expires_in);
+    let mut interval = authorization.interval.unwrap_or(5);
+    let params = with_secret(
+        vec![
+            ("grant_type", "urn:ietf:params:oauth:grant-type:device_code"),
+            ("device_code", &authorization.device_code),
+            ("client_id", &config.client_id),
+        ],
+        config,
+    );
+    while Utc::now() < deadline {
+        thread::sleep(std::time::Duration::from_secs(interval));
+        let response = post_form::<TokenResponse>(&endpoints.token_url, &params)?;
+        match response.error.as_deref() {
+            Some("authorization_pending") => continue,
+            Some("slow_down") => interval += 5,
+            _ => return response.into_token(Utc::now()),
+        }
+    }
+    Err("The device code expired before the sign-in was completed".to_string())
+}
+
+fn random_token(bytes: usize) -> String {
+    let random: Vec<u8> = (0..bytes).map(|_| rand::random::<u8>()).collect();
+    URL_SAFE_NO_PAD.encode(random)
+}
+
+/// Accept redirects on `listener` until one carries the authorization code for `state`.
+pub fn wait_for_redirect(listener: &TcpListener, state: &str) -> Result<String, String> {
+    loop {
+        let (mut stream, _) = listener.accept().map_err(|e| e.to_string())?;
+        let mut request_line = String::new();
+        BufReader::new(&stream)
+            .read_line(&mut request_line)
+            .map_err(|e| e.to_string())?;
+        let path = request_line.split(' ').nth(1).unwrap_or("/");
+        let url = Url::parse(&format!("http://127.0.0.1{}", path)).map_err(|e| e.to_string())?;
+        let query = |key: &str| {
+            url.query_pairs()
+                .find(|(name, _)| name == key)
+                .map(|(_, value)| value.to_string())
+        };
+        // NOTE: Browsers also ask for /favicon.ico and the like
+        let result = match (query("code"), query("error")) {
+            (_, Some(error)) => Err(format!("Authorization failed: {}", error)),
+            (Some(_), _) if query("state").as_deref() != Some(state) => {
+                Err("Authorization response has the wrong state".to_string())
+            }
+            (Some(code), _) => Ok(code),
+            (None, None) => {
+                let _ = stream.write_all(b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n");
+                continue;
+            }
+        };
+        let body = match &result {
+            Ok(_) => "antworker is authorized, you can close this tab.",
+            Err(_) => "antworker was not authorized, see the terminal.",
+        };
+        let _ = stream.write_all(
+            format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: text/plain; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            )
+            .as_bytes(),
+        );
+        return result;
+    }
+}
+
+/// RFC 8252: authorize in the browser, which redirects back to 127.0.0.1, using PKCE.
+pub fn loopback_flow(config: &OAuth2Config) -> Result<OAuthToken, String> {
+    let endpoints = config.endpoints()?;
+    let authorization_url = endpoints
+        .authorization_url
+        .as_deref()
+        .ok_or("no authorization_url")?;
+    let listener = TcpListener::bind("127.0.0.1:0").map_err(|e| e.to_string())?;
+    let redirect_uri = format!(
+        "http://127.0.0.1:{}/",
+        listener.local_addr().map_err(|e| e.to_string())?.port()
+    );
+    let verifier = random_token(32);
+    let challenge = URL_SAFE_NO_PAD.encode(Sha256::digest(verifier.as_bytes()));
+    let state = random_token(16);
+    let url = Url::parse_with_params(
+        authorization_url,
+        &[
+            ("client_id", config.client_id.as_str()),
+            ("response_type", "code"),
+            ("redirect_uri", &redirect_uri),
+            ("scope", &endpoints.scopes.join(" ")),
+            ("code_challenge", &challenge),
+            ("code_challenge_method", "S256"),
+            ("state", &state),
+            // NOTE: Google only hands out a refresh token with these two
+            ("access_type", "offline"),
+            ("prompt", "consent"),
+        ],
+    )
+    .map_err(|e| e.to_string())?;
+    println!("Open this URL in a browser to sign in:\n{}", url);
+    let code = wait_for_redirect(&listener, &state)?;
+    let params = with_secret(
+        vec![
+            ("grant_type", "authorization_code"),
+            ("code", &code),
+            ("redirect_uri", &redirect_uri),
+            ("client_id", &config.client_id),
+            ("code_verifier", &verifier),
+        ],
+        config,
+    );
+    post_form::<TokenResponse>(&endpoints.token_url, &params)?.into_token(Utc::now())
+}
+
+/// Run the account's interactive flow and remember the tokens.
+pub fn login(account: &str, config: &OAuth2Config, cache_path: &Path) -> Result<(), String> {
+    let token = match config.endpoints()?.flow {
+        OAuthFlow::Device => device_flow(config)?,
+        OAuthFlow::Loopback => loopback_flow(config)?,
+    };
+    let _lock = CACHE_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let mut cache = TokenCache::load(cache_path).map_err(|e| e.to_string())?;
+    cache.insert(account, token);
+    cache.save().map_err(|e| e.to_string())
+}
+
+/// A usable access token for the account, refreshed and cached when the stored one expired.
+pub fn access_token(
+    account: &str,
+    config: &OAuth2Config,
+    cache_path: &Path,
+) -> Result<String, String> {
+    // NOTE: Under the lock a second caller sees the refreshed token instead of refreshing again
+    let _lock = CACHE_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let mut cache = TokenCache::load(cache_path).map_err(|e| e.to_string())?;
+    let token = cache.get(account).cloned().ok_or_else(|| {
+        format!(
+            "No OAuth2 token for account '{}', run `antworker login {}` first",
+            account, account
+        )
+    })?;
+    if token.is_fresh(Utc::now()) {
+        return Ok(token.access_token);
+    }
+    let refresh_token = token.refresh_token.ok_or_else(|| {
+        format!(
+            "The OAuth2 token of account '{}' expired, run `antworker login {}` again",
+            account, account
+        )
+    })?;
+    let token = refresh(config, &refresh_token)?;
+    cache.insert(account, token.clone());
+    cache.save().map_err(|e| e.to_string())?;
+    Ok(token.access_token)
+}
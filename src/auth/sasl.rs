@@ -0,0 +1,62 @@
+use imap::Authenticator;
+use serde::Deserialize;
+
+/// SASL mechanism used to present an OAuth2 access token.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OAuthMechanism {
+    /// Google's and Microsoft's `XOAUTH2`.
+    #[default]
+    Xoauth2,
+    /// The standardised `OAUTHBEARER` (RFC 7628).
+    Oauthbearer,
+}
+
+impl OAuthMechanism {
+    pub fn name(&self) -> &'static str {
+        match self {
+            OAuthMechanism::Xoauth2 => "XOAUTH2",
+            OAuthMechanism::Oauthbearer => "OAUTHBEARER",
+        }
+    }
+}
+
+/// Initial client response for `AUTHENTICATE`, before base64 encoding.
+pub fn initial_response(
+    mechanism: OAuthMechanism,
+    user: &str,
+    access_token: &str,
+    host: &str,
+    port: u16,
+) -> String {
+    match mechanism {
+        OAuthMechanism::Xoauth2 => format!("user={}\x01auth=Bearer {}\x01\x01", user, access_token),
+        OAuthMechanism::Oauthbearer => format!(
+            "n,a={},\x01host={}\x01port={}\x01auth=Bearer {}\x01\x01",
+            user.replace('=', "=3D").replace(',', "=2C"),
+            host,
+            port,
+            access_token
+        ),
+    }
+}
+
+pub struct OAuthAuthenticator {
+    pub response: String,
+}
+
+impl Authenticator for OAuthAuthenticator {
+    type Response = String;
+
+    fn process(&self, challenge: &[u8]) -> Self::Response {
+        match challenge.is_empty() {
+            true => self.response.clone(),
+            // NOTE: A challenge after the response carries the error, an empty reply (or `\x01`
+            // for OAUTHBEARER) makes the server finish with a tagged NO
+            false => match self.response.starts_with("n,") {
+                true => "\x01".to_string(),
+                false => String::new(),
+            },
+        }
+    }
+}
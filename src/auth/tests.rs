@@ -0,0 +1,274 @@
+use chrono::{Duration, Utc};
+use imap::Authenticator;
+use mockito::Matcher;
+use std::{
+    fs,
+    io::{Read, Write},
+    net::{TcpListener, TcpStream},
+    thread,
+};
+use tempfile::tempdir;
+
+use crate::auth::{
+    oauth::{access_token, device_flow, wait_for_redirect, OAuth2Config, OAuthFlow},
+    sasl::{initial_response, OAuthAuthenticator, OAuthMechanism},
+    tokens::{OAuthToken, TokenCache},
+};
+
+fn custom_config(server_url: &str) -> OAuth2Config {
+    toml::from_str(&format!(
+        "client_id = \"antworker\"\n\
+         token_url = \"{0}/token\"\n\
+         device_authorization_url = \"{0}/device\"\n\
+         scopes = [\"mail\"]",
+        server_url
+    ))
+    .unwrap()
+}
+
+#[test]
+fn test_initial_response() {
+    assert_eq!(
+        initial_response(
+            OAuthMechanism::Xoauth2,
+            "me@gmail.com",
+            "ya29.token",
+            "imap.gmail.com",
+            993
+        ),
+        "user=me@gmail.com\x01auth=Bearer ya29.token\x01\x01"
+    );
+    assert_eq!(
+        initial_response(
+            OAuthMechanism::Oauthbearer,
+            "me@company.com",
+            "token",
+            "outlook.office365.com",
+            993
+        ),
+        "n,a=me@company.com,\x01host=outlook.office365.com\x01port=993\x01auth=Bearer token\x01\x01"
+    );
+}
+
+#[test]
+fn test_authenticator_error_challenge() {
+    let authenticator = OAuthAuthenticator {
+        response: "user=me@gmail.com\x01auth=Bearer expired\x01\x01".to_string(),
+    };
+    assert_eq!(authenticator.process(b""), authenticator.response);
+    assert_eq!(authenticator.process(b"{\"status\":\"401\"}"), "");
+}
+
+#[test]
+fn test_provider_endpoints() {
+    let google: OAuth2Config =
+        toml::from_str("provider = \"google\"\nclient_id = \"id\"\nclient_secret = \"secret\"")
+            .unwrap();
+    let endpoints = google.endpoints().unwrap();
+    assert_eq!(endpoints.flow, OAuthFlow::Loopback);
+    assert_eq!(endpoints.scopes, vec!["https://mail.google.com/"]);
+
+    let microsoft: OAuth2Config =
+        toml::from_str("provider = \"microsoft\"\nclient_id = \"id\"\ntenant = \"contoso\"")
+            .unwrap();
+    let endpoints = microsoft.endpoints().unwrap();
+    assert_eq!(endpoints.flow, OAuthFlow::Device);
+    assert_eq!(
+        endpoints.token_url,
+        "https://login.microsoftonline.com/contoso/oauth2/v2.0/token"
+    );
+    assert!(endpoints.scopes.contains(&"offline_access".to_string()));
+}
+
+#[test]
+fn test_invalid_endpoints() {
+    let no_token_url: OAuth2Config = toml::from_str("client_id = \"id\"").unwrap();
+    assert!(no_token_url.endpoints().is_err());
+    let device_without_url: OAuth2Config =
+        toml::from_str("client_id = \"id\"\ntoken_url = \"https://idp/token\"\nflow = \"device\"")
+            .unwrap();
+    assert!(device_without_url.endpoints().is_err());
+}
+
+#[test]
+fn test_token_cache() {
+    let temp_dir = tempdir().unwrap();
+    let path = temp_dir.path().join("antworker/tokens.json");
+    let token = OAuthToken {
+        access_token: "access".to_string(),
+        refresh_token: Some("refresh".to_string()),
+        expires_at: Utc::now() + Duration::hours(1),
+    };
+    let mut cache = TokenCache::load(&path).unwrap();
+    assert!(cache.get("gmail").is_none());
+    cache.insert("gmail", token.clone());
+    cache.save().unwrap();
+    assert_eq!(TokenCache::load(&path).unwrap().get("gmail"), Some(&token));
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+}
+
+#[test]
+fn test_access_token_refresh() {
+    let mut server = mockito::Server::new();
+    let refresh = server
+        .mock("POST", "/token")
+        .match_body(Matcher::AllOf(vec![
+            Matcher::UrlEncoded("grant_type".into(), "refresh_token".into()),
+            Matcher::UrlEncoded("refresh_token".into(), "refresh".into()),
+            Matcher::UrlEncoded("client_id".into(), "antworker".into()),
+        ]))
+        .with_header("content-type", "application/json")
+        .with_body(r#"{"access_token":"new-access","expires_in":3600,"token_type":"Bearer"}"#)
+        .expect(1)
+        .create();
+    let config = custom_config(&server.url());
+    let temp_dir = tempdir().unwrap();
+    let path = temp_dir.path().join("tokens.json");
+    let mut cache = TokenCache::load(&path).unwrap();
+    cache.insert(
+        "work",
+        OAuthToken {
+            access_token: "old-access".to_string(),
+            refresh_token: Some("refresh".to_string()),
+            expires_at: Utc::now() - Duration::minutes(5),
+        },
+    );
+    cache.save().unwrap();
+
+    assert_eq!(access_token("work", &config, &path).unwrap(), "new-access");
+    // NOTE: The refreshed token is cached, the second call does not hit the server
+    assert_eq!(access_token("work", &config, &path).unwrap(), "new-access");
+    refresh.assert();
+    let cached = TokenCache::load(&path)
+        .unwrap()
+        .get("work")
+        .cloned()
+        .unwrap();
+    assert_eq!(cached.refresh_token.as_deref(), Some("refresh"));
+
+    let error = access_token("other", &config, &path).unwrap_err();
+    assert!(error.contains("antworker login other"), "{}", error);
+}
+
+#[test]
+fn test_access_token_concurrent_refresh() {
+    let mut server = mockito::Server::new();
+    let refresh = server
+        .mock("POST", "/token")
+        .with_header("content-type", "application/json")
+        .with_body(r#"{"access_token":"new-access","expires_in":3600,"token_type":"Bearer"}"#)
+        .expect(4)
+        .create();
+    let config = custom_config(&server.url());
+    let temp_dir = tempdir().unwrap();
+    let path = temp_dir.path().join("tokens.json");
+    let accounts = ["a", "b", "c", "d"];
+    let mut cache = TokenCache::load(&path).unwrap();
+    for account in accounts {
+        cache.insert(
+            account,
+            OAuthToken {
+                access_token: "old-access".to_string(),
+                refresh_token: Some("refresh".to_string()),
+                expires_at: Utc::now() - Duration::minutes(5),
+            },
+        );
+    }
+    cache.save().unwrap();
+
+    thread::scope(|scope| {
+        for account in accounts {
+            let (config, path) = (&config, &path);
+            scope.spawn(move || access_token(account, config, path).unwrap());
+        }
+    });
+    refresh.assert();
+    // NOTE: Every refresh survives, none of the saves overwrote another account's token
+    let cache = TokenCache::load(&path).unwrap();
+    for account in accounts {
+        assert_eq!(cache.get(account).unwrap().access_token, "new-access");
+    }
+    assert_eq!(fs::read_dir(temp_dir.path()).unwrap().count(), 1);
+}
+
+#[test]
+fn test_access_token_refresh_rejected() {
+    let mut server = mockito::Server::new();
+    server
+        .mock("POST", "/token")
+        .with_status(400)
+        .with_header("content-type", "application/json")
+        .with_body(r#"{"error":"invalid_grant","error_description":"Token has been revoked."}"#)
+        .create();
+    let config = custom_config(&server.url());
+    let temp_dir = tempdir().unwrap();
+    let path = temp_dir.path().join("tokens.json");
+    let mut cache = TokenCache::load(&path).unwrap();
+    cache.insert(
+        "work",
+        OAuthToken {
+            access_token: "old-access".to_string(),
+            refresh_token: Some("revoked".to_string()),
+            expires_at: Utc::now(),
+        },
+    );
+    cache.save().unwrap();
+    let error = access_token("work", &config, &path).unwrap_err();
+    assert!(
+        error.contains("invalid_grant: Token has been revoked."),
+        "{}",
+        error
+    );
+}
+
+#[test]
+fn test_device_flow() {
+    let mut server = mockito::Server::new();
+    server
+        .mock("POST", "/device")
+        .match_body(Matcher::UrlEncoded("scope".into(), "mail".into()))
+        .with_header("content-type", "application/json")
+        .with_body(
+            r#"{"device_code":"device","user_code":"ABCD-EFGH","verification_uri":"https://idp/device","expires_in":900,"interval":0}"#,
+        )
+        .create();
+    server
+        .mock("POST", "/token")
+        .match_body(Matcher::UrlEncoded("device_code".into(), "device".into()))
+        .with_header("content-type", "application/json")
+        .with_body(r#"{"access_token":"access","refresh_token":"refresh","expires_in":3600}"#)
+        .create();
+    let token = device_flow(&custom_config(&server.url())).unwrap();
+    assert_eq!(token.access_token, "access");
+    assert_eq!(token.refresh_token.as_deref(), Some("refresh"));
+    assert!(token.is_fresh(Utc::now()));
+}
+
+fn browse(port: u16, path: &str) -> String {
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    write!(stream, "GET {} HTTP/1.1\r\nHost: 127.0.0.1\r\n\r\n", path).unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    response
+}
+
+#[test]
+fn test_wait_for_redirect() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let browser = thread::spawn(move || {
+        assert!(browse(port, "/favicon.ico").starts_with("HTTP/1.1 404"));
+        browse(port, "/?state=xyz&code=4%2F0Ab")
+    });
+    assert_eq!(wait_for_redirect(&listener, "xyz").unwrap(), "4/0Ab");
+    assert!(browser.join().unwrap().contains("you can close this tab"));
+
+    let browser = thread::spawn(move || browse(port, "/?state=forged&code=stolen"));
+    assert!(wait_for_redirect(&listener, "xyz").is_err());
+    browser.join().unwrap();
+}
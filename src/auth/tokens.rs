@@ -0,0 +1,97 @@
+use chrono::{DateTime, Duration, Utc};
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    env::var,
+    fs::{self, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use crate::io::writer::unique_temp_path;
+
+lazy_static! {
+    pub static ref TOKENS_PATH: String = var("ANTWORKER_TOKENS_PATH").unwrap_or_else(|_| {
+        format!(
+            "{}/.config/antworker/tokens.json",
+            var("HOME").expect("HOME must be set.")
+        )
+    });
+    /// Held from loading the cache to saving it, so accounts refreshing at the same time
+    /// do not drop each other's tokens.
+    pub static ref CACHE_LOCK: Mutex<()> = Mutex::new(());
+}
+
+/// Tokens are refreshed this long before they expire, so a long IMAP session does not
+/// start with a token about to run out.
+const EXPIRY_MARGIN: Duration = Duration::seconds(60);
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct OAuthToken {
+    pub access_token: String,
+    pub refresh_token: Option<String>,
+    pub expires_at: DateTime<Utc>,
+}
+
+impl OAuthToken {
+    pub fn is_fresh(&self, now: DateTime<Utc>) -> bool {
+        now + EXPIRY_MARGIN < self.expires_at
+    }
+}
+
+/// OAuth2 tokens of every account, keyed by account name.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct TokenCache {
+    #[serde(skip)]
+    path: PathBuf,
+    accounts: BTreeMap<String, OAuthToken>,
+}
+
+impl TokenCache {
+    pub fn load(path: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut cache = match fs::read_to_string(path) {
+            Ok(content) => serde_json::from_str::<TokenCache>(&content)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => TokenCache::default(),
+            Err(e) => return Err(e.into()),
+        };
+        cache.path = path.to_path_buf();
+        Ok(cache)
+    }
+
+    pub fn save(&self) -> Result<(), Box<dyn std::error::Error>> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let tmp_path = unique_temp_path(&self.path);
+        let mut options = OpenOptions::new();
+        options.write(true).create_new(true);
+        // NOTE: Refresh tokens are as good as a password, the file is never readable by others,
+        // not even before it is written
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        let content = serde_json::to_string_pretty(self)?;
+        options
+            .open(&tmp_path)?
+            .write_all(content.as_bytes())
+            .inspect_err(|_| {
+                let _ = fs::remove_file(&tmp_path);
+            })?;
+        fs::rename(&tmp_path, &self.path).inspect_err(|_| {
+            let _ = fs::remove_file(&tmp_path);
+        })?;
+        Ok(())
+    }
+
+    pub fn get(&self, account: &str) -> Option<&OAuthToken> {
+        self.accounts.get(account)
+    }
+
+    pub fn insert(&mut self, account: &str, token: OAuthToken) {
+        self.accounts.insert(account.to_string(), token);
+    }
+}
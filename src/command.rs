@@ -1 +1,4 @@
+pub mod dedupe;
+pub mod login;
 pub mod open;
+pub mod rules_test;
@@ -0,0 +1,41 @@
+use crate::{
+    error::AntworkerError,
+    io::{
+        dedupe::{find_duplicates, link_duplicate},
+        save_location::root_save_location_outcome_invoices,
+    },
+};
+use std::path::Path;
+
+/// Report files with identical content under the outcome invoices tree and, with `collapse`,
+/// hard-link every copy to the first one.
+pub fn dedupe_invoices(collapse: bool) -> Result<(), AntworkerError> {
+    let root = root_save_location_outcome_invoices()?;
+    let root = Path::new(&root);
+    let duplicates = find_duplicates(root).map_err(|e| AntworkerError::io(root, e))?;
+    if duplicates.is_empty() {
+        println!("✅ No duplicates in {}", root.display());
+        return Ok(());
+    }
+    let mut linked = 0;
+    for group in duplicates.iter() {
+        let Some((original, copies)) = group.split_first() else {
+            continue;
+        };
+        println!("🗂️  {}", original.display());
+        for copy in copies {
+            println!("    = {}", copy.display());
+            if collapse
+                && link_duplicate(original, copy).map_err(|e| AntworkerError::io(copy, e))?
+            {
+                linked += 1;
+            }
+        }
+    }
+    println!(
+        "Found {} groups of duplicates, {} files linked",
+        duplicates.len(),
+        linked
+    );
+    Ok(())
+}
@@ -0,0 +1,22 @@
+use std::path::Path;
+
+use crate::{
+    auth::{oauth::login, tokens::TOKENS_PATH},
+    config::accounts::{Config, CONFIG_PATH},
+};
+
+/// Sign in to an OAuth2 account interactively and store its tokens for later runs.
+pub fn login_account(account: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let config = Config::load(Path::new(CONFIG_PATH.as_str()))?;
+    let account_config = config
+        .accounts
+        .get(account)
+        .ok_or_else(|| format!("No account '{}' in {}", account, CONFIG_PATH.as_str()))?;
+    let oauth2 = account_config
+        .oauth2
+        .as_ref()
+        .ok_or_else(|| format!("Account '{}' has no [oauth2] section", account))?;
+    login(account, oauth2, Path::new(TOKENS_PATH.as_str()))?;
+    println!("✅ Signed in to {} as {}", account, account_config.login);
+    Ok(())
+}
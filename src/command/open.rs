@@ -1,17 +1,25 @@
-use crate::io::save_location::{
-    get_save_location_income_invoices, get_save_location_outcome_invoices,
-    ROOT_SAVE_LOCATION_INCOME_INVOICES, ROOT_SAVE_LOCATION_OUTCOME_INVOICES,
+use crate::{
+    datemath::clock::SystemClock,
+    error::AntworkerError,
+    io::save_location::{
+        get_save_location_income_invoices, get_save_location_outcome_invoices,
+        root_save_location_income_invoices, root_save_location_outcome_invoices,
+    },
 };
 use std::process::Command;
 
-pub fn open_save_location_invoices(year_month_or_year: &str, is_income: bool) {
+pub fn open_save_location_invoices(
+    year_month_or_year: &str,
+    is_income: bool,
+    clock: SystemClock,
+) -> Result<(), AntworkerError> {
     let dir_path = match is_income {
         true => {
             let dir_path = match year_month_or_year {
-                "" => get_save_location_income_invoices().to_string(),
+                "" => get_save_location_income_invoices(&clock)?,
                 _ => format!(
                     "{}/{}",
-                    ROOT_SAVE_LOCATION_INCOME_INVOICES.as_str(),
+                    root_save_location_income_invoices()?,
                     year_month_or_year
                 ),
             };
@@ -19,12 +27,14 @@ pub fn open_save_location_invoices(year_month_or_year: &str, is_income: bool) {
         }
         false => {
             let dir_path = match year_month_or_year {
-                "" => get_save_location_outcome_invoices().to_string(),
+                "" => get_save_location_outcome_invoices(&clock)?,
                 _ => {
-                    let year = year_month_or_year.split("_").next().unwrap();
+                    let year = year_month_or_year
+                        .split_once('_')
+                        .map_or(year_month_or_year, |(year, _)| year);
                     format!(
                         "{}/{}/{}",
-                        ROOT_SAVE_LOCATION_OUTCOME_INVOICES.as_str(),
+                        root_save_location_outcome_invoices()?,
                         year,
                         year_month_or_year
                     )
@@ -33,12 +43,13 @@ pub fn open_save_location_invoices(year_month_or_year: &str, is_income: bool) {
             dir_path
         }
     };
-    let status = Command::new("open")
-        .arg(dir_path.as_str())
-        .status()
-        .expect("Failed to open directory");
-
-    if !status.success() {
-        eprintln!("Failed to open directory");
+    let opened = Command::new("open").arg(dir_path.as_str()).status();
+    match opened {
+        Ok(status) if status.success() => Ok(()),
+        Ok(status) => Err(AntworkerError::io(
+            &dir_path,
+            format!("could not open the directory, open exited with {}", status),
+        )),
+        Err(e) => Err(AntworkerError::io(&dir_path, e)),
     }
 }
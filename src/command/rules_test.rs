@@ -0,0 +1,272 @@
+use chrono::{Duration, NaiveDate};
+use chrono_tz::Tz;
+use mailparse::{parse_mail, ParsedMail};
+use std::{
+    fmt::Display,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    config::accounts::{AttachmentsConfig, Config, CONFIG_PATH},
+    datemath::{
+        clock::{local, Clock, SystemClock},
+        period::Period,
+    },
+    email_parser::{
+        attachment::{plan_message, target_filename},
+        connection::connect,
+        mailbox::resolve_mailboxes,
+        mime::find_attachments,
+        offline::{MailArchive, IMPORT_ACCOUNT},
+        parser::{get_email_details_from_mail, EmailDetails},
+        source::MailSource,
+    },
+    rules::{
+        define::{define_rules, FilterRules},
+        file::{load_rules, RULES_PATH},
+        search::{compress_uid_set, date_range_query},
+    },
+};
+
+/// What a real run would do with one message.
+enum Verdict {
+    /// Matching rule and the path of every attachment that would be saved.
+    Saved(String, Vec<PathBuf>),
+    /// Why every rule failed.
+    NoRule(Vec<String>),
+}
+
+fn truncate(value: &str, width: usize) -> String {
+    match value.chars().count() > width {
+        true => format!("{}…", value.chars().take(width - 1).collect::<String>()),
+        false => value.to_string(),
+    }
+}
+
+/// Evaluate the rules against one message, `mail` is its parsed body if it was fetched.
+fn judge(
+    email: &EmailDetails,
+    account: &str,
+    mail: Option<&ParsedMail>,
+    rules: &FilterRules,
+    attachments: &AttachmentsConfig,
+) -> Result<Verdict, Box<dyn std::error::Error>> {
+    let Some(mail) = mail else {
+        return Ok(Verdict::NoRule(rules.why_none(&email.facts(account, None))));
+    };
+    let walk = find_attachments(mail);
+    let Some((action, values)) =
+        plan_message(email, account, &walk, rules, attachments.transliterate)
+    else {
+        let filenames = walk
+            .attachments
+            .iter()
+            .filter_map(|attachment| attachment.filename.clone())
+            .collect();
+        return Ok(Verdict::NoRule(
+            rules.why_none(&email.facts(account, Some(filenames))),
+        ));
+    };
+    let save_location = action.save_location(&values)?;
+    let mut paths = Vec::new();
+    for attachment in walk.attachments.iter() {
+        let filename = target_filename(
+            email,
+            attachment,
+            &action,
+            &values,
+            attachments.transliterate,
+        )?;
+        paths.push(save_location.join(filename));
+    }
+    Ok(Verdict::Saved(
+        format!("{} ({})", values.rule, action.category),
+        paths,
+    ))
+}
+
+fn print_row(email: &EmailDetails, verdict: &Verdict, timezone: Tz) {
+    println!(
+        "{:<10}  {:<32}  {:<40}  {}",
+        local(&email.date, timezone).format("%Y-%m-%d"),
+        truncate(email.from.first().map_or("", |from| from.as_str()), 32),
+        truncate(&email.subject, 40),
+        match verdict {
+            Verdict::Saved(rule, _) => format!("✅ {}", rule),
+            Verdict::NoRule(_) => "❌ no rule matched".to_string(),
+        }
+    );
+    match verdict {
+        Verdict::Saved(_, paths) if paths.is_empty() => println!("{:>12}no attachments", ""),
+        Verdict::Saved(_, paths) => {
+            for path in paths {
+                println!("{:>12}-> {}", "", path.display());
+            }
+        }
+        Verdict::NoRule(reasons) => {
+            for reason in reasons {
+                println!("{:>12}{}", "", reason);
+            }
+        }
+    }
+}
+
+/// A message the rules could not be tested against, the others still are.
+fn print_failure(item: &str, error: &dyn Display) {
+    println!("⚠️  {} could not be tested: {}", item, error);
+}
+
+fn in_range(email: &EmailDetails, from: NaiveDate, to: NaiveDate, timezone: Tz) -> bool {
+    (from..=to).contains(&local(&email.date, timezone).date_naive())
+}
+
+/// What a real import would do with one archived message, `None` when it was not sent between
+/// `from` and `to`.
+fn test_message(
+    content: &[u8],
+    uid: u32,
+    account: &str,
+    from: NaiveDate,
+    to: NaiveDate,
+    rules: &FilterRules,
+    attachments: &AttachmentsConfig,
+) -> Result<Option<(EmailDetails, Verdict)>, Box<dyn std::error::Error>> {
+    let mail = parse_mail(content)?;
+    let email = get_email_details_from_mail(&mail, uid)?;
+    if !in_range(&email, from, to, rules.timezone) {
+        return Ok(None);
+    }
+    let verdict = judge(&email, account, Some(&mail), rules, attachments)?;
+    Ok(Some((email, verdict)))
+}
+
+fn test_eml_dir(
+    dir: &Path,
+    account: &str,
+    from: NaiveDate,
+    to: NaiveDate,
+    rules: &FilterRules,
+    attachments: &AttachmentsConfig,
+) -> Result<(), Box<dyn std::error::Error>> {
+    println!("📂 {}", dir.display());
+    for (index, content) in MailArchive::detect(dir)?.messages()?.enumerate() {
+        // NOTE: Like `import`, a message that cannot be read, parsed or planned is reported and
+        // the rest are still tested
+        let uid = index as u32 + 1;
+        let tested = content
+            .map_err(Box::from)
+            .and_then(|content| test_message(&content, uid, account, from, to, rules, attachments));
+        match tested {
+            Ok(Some((email, verdict))) => print_row(&email, &verdict, rules.timezone),
+            Ok(None) => {}
+            Err(e) => print_failure(&format!("Message {}", uid), &e),
+        }
+    }
+    Ok(())
+}
+
+fn test_accounts(
+    config: &Config,
+    account: Option<&str>,
+    from: NaiveDate,
+    to: NaiveDate,
+    rules: &FilterRules,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let query = format!(
+        "{} {}",
+        rules.search_query("1:*"),
+        date_range_query(from, to + Duration::days(1))
+    );
+    if let Some(account) = account.filter(|account| !config.accounts.contains_key(*account)) {
+        return Err(format!("No account '{}' in {}", account, CONFIG_PATH.as_str()).into());
+    }
+    for (name, account_config) in config.accounts.iter() {
+        if account.is_some_and(|account| account != name) {
+            continue;
+        }
+        // NOTE: Only the accounts being checked need their password
+        let email_account = account_config.builder(name, config.secrets.as_ref())?;
+        let mut imap_session = connect(&email_account)?;
+        let source: &mut dyn MailSource = &mut imap_session;
+        for mailbox in resolve_mailboxes(source, &email_account.mailboxes)? {
+            println!("📬 {}/{}", name, mailbox.decoded);
+            // NOTE: EXAMINE and BODY.PEEK keep the mailbox read-only, no \Seen flags are set
+            source.examine(&mailbox.raw)?;
+            let uids = source.search(&query)?;
+            if uids.is_empty() {
+                continue;
+            }
+            // NOTE: Rules are judged below, so every message the search returns is kept
+            for (uid, details) in source.fetch_details(&compress_uid_set(&uids))? {
+                let email = match details {
+                    Ok(email) => email,
+                    Err(e) => {
+                        let item =
+                            uid.map_or("A message".to_string(), |uid| format!("UID {}", uid));
+                        print_failure(&item, &e);
+                        continue;
+                    }
+                };
+                if !in_range(&email, from, to, rules.timezone) {
+                    continue;
+                }
+                let facts = email.facts(name, None);
+                let verdict = match rules.may_match(&facts) {
+                    true => {
+                        let body = source.fetch_body(email.uid, true)?;
+                        let mail = parse_mail(&body)?;
+                        judge(&email, name, Some(&mail), rules, &config.attachments)?
+                    }
+                    false => judge(&email, name, None, rules, &config.attachments)?,
+                };
+                print_row(&email, &verdict, rules.timezone);
+            }
+        }
+        source.logout()?;
+    }
+    Ok(())
+}
+
+/// Show which rule every message between `from` and `to` would match and where its attachments
+/// would be saved, without saving anything. Defaults to the current month.
+pub fn test_rules(
+    from: Option<NaiveDate>,
+    to: Option<NaiveDate>,
+    eml_dir: Option<PathBuf>,
+    account: Option<String>,
+    clock: SystemClock,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let today = clock.today();
+    let from = from.unwrap_or_else(|| Period::of(today).first_day());
+    let to = to.unwrap_or(today);
+    if from > to {
+        return Err(format!("--from {} is after --to {}", from, to).into());
+    }
+    // NOTE: The date range is checked for every message below, so the rules take any date
+    let rules = define_rules(
+        &load_rules(Path::new(RULES_PATH.as_str()))?,
+        None,
+        clock.timezone(),
+    );
+    match eml_dir {
+        Some(dir) => {
+            // NOTE: Same as `import`, only a missing config file falls back to the defaults
+            let attachments = match Path::new(CONFIG_PATH.as_str()).exists() {
+                true => Config::load(Path::new(CONFIG_PATH.as_str()))?.attachments,
+                false => Default::default(),
+            };
+            test_eml_dir(
+                &dir,
+                account.as_deref().unwrap_or(IMPORT_ACCOUNT),
+                from,
+                to,
+                &rules,
+                &attachments,
+            )
+        }
+        None => {
+            let config = Config::load(Path::new(CONFIG_PATH.as_str()))?;
+            test_accounts(&config, account.as_deref(), from, to, &rules)
+        }
+    }
+}
@@ -0,0 +1,5 @@
+pub mod accounts;
+pub mod env;
+pub mod secrets;
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,278 @@
+use lazy_static::lazy_static;
+use serde::Deserialize;
+use std::{
+    collections::BTreeMap,
+    env::var,
+    fmt::{self, Display, Formatter},
+    fs,
+    path::{Path, PathBuf},
+};
+
+use super::secrets::{decrypt_command, PasswordSource, SecretsConfig};
+use crate::{
+    auth::oauth::OAuth2Config,
+    email_parser::{
+        actions::PostActions,
+        connection::{is_loopback, normalize_fingerprint, ConnectionSettings, Security},
+    },
+    factories::credentials::EmailAccountBuilder,
+    io::{dedupe::DuplicatePolicy, writer::CollisionPolicy},
+    retry::RetryPolicy,
+};
+
+lazy_static! {
+    pub static ref CONFIG_PATH: String = var("ANTWORKER_CONFIG").unwrap_or_else(|_| {
+        format!(
+            "{}/.config/antworker/config.toml",
+            var("HOME").expect("HOME must be set.")
+        )
+    });
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(String, std::io::Error),
+    Parse(String, toml::de::Error),
+    Invalid(String),
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Io(path, e) => write!(f, "Could not read config file {}: {}", path, e),
+            ConfigError::Parse(path, e) => write!(f, "Invalid config file {}: {}", path, e),
+            ConfigError::Invalid(msg) => write!(f, "Invalid config: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+const PASSWORD_OPTIONS: &str =
+    "password, password_env, password_command, password_file or password_secret";
+
+fn default_concurrency() -> usize {
+    4
+}
+
+fn default_mailboxes() -> Vec<String> {
+    vec!["INBOX".to_string()]
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AccountConfig {
+    pub server: String,
+    /// Defaults to 993 for `tls` and 143 otherwise.
+    pub port: Option<u16>,
+    #[serde(default)]
+    pub security: Security,
+    /// PEM file with certificate authorities to trust on top of the system ones.
+    pub ca_file: Option<PathBuf>,
+    /// SHA-256 fingerprint of the server certificate to accept, even when self-signed.
+    pub fingerprint: Option<String>,
+    pub login: String,
+    /// Password stored directly in the config file.
+    pub password: Option<String>,
+    /// Name of the environment variable holding the password.
+    pub password_env: Option<String>,
+    /// Command printing the password, e.g. `pass show mail/company`.
+    pub password_command: Option<String>,
+    /// File containing only the password, must not be accessible by other users.
+    pub password_file: Option<PathBuf>,
+    /// Name of the password in the encrypted `[secrets]` file.
+    pub password_secret: Option<String>,
+    /// Mailbox names or glob patterns such as `Faktury/*` or `[Gmail]/All Mail`.
+    #[serde(default = "default_mailboxes")]
+    pub mailboxes: Vec<String>,
+    /// Server-side actions applied to messages whose attachments were saved.
+    #[serde(default)]
+    pub after_save: PostActions,
+    /// Log in with OAuth2 instead of a password, see `antworker login`.
+    pub oauth2: Option<OAuth2Config>,
+}
+
+impl AccountConfig {
+    pub fn port(&self) -> u16 {
+        self.port.unwrap_or(self.security.default_port())
+    }
+
+    fn validate_connection(&self, name: &str) -> Result<(), ConfigError> {
+        let invalid = |msg: String| ConfigError::Invalid(format!("account '{}': {}", name, msg));
+        if self.security == Security::None {
+            if !is_loopback(&self.server) {
+                return Err(invalid(format!(
+                    "security = \"none\" is only allowed for localhost, not {}",
+                    self.server
+                )));
+            }
+            if self.ca_file.is_some() || self.fingerprint.is_some() {
+                return Err(invalid(
+                    "ca_file and fingerprint need security = \"tls\" or \"starttls\"".to_string(),
+                ));
+            }
+        }
+        if let Some(fingerprint) = &self.fingerprint {
+            if normalize_fingerprint(fingerprint).is_none() {
+                return Err(invalid(format!(
+                    "fingerprint '{}' is not a SHA-256 fingerprint",
+                    fingerprint
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Every password option set for the account, at most one is allowed.
+    pub fn password_sources(&self) -> Vec<PasswordSource<'_>> {
+        [
+            self.password.as_deref().map(PasswordSource::Plain),
+            self.password_env.as_deref().map(PasswordSource::Env),
+            self.password_command
+                .as_deref()
+                .map(PasswordSource::Command),
+            self.password_file.as_deref().map(PasswordSource::File),
+            self.password_secret.as_deref().map(PasswordSource::Secret),
+        ]
+        .into_iter()
+        .flatten()
+        .collect()
+    }
+
+    pub fn resolve_password(
+        &self,
+        name: &str,
+        secrets: Option<&SecretsConfig>,
+    ) -> Result<String, ConfigError> {
+        let invalid = |msg: String| ConfigError::Invalid(format!("account '{}': {}", name, msg));
+        match (self.password_sources().as_slice(), &self.oauth2) {
+            ([], Some(_)) => Ok(String::new()),
+            (_, Some(_)) => Err(invalid(
+                "password options cannot be used with oauth2".to_string(),
+            )),
+            ([source], None) => source.resolve(secrets).map_err(invalid),
+            ([], None) => Err(invalid(format!("one of {} must be set", PASSWORD_OPTIONS))),
+            (_, None) => Err(invalid(format!(
+                "only one of {} may be set",
+                PASSWORD_OPTIONS
+            ))),
+        }
+    }
+
+    /// Settings for a session with the account. The password is only looked up here, so
+    /// commands and encrypted files run for the accounts that are actually used.
+    pub fn builder(
+        &self,
+        name: &str,
+        secrets: Option<&SecretsConfig>,
+    ) -> Result<EmailAccountBuilder, ConfigError> {
+        let password = self.resolve_password(name, secrets)?;
+        let mut builder =
+            EmailAccountBuilder::new(&self.server, self.port(), &self.login, &password)
+                .account(name)
+                .mailboxes(&self.mailboxes)
+                .post_actions(&self.after_save)
+                .connection(&ConnectionSettings {
+                    security: self.security,
+                    ca_file: self.ca_file.clone(),
+                    fingerprint: self.fingerprint.clone(),
+                });
+        if let Some(oauth2) = &self.oauth2 {
+            builder = builder.oauth2(oauth2);
+        }
+        Ok(builder.build())
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AttachmentsConfig {
+    /// Replace Polish diacritics in saved filenames, e.g. `Faktura_Łódź.pdf` -> `Faktura_Lodz.pdf`.
+    #[serde(default)]
+    pub transliterate: bool,
+    /// What to do when a different file with the same name is already saved.
+    #[serde(default)]
+    pub on_collision: CollisionPolicy,
+    /// What to do with content that was already saved before, from any account or month.
+    #[serde(default)]
+    pub duplicates: DuplicatePolicy,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    #[serde(default)]
+    pub accounts: BTreeMap<String, AccountConfig>,
+    #[serde(default)]
+    pub attachments: AttachmentsConfig,
+    pub secrets: Option<SecretsConfig>,
+    /// How many accounts are processed at the same time.
+    #[serde(default = "default_concurrency")]
+    pub concurrency: usize,
+    /// How dropped IMAP connections and temporary SMTP failures are retried.
+    #[serde(default)]
+    pub retry: RetryPolicy,
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Config, ConfigError> {
+        let path_str = path.to_string_lossy().to_string();
+        let content = fs::read_to_string(path).map_err(|e| ConfigError::Io(path_str.clone(), e))?;
+        Config::parse(&content).map_err(|e| match e {
+            ConfigError::Parse(_, e) => ConfigError::Parse(path_str, e),
+            e => e,
+        })
+    }
+
+    pub fn parse(content: &str) -> Result<Config, ConfigError> {
+        let config: Config =
+            toml::from_str(content).map_err(|e| ConfigError::Parse(String::new(), e))?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        if self.concurrency == 0 {
+            return Err(ConfigError::Invalid(
+                "concurrency must be at least 1".to_string(),
+            ));
+        }
+        self.retry
+            .validate()
+            .map_err(|e| ConfigError::Invalid(format!("retry: {}", e)))?;
+        if let Some(secrets) = &self.secrets {
+            decrypt_command(secrets)
+                .map_err(|e| ConfigError::Invalid(format!("secrets: {}", e)))?;
+        }
+        if self.accounts.is_empty() {
+            return Err(ConfigError::Invalid(
+                "at least one [accounts.<name>] section is required".to_string(),
+            ));
+        }
+        for (name, account) in self.accounts.iter() {
+            if account.mailboxes.is_empty() {
+                return Err(ConfigError::Invalid(format!(
+                    "account '{}': mailboxes must not be empty",
+                    name
+                )));
+            }
+            account
+                .after_save
+                .validate()
+                .map_err(|e| ConfigError::Invalid(format!("account '{}': {}", name, e)))?;
+            account.validate_connection(name)?;
+            if account.password_secret.is_some() && self.secrets.is_none() {
+                return Err(ConfigError::Invalid(format!(
+                    "account '{}': password_secret needs a [secrets] section",
+                    name
+                )));
+            }
+            if let Some(oauth2) = &account.oauth2 {
+                oauth2.endpoints().map_err(|e| {
+                    ConfigError::Invalid(format!("account '{}': oauth2: {}", name, e))
+                })?;
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,8 @@
+use std::env::var;
+
+use super::accounts::ConfigError;
+
+/// The value of the environment variable `name`, an error naming it when it is not set.
+pub fn required_var(name: &str) -> Result<String, ConfigError> {
+    var(name).map_err(|_| ConfigError::Invalid(format!("{} must be set", name)))
+}
@@ -0,0 +1,155 @@
+use lazy_static::lazy_static;
+use serde::Deserialize;
+use std::{
+    collections::BTreeMap,
+    env::var,
+    fs,
+    path::{Path, PathBuf},
+    process::Command,
+    sync::Mutex,
+};
+
+lazy_static! {
+    /// Decrypted secrets files, so gpg or age runs at most once per file and run.
+    static ref DECRYPTED: Mutex<BTreeMap<PathBuf, BTreeMap<String, String>>> =
+        Mutex::new(BTreeMap::new());
+}
+
+/// An encrypted TOML file of `name = "password"` pairs.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SecretsConfig {
+    /// `.age` files are decrypted with age, `.gpg` and `.asc` files with gpg.
+    pub file: PathBuf,
+    /// age identity file, e.g. `~/.config/age/key.txt`.
+    pub identity: Option<PathBuf>,
+}
+
+/// Where an account's password comes from.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PasswordSource<'a> {
+    Plain(&'a str),
+    Env(&'a str),
+    /// Shell command whose first line of output is the password, e.g. `pass show mail/company`.
+    Command(&'a str),
+    /// File holding only the password, readable by its owner alone.
+    File(&'a Path),
+    /// Entry of the encrypted secrets file.
+    Secret(&'a str),
+}
+
+impl PasswordSource<'_> {
+    pub fn resolve(&self, secrets: Option<&SecretsConfig>) -> Result<String, String> {
+        match self {
+            PasswordSource::Plain(password) => Ok(password.to_string()),
+            PasswordSource::Env(name) => {
+                var(name).map_err(|_| format!("environment variable {} is not set", name))
+            }
+            PasswordSource::Command(command) => run_password_command(command),
+            PasswordSource::File(path) => read_password_file(path),
+            PasswordSource::Secret(name) => {
+                let secrets = secrets.ok_or(
+                    "password_secret needs a [secrets] section pointing at an encrypted file",
+                )?;
+                lookup_secret(secrets, name)
+            }
+        }
+    }
+}
+
+fn run_password_command(command: &str) -> Result<String, String> {
+    let output = Command::new("sh")
+        .args(["-c", command])
+        .output()
+        .map_err(|e| format!("could not run password_command: {}", e))?;
+    if !output.status.success() {
+        return Err(format!(
+            "password_command `{}` failed: {}",
+            command,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    // NOTE: pass and friends keep the password on the first line and metadata below
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .next()
+        .filter(|password| !password.is_empty())
+        .map(|password| password.to_string())
+        .ok_or_else(|| format!("password_command `{}` printed nothing", command))
+}
+
+fn read_password_file(path: &Path) -> Result<String, String> {
+    let metadata =
+        fs::metadata(path).map_err(|e| format!("could not read {}: {}", path.display(), e))?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = metadata.permissions().mode();
+        if mode & 0o077 != 0 {
+            return Err(format!(
+                "{} is accessible by other users (mode {:o}), run chmod 600 on it",
+                path.display(),
+                mode & 0o777
+            ));
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = metadata;
+    let content = fs::read_to_string(path)
+        .map_err(|e| format!("could not read {}: {}", path.display(), e))?;
+    Ok(content.trim_end_matches(['\r', '\n']).to_string())
+}
+
+/// The command printing the decrypted secrets file.
+pub fn decrypt_command(secrets: &SecretsConfig) -> Result<Command, String> {
+    let extension = secrets
+        .file
+        .extension()
+        .map(|extension| extension.to_string_lossy().to_lowercase());
+    match extension.as_deref() {
+        Some("age") => {
+            let mut command = Command::new("age");
+            command.arg("--decrypt");
+            if let Some(identity) = &secrets.identity {
+                command.arg("--identity").arg(identity);
+            }
+            command.arg(&secrets.file);
+            Ok(command)
+        }
+        Some("gpg") | Some("asc") => {
+            let mut command = Command::new("gpg");
+            command.args(["--quiet", "--decrypt"]).arg(&secrets.file);
+            Ok(command)
+        }
+        _ => Err(format!(
+            "{} should end in .age, .gpg or .asc",
+            secrets.file.display()
+        )),
+    }
+}
+
+pub fn parse_secrets(content: &str) -> Result<BTreeMap<String, String>, String> {
+    toml::from_str(content).map_err(|e| format!("decrypted secrets are not valid TOML: {}", e))
+}
+
+fn lookup_secret(secrets: &SecretsConfig, name: &str) -> Result<String, String> {
+    let mut decrypted = DECRYPTED.lock().unwrap_or_else(|e| e.into_inner());
+    if !decrypted.contains_key(&secrets.file) {
+        let output = decrypt_command(secrets)?
+            .output()
+            .map_err(|e| format!("could not decrypt {}: {}", secrets.file.display(), e))?;
+        if !output.status.success() {
+            return Err(format!(
+                "could not decrypt {}: {}",
+                secrets.file.display(),
+                String::from_utf8_lossy(&output.stderr).trim()
+            ));
+        }
+        let parsed = parse_secrets(&String::from_utf8_lossy(&output.stdout))?;
+        decrypted.insert(secrets.file.clone(), parsed);
+    }
+    decrypted[&secrets.file]
+        .get(name)
+        .cloned()
+        .ok_or_else(|| format!("no secret '{}' in {}", name, secrets.file.display()))
+}
@@ -0,0 +1,405 @@
+use std::fs;
+
+use tempfile::tempdir;
+
+use crate::{
+    config::{
+        accounts::{Config, ConfigError},
+        env::required_var,
+        secrets::{decrypt_command, parse_secrets, SecretsConfig},
+    },
+    email_parser::connection::Security,
+    io::{dedupe::DuplicatePolicy, writer::CollisionPolicy},
+    retry::RetryPolicy,
+};
+
+const CONFIG: &str = r#"
+[attachments]
+transliterate = true
+on_collision = "prefix_date"
+duplicates = "link"
+
+[accounts.company]
+server = "imap.company.com"
+login = "me@company.com"
+password = "secret"
+mailboxes = ["INBOX", "Faktury"]
+
+[accounts.company.after_save]
+flag = "$AntworkerSaved"
+mark_seen = true
+move_to = "Archiwum/Faktury"
+
+[accounts.gmail]
+server = "imap.gmail.com"
+port = 1993
+login = "me@gmail.com"
+password_env = "ANTWORKER_TEST_GMAIL_PASSWORD"
+"#;
+
+#[test]
+fn test_parse_accounts() {
+    let config = Config::parse(CONFIG).unwrap();
+    assert_eq!(config.accounts.len(), 2);
+    assert!(config.attachments.transliterate);
+    assert_eq!(config.attachments.on_collision, CollisionPolicy::PrefixDate);
+    assert_eq!(config.attachments.duplicates, DuplicatePolicy::Link);
+
+    let company = &config.accounts["company"];
+    assert_eq!(company.server, "imap.company.com");
+    assert_eq!(company.port(), 993);
+    assert_eq!(company.security, Security::Tls);
+    assert_eq!(company.mailboxes, vec!["INBOX", "Faktury"]);
+    assert_eq!(company.after_save.flag.as_deref(), Some("$AntworkerSaved"));
+    assert!(company.after_save.mark_seen);
+    assert_eq!(
+        company.after_save.move_to.as_deref(),
+        Some("Archiwum/Faktury")
+    );
+
+    let gmail = &config.accounts["gmail"];
+    assert_eq!(gmail.port(), 1993);
+    assert_eq!(gmail.mailboxes, vec!["INBOX"]);
+    assert!(gmail.after_save.is_empty());
+    assert_eq!(config.concurrency, 4);
+}
+
+#[test]
+fn test_concurrency() {
+    let config = Config::parse(&format!("concurrency = 8\n{}", CONFIG)).unwrap();
+    assert_eq!(config.concurrency, 8);
+    let result = Config::parse(&format!("concurrency = 0\n{}", CONFIG));
+    assert!(matches!(result, Err(ConfigError::Invalid(_))));
+}
+
+#[test]
+fn test_retry_policy() {
+    assert_eq!(Config::parse(CONFIG).unwrap().retry, RetryPolicy::default());
+    let config = Config::parse(&format!(
+        "{}\n[retry]\nmax_attempts = 6\njitter = false\n",
+        CONFIG
+    ))
+    .unwrap();
+    assert_eq!(config.retry.max_attempts, 6);
+    assert!(!config.retry.jitter);
+    assert_eq!(config.retry.initial_backoff_ms, 1000);
+    let result = Config::parse(&format!("{}\n[retry]\nmax_attempts = 0\n", CONFIG));
+    assert!(matches!(result, Err(ConfigError::Invalid(_))));
+}
+
+#[test]
+fn test_required_var() {
+    std::env::set_var("ANTWORKER_TEST_REQUIRED", "set");
+    assert_eq!(required_var("ANTWORKER_TEST_REQUIRED").unwrap(), "set");
+    let error = required_var("ANTWORKER_TEST_MISSING").unwrap_err();
+    assert!(matches!(error, ConfigError::Invalid(_)));
+    assert!(error
+        .to_string()
+        .contains("ANTWORKER_TEST_MISSING must be set"));
+}
+
+#[test]
+fn test_builder() {
+    std::env::set_var("ANTWORKER_TEST_GMAIL_PASSWORD", "gmail-secret");
+    let config = Config::parse(CONFIG).unwrap();
+
+    let company = config.accounts["company"].builder("company", None).unwrap();
+    assert_eq!(company.server, "imap.company.com");
+    assert_eq!(company.password, "secret");
+    assert_eq!(company.mailboxes, vec!["INBOX", "Faktury"]);
+
+    let gmail = config.accounts["gmail"].builder("gmail", None).unwrap();
+    assert_eq!(gmail.email, "me@gmail.com");
+    assert_eq!(gmail.password, "gmail-secret");
+}
+
+#[test]
+fn test_missing_password() {
+    let config = Config::parse(
+        r#"
+[accounts.company]
+server = "imap.company.com"
+login = "me@company.com"
+"#,
+    )
+    .unwrap();
+    let result = config.accounts["company"].builder("company", None);
+    assert!(matches!(result, Err(ConfigError::Invalid(_))));
+}
+
+#[test]
+fn test_no_accounts() {
+    assert!(matches!(Config::parse(""), Err(ConfigError::Invalid(_))));
+}
+
+#[test]
+fn test_unknown_field() {
+    let result = Config::parse(
+        r#"
+[accounts.company]
+server = "imap.company.com"
+login = "me@company.com"
+pasword = "typo"
+"#,
+    );
+    assert!(matches!(result, Err(ConfigError::Parse(_, _))));
+}
+
+#[test]
+fn test_load_missing_file() {
+    let temp_dir = tempdir().unwrap();
+    let result = Config::load(&temp_dir.path().join("config.toml"));
+    assert!(matches!(result, Err(ConfigError::Io(_, _))));
+}
+
+#[test]
+fn test_load() {
+    let temp_dir = tempdir().unwrap();
+    let path = temp_dir.path().join("config.toml");
+    fs::write(&path, CONFIG).unwrap();
+    let config = Config::load(&path).unwrap();
+    assert_eq!(config.accounts.len(), 2);
+}
+
+#[test]
+fn test_invalid_after_save_flag() {
+    let result = Config::parse(
+        r#"
+[accounts.company]
+server = "imap.company.com"
+login = "me@company.com"
+password = "secret"
+
+[accounts.company.after_save]
+flag = "not a keyword"
+"#,
+    );
+    assert!(matches!(result, Err(ConfigError::Invalid(_))));
+}
+
+#[test]
+fn test_default_attachments() {
+    let config = Config::parse(
+        r#"
+[accounts.company]
+server = "imap.company.com"
+login = "me@company.com"
+password = "secret"
+"#,
+    )
+    .unwrap();
+    assert!(!config.attachments.transliterate);
+    assert_eq!(config.attachments.on_collision, CollisionPolicy::Suffix);
+    assert_eq!(config.attachments.duplicates, DuplicatePolicy::Skip);
+}
+
+#[test]
+fn test_connection_security() {
+    let config = Config::parse(
+        r#"
+[accounts.bridge]
+server = "127.0.0.1"
+port = 1143
+security = "starttls"
+fingerprint = "90:52:CF:82:D2:4F:09:56:99:3F:30:F8:23:68:D8:98:49:C6:FD:FE:98:C6:BE:CF:02:73:FB:FE:22:79:26:8A"
+login = "me@proton.me"
+password = "bridge-secret"
+
+[accounts.davmail]
+server = "localhost"
+security = "none"
+login = "me@company.com"
+password = "secret"
+
+[accounts.company]
+server = "imap.company.com"
+security = "starttls"
+ca_file = "/etc/ssl/company-ca.pem"
+login = "me@company.com"
+password = "secret"
+"#,
+    )
+    .unwrap();
+    let bridge = config.accounts["bridge"].builder("bridge", None).unwrap();
+    assert_eq!(bridge.port, 1143);
+    assert_eq!(bridge.connection.security, Security::Starttls);
+    assert!(bridge.connection.fingerprint.is_some());
+
+    let davmail = config.accounts["davmail"].builder("davmail", None).unwrap();
+    assert_eq!(davmail.port, 143);
+    assert_eq!(davmail.connection.security, Security::None);
+
+    let company = config.accounts["company"].builder("company", None).unwrap();
+    assert_eq!(company.port, 143);
+    assert_eq!(
+        company.connection.ca_file.as_deref(),
+        Some(std::path::Path::new("/etc/ssl/company-ca.pem"))
+    );
+}
+
+#[test]
+fn test_invalid_connection_security() {
+    for account in [
+        // NOTE: Credentials in plaintext must never leave the machine
+        "server = \"imap.company.com\"\nsecurity = \"none\"",
+        "server = \"127.0.0.1\"\nsecurity = \"none\"\nca_file = \"ca.pem\"",
+        "server = \"127.0.0.1\"\nfingerprint = \"AB:CD\"",
+        "server = \"127.0.0.1\"\nsecurity = \"ssl\"",
+    ] {
+        let result = Config::parse(&format!(
+            "[accounts.company]\n{}\nlogin = \"me@company.com\"\npassword = \"secret\"\n",
+            account
+        ));
+        assert!(result.is_err(), "{} should be rejected", account);
+    }
+}
+
+#[test]
+fn test_oauth2_account() {
+    let config = Config::parse(
+        r#"
+[accounts.gmail]
+server = "imap.gmail.com"
+login = "me@gmail.com"
+
+[accounts.gmail.oauth2]
+provider = "google"
+client_id = "123.apps.googleusercontent.com"
+client_secret = "secret"
+"#,
+    )
+    .unwrap();
+    let gmail = config.accounts["gmail"].builder("gmail", None).unwrap();
+    assert_eq!(gmail.password, "");
+    assert_eq!(
+        gmail.oauth2.unwrap().client_id,
+        "123.apps.googleusercontent.com"
+    );
+}
+
+#[test]
+fn test_invalid_oauth2_account() {
+    // NOTE: A password next to oauth2 is ambiguous
+    let config = Config::parse(
+        r#"
+[accounts.gmail]
+server = "imap.gmail.com"
+login = "me@gmail.com"
+password = "app-password"
+
+[accounts.gmail.oauth2]
+provider = "google"
+client_id = "id"
+"#,
+    )
+    .unwrap();
+    assert!(config.accounts["gmail"].builder("gmail", None).is_err());
+
+    let result = Config::parse(
+        r#"
+[accounts.company]
+server = "imap.company.com"
+login = "me@company.com"
+
+[accounts.company.oauth2]
+client_id = "id"
+"#,
+    );
+    assert!(matches!(result, Err(ConfigError::Invalid(_))));
+}
+
+fn account_with(password_option: &str) -> String {
+    format!(
+        "[accounts.company]\nserver = \"imap.company.com\"\nlogin = \"me@company.com\"\n{}\n",
+        password_option
+    )
+}
+
+#[test]
+fn test_password_command() {
+    let config = Config::parse(&account_with(
+        "password_command = \"printf 'from-pass\\\\nurl: imap.company.com'\"",
+    ))
+    .unwrap();
+    let company = config.accounts["company"].builder("company", None).unwrap();
+    assert_eq!(company.password, "from-pass");
+
+    let config = Config::parse(&account_with(
+        "password_command = \"echo locked >&2; exit 1\"",
+    ))
+    .unwrap();
+    let error = config.accounts["company"]
+        .builder("company", None)
+        .unwrap_err();
+    assert!(error.to_string().contains("locked"), "{}", error);
+}
+
+#[test]
+fn test_password_file() {
+    let temp_dir = tempdir().unwrap();
+    let path = temp_dir.path().join("company.password");
+    fs::write(&path, "from-file\n").unwrap();
+    let config = Config::parse(&account_with(&format!("password_file = {:?}", path))).unwrap();
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+        let error = config.accounts["company"]
+            .builder("company", None)
+            .unwrap_err();
+        assert!(error.to_string().contains("chmod 600"), "{}", error);
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o600)).unwrap();
+    }
+    let company = config.accounts["company"].builder("company", None).unwrap();
+    assert_eq!(company.password, "from-file");
+}
+
+#[test]
+fn test_several_password_options() {
+    let config = Config::parse(&account_with(
+        "password = \"secret\"\npassword_command = \"pass show mail/company\"",
+    ))
+    .unwrap();
+    let error = config.accounts["company"]
+        .builder("company", None)
+        .unwrap_err();
+    assert!(error.to_string().contains("only one of"), "{}", error);
+}
+
+#[test]
+fn test_password_secret() {
+    let result = Config::parse(&account_with("password_secret = \"company\""));
+    assert!(matches!(result, Err(ConfigError::Invalid(_))));
+
+    let config = Config::parse(&format!(
+        "{}\n[secrets]\nfile = \"/home/me/.config/antworker/secrets.toml.age\"\nidentity = \"/home/me/.config/age/key.txt\"\n",
+        account_with("password_secret = \"company\"")
+    ))
+    .unwrap();
+    let command = decrypt_command(config.secrets.as_ref().unwrap()).unwrap();
+    assert_eq!(command.get_program(), "age");
+    assert_eq!(
+        command.get_args().collect::<Vec<_>>(),
+        vec![
+            "--decrypt",
+            "--identity",
+            "/home/me/.config/age/key.txt",
+            "/home/me/.config/antworker/secrets.toml.age"
+        ]
+    );
+
+    let gpg = SecretsConfig {
+        file: "secrets.toml.gpg".into(),
+        identity: None,
+    };
+    assert_eq!(decrypt_command(&gpg).unwrap().get_program(), "gpg");
+    let plaintext = SecretsConfig {
+        file: "secrets.toml".into(),
+        identity: None,
+    };
+    assert!(decrypt_command(&plaintext).is_err());
+
+    let secrets = parse_secrets("company = \"s3cret\"\ngmail = \"other\"\n").unwrap();
+    assert_eq!(secrets["company"], "s3cret");
+    assert!(parse_secrets("company = 1").is_err());
+}
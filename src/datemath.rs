@@ -1,4 +1,5 @@
+pub mod clock;
 pub mod date;
+pub mod period;
 #[cfg(test)]
 mod tests;
-
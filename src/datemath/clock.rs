@@ -0,0 +1,79 @@
+use chrono::{DateTime, NaiveDate, Utc};
+use chrono_tz::Tz;
+use std::env::var;
+
+use crate::{config::accounts::ConfigError, error::AntworkerError};
+
+use super::period::Period;
+
+/// Time zone whose calendar decides which month an invoice belongs to, unless
+/// `ANTWORKER_TIMEZONE` names another one.
+pub const DEFAULT_TIMEZONE: Tz = chrono_tz::Europe::Warsaw;
+
+/// The time zone called `name`, `DEFAULT_TIMEZONE` without one.
+pub fn parse_timezone(name: Option<&str>) -> Result<Tz, ConfigError> {
+    match name {
+        Some(name) => name.parse().map_err(|_| {
+            ConfigError::Invalid(format!(
+                "ANTWORKER_TIMEZONE '{}' is not a time zone name such as Europe/Warsaw",
+                name
+            ))
+        }),
+        None => Ok(DEFAULT_TIMEZONE),
+    }
+}
+
+/// Where "now" comes from, so tests can pin it.
+pub trait Clock {
+    fn now(&self) -> DateTime<Tz>;
+
+    fn timezone(&self) -> Tz {
+        self.now().timezone()
+    }
+
+    fn today(&self) -> NaiveDate {
+        self.now().date_naive()
+    }
+
+    fn current_period(&self) -> Period {
+        Period::of(self.today())
+    }
+}
+
+/// The system time in the given time zone.
+#[derive(Debug, Clone, Copy)]
+pub struct SystemClock(pub Tz);
+
+impl SystemClock {
+    /// The system time in the zone `ANTWORKER_TIMEZONE` names.
+    pub fn from_env() -> Result<Self, AntworkerError> {
+        Ok(SystemClock(parse_timezone(
+            var("ANTWORKER_TIMEZONE").ok().as_deref(),
+        )?))
+    }
+}
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Tz> {
+        Utc::now().with_timezone(&self.0)
+    }
+
+    fn timezone(&self) -> Tz {
+        self.0
+    }
+}
+
+/// Always the same moment.
+#[derive(Debug, Clone, Copy)]
+pub struct FixedClock(pub DateTime<Tz>);
+
+impl Clock for FixedClock {
+    fn now(&self) -> DateTime<Tz> {
+        self.0
+    }
+}
+
+/// `date` on the calendar of `timezone`.
+pub fn local(date: &DateTime<Utc>, timezone: Tz) -> DateTime<Tz> {
+    date.with_timezone(&timezone)
+}
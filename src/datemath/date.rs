@@ -1,38 +1,101 @@
-use chrono::{DateTime, Utc, Datelike};
+use chrono::{Months, NaiveDate};
 
-pub fn get_current_year_str() -> String {
-    let now: DateTime<Utc> = chrono::Utc::now();
-    now.format("%Y").to_string()
+use super::{clock::Clock, period::Period};
+
+pub fn get_current_year_str(clock: &dyn Clock) -> String {
+    clock.current_period().format("%Y")
+}
+
+pub fn get_current_month_str(clock: &dyn Clock) -> String {
+    clock.current_period().format("%m")
+}
+
+pub fn get_current_year_month_str(clock: &dyn Clock) -> String {
+    clock.current_period().format("%Y_%m")
 }
 
-pub fn get_current_month_str() -> String {
-    let now: DateTime<Utc> = chrono::Utc::now();
-    now.format("%m").to_string()
+/// Days from `from` to `to`, both included.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DateRange {
+    pub from: NaiveDate,
+    pub to: NaiveDate,
 }
 
-pub fn get_current_year_month_str() -> String {
-    let now: DateTime<Utc> = chrono::Utc::now();
-    now.format("%Y_%m").to_string()
+impl DateRange {
+    pub fn new(from: NaiveDate, to: NaiveDate) -> Result<Self, String> {
+        match from <= to {
+            true => Ok(DateRange { from, to }),
+            false => Err(format!("{} is after {}", from, to)),
+        }
+    }
+
+    /// Every day of a calendar month.
+    pub fn month(year: i32, month: u32) -> Option<Self> {
+        Period::new(year, month).map(|period| period.days())
+    }
+
+    pub fn contains(&self, date: NaiveDate) -> bool {
+        (self.from..=self.to).contains(&date)
+    }
+
+    /// Every month the range touches, in order.
+    pub fn months(&self) -> Vec<Period> {
+        let first = Period::of(self.from);
+        (0..=Period::of(self.to) - first)
+            .map(|offset| first + Months::new(offset as u32))
+            .collect()
+    }
 }
 
-pub fn get_current_month_year() -> Option<(i32, u32)> {
-    let now: DateTime<Utc> = chrono::Utc::now();
-    return Some((now.year(), now.month()));
+/// Every day of the current month.
+pub fn get_current_month_range(clock: &dyn Clock) -> DateRange {
+    clock.current_period().days()
 }
 
-pub fn get_previous_month_year() -> (i32, u32) {
-    let now: DateTime<Utc> = Utc::now();
-    let (year, month) = now
-        .checked_sub_signed(chrono::Duration::days(now.day() as i64))
-        .and_then(|dt| dt.checked_sub_signed(chrono::Duration::days(1)))
-        .map(|dt| (dt.year(), dt.month()))
-        .unwrap_or_else(|| (now.year(), now.month()));
-    (year, month)
+/// The days a command works on, picked from `--month`, `--previous` or `--from`/`--to`, `None`
+/// when none of them was given. A missing `--from` starts the month of `--to`, a missing `--to`
+/// ends at `today`.
+pub fn resolve_date_range(
+    month: Option<&str>,
+    previous: bool,
+    from: Option<NaiveDate>,
+    to: Option<NaiveDate>,
+    today: NaiveDate,
+) -> Result<Option<DateRange>, String> {
+    match (month, previous, from, to) {
+        (None, false, None, None) => Ok(None),
+        (Some(month), false, None, None) => Ok(Some(month.parse::<Period>()?.days())),
+        (None, true, None, None) => Ok(Some(Period::of(today).previous().days())),
+        (None, false, from, to) => {
+            let to = to.unwrap_or(today);
+            let from = from.unwrap_or_else(|| Period::of(to).first_day());
+            Ok(Some(
+                DateRange::new(from, to).map_err(|e| format!("--from {}", e))?,
+            ))
+        }
+        _ => Err("use only one of --month, --previous or --from/--to".to_string()),
+    }
 }
 
-pub fn get_previous_month_year_str() -> (String, String) {
-    let (previous_year, previous_month) = get_previous_month_year();
-    let previous_month_str = format!("{:02}", previous_month);
-    let previous_year_str = previous_year.to_string();
-    (previous_month_str, previous_year_str)
+/// The days `backfill` walks, a whole `year` or `from` up to `to`, never past `today`.
+pub fn resolve_backfill_range(
+    year: Option<i32>,
+    from: Option<NaiveDate>,
+    to: Option<NaiveDate>,
+    today: NaiveDate,
+) -> Result<DateRange, String> {
+    match (year, from, to) {
+        (Some(year), None, None) => {
+            let january =
+                Period::new(year, 1).ok_or_else(|| format!("{} is out of range", year))?;
+            let december = january + Months::new(11);
+            DateRange::new(january.first_day(), december.last_day().min(today))
+                .map_err(|_| format!("{} has not started yet", year))
+        }
+        (None, Some(from), to) => {
+            DateRange::new(from, to.unwrap_or(today)).map_err(|e| format!("--from {}", e))
+        }
+        (None, None, _) => Err("give --year or --from".to_string()),
+        _ => Err("use either --year or --from/--to".to_string()),
+    }
 }
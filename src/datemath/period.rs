@@ -0,0 +1,116 @@
+use std::{
+    fmt::{self, Display, Formatter},
+    ops::{Add, Sub},
+    str::FromStr,
+};
+
+use chrono::{Datelike, Months, NaiveDate};
+
+use super::date::DateRange;
+
+/// A calendar month, e.g. `2024-01`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Period {
+    // NOTE: Always the 1st, so chrono does the month arithmetic
+    first_day: NaiveDate,
+}
+
+impl Period {
+    pub fn new(year: i32, month: u32) -> Option<Self> {
+        NaiveDate::from_ymd_opt(year, month, 1).map(|first_day| Period { first_day })
+    }
+
+    /// The month `date` falls in.
+    pub fn of(date: NaiveDate) -> Self {
+        Period {
+            first_day: date.with_day(1).unwrap_or(date),
+        }
+    }
+
+    pub fn year(&self) -> i32 {
+        self.first_day.year()
+    }
+
+    pub fn month(&self) -> u32 {
+        self.first_day.month()
+    }
+
+    pub fn first_day(&self) -> NaiveDate {
+        self.first_day
+    }
+
+    pub fn last_day(&self) -> NaiveDate {
+        self.first_day
+            .checked_add_months(Months::new(1))
+            .and_then(|next| next.pred_opt())
+            .unwrap_or(NaiveDate::MAX)
+    }
+
+    /// Every day of the month.
+    pub fn days(&self) -> DateRange {
+        DateRange {
+            from: self.first_day(),
+            to: self.last_day(),
+        }
+    }
+
+    pub fn previous(&self) -> Self {
+        *self - Months::new(1)
+    }
+
+    pub fn next(&self) -> Self {
+        *self + Months::new(1)
+    }
+
+    /// Format with chrono's specifiers, e.g. `%Y_%m` for `2024_01`.
+    pub fn format(&self, format: &str) -> String {
+        self.first_day.format(format).to_string()
+    }
+}
+
+/// Panics when the result is out of chrono's range, like adding `Months` to a `NaiveDate` does.
+impl Add<Months> for Period {
+    type Output = Period;
+
+    fn add(self, months: Months) -> Period {
+        Period {
+            first_day: self.first_day + months,
+        }
+    }
+}
+
+impl Sub<Months> for Period {
+    type Output = Period;
+
+    fn sub(self, months: Months) -> Period {
+        Period {
+            first_day: self.first_day - months,
+        }
+    }
+}
+
+/// Months from `other` to `self`, negative when `other` is later.
+impl Sub for Period {
+    type Output = i32;
+
+    fn sub(self, other: Period) -> i32 {
+        (self.year() - other.year()) * 12 + self.month() as i32 - other.month() as i32
+    }
+}
+
+impl Display for Period {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.format("%Y-%m"))
+    }
+}
+
+impl FromStr for Period {
+    type Err = String;
+
+    /// Parse a month written as `2024-01`.
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        NaiveDate::parse_from_str(&format!("{}-01", value), "%Y-%m-%d")
+            .map(|first_day| Period { first_day })
+            .map_err(|_| format!("'{}' is not a month like 2024-01", value))
+    }
+}
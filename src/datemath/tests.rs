@@ -1,51 +1,157 @@
-use crate::datemath::date::{
-    get_current_month_str, get_current_month_year, get_current_year_str, get_previous_month_year,
-    get_previous_month_year_str,
+use crate::datemath::{
+    clock::{local, parse_timezone, Clock, FixedClock, DEFAULT_TIMEZONE},
+    date::{
+        get_current_month_range, get_current_month_str, get_current_year_month_str,
+        get_current_year_str, resolve_backfill_range, resolve_date_range, DateRange,
+    },
+    period::Period,
 };
-use chrono::{DateTime, Datelike, Duration, Utc};
+use chrono::{Months, NaiveDate, TimeZone, Utc};
+use chrono_tz::{Europe::Warsaw, Tz};
+
+/// A clock stopped at the given minute of Warsaw time.
+fn warsaw(year: i32, month: u32, day: u32, hour: u32, minute: u32) -> FixedClock {
+    FixedClock(
+        Warsaw
+            .with_ymd_and_hms(year, month, day, hour, minute, 0)
+            .unwrap(),
+    )
+}
 
 #[test]
 fn test_get_current_year_str() {
-    let current_year = get_current_year_str();
-    let now: DateTime<Utc> = Utc::now();
-    let expected_year = now.format("%Y").to_string();
-    assert_eq!(current_year, expected_year);
+    assert_eq!(get_current_year_str(&warsaw(2024, 1, 1, 0, 30)), "2024");
+    assert_eq!(get_current_year_str(&warsaw(2023, 12, 31, 23, 59)), "2023");
 }
 
 #[test]
 fn test_get_current_month_str() {
-    let current_month = get_current_month_str();
-    let now: DateTime<Utc> = Utc::now();
-    let expected_month = now.format("%m").to_string();
-    assert_eq!(current_month, expected_month);
+    assert_eq!(get_current_month_str(&warsaw(2024, 3, 1, 0, 0)), "03");
+    assert_eq!(
+        get_current_year_month_str(&warsaw(2024, 3, 1, 0, 0)),
+        "2024_03"
+    );
+}
+
+#[test]
+fn test_clock_timezone() {
+    // NOTE: 00:30 on the 1st in Warsaw is still the last day of the previous month in UTC
+    let clock = warsaw(2024, 2, 1, 0, 30);
+    assert_eq!(clock.now().naive_utc().date(), date(2024, 1, 31));
+    assert_eq!(clock.today(), date(2024, 2, 1));
+    assert_eq!(clock.current_period(), Period::new(2024, 2).unwrap());
+    assert_eq!(
+        get_current_month_range(&clock),
+        DateRange::month(2024, 2).unwrap()
+    );
+    let sent = Utc.with_ymd_and_hms(2024, 1, 31, 23, 30, 0).unwrap();
+    assert_eq!(local(&sent, Warsaw).date_naive(), date(2024, 2, 1));
+    assert_eq!(local(&sent, Tz::UTC).date_naive(), date(2024, 1, 31));
+}
+
+#[test]
+fn test_parse_timezone() {
+    assert_eq!(parse_timezone(None).unwrap(), DEFAULT_TIMEZONE);
+    assert_eq!(
+        parse_timezone(Some("America/New_York")).unwrap(),
+        Tz::America__New_York
+    );
+    let error = parse_timezone(Some("Mars/Olympus")).unwrap_err();
+    assert!(error.to_string().contains("'Mars/Olympus'"), "{}", error);
+}
+
+#[test]
+fn test_period_arithmetic() {
+    let january = Period::new(2024, 1).unwrap();
+    assert_eq!(january.previous(), Period::new(2023, 12).unwrap());
+    assert_eq!(january.next(), Period::new(2024, 2).unwrap());
+    assert_eq!(january + Months::new(14), Period::new(2025, 3).unwrap());
+    assert_eq!(Period::new(2025, 3).unwrap() - january, 14);
+    assert_eq!(january - Period::new(2024, 3).unwrap(), -2);
+    assert_eq!(Period::of(date(2024, 2, 29)), Period::new(2024, 2).unwrap());
+    assert_eq!(Period::new(2024, 2).unwrap().last_day(), date(2024, 2, 29));
+    assert!(Period::new(2024, 13).is_none());
+}
+
+#[test]
+fn test_period_format_and_parse() {
+    let january = Period::new(2024, 1).unwrap();
+    assert_eq!(january.to_string(), "2024-01");
+    assert_eq!(january.format("%Y/%Y_%m"), "2024/2024_01");
+    assert_eq!("2024-01".parse::<Period>(), Ok(january));
+    assert!("2024-13".parse::<Period>().is_err());
+    assert!("01/2024".parse::<Period>().is_err());
+}
+
+fn date(year: i32, month: u32, day: u32) -> NaiveDate {
+    NaiveDate::from_ymd_opt(year, month, day).unwrap()
+}
+
+#[test]
+fn test_date_range_month() {
+    let february = DateRange::month(2024, 2).unwrap();
+    assert_eq!(february.from, date(2024, 2, 1));
+    assert_eq!(february.to, date(2024, 2, 29));
+    assert_eq!(DateRange::month(2023, 12).unwrap().to, date(2023, 12, 31));
+    assert!(DateRange::month(2024, 13).is_none());
 }
 
 #[test]
-fn test_get_current_month_year() {
-    let current_month_year = get_current_month_year().unwrap();
-    let now: DateTime<Utc> = Utc::now();
-    let expected_month_year = (now.year(), now.month());
-    assert_eq!(current_month_year, expected_month_year);
+fn test_date_range_months() {
+    let range = DateRange::new(date(2023, 11, 20), date(2024, 2, 1)).unwrap();
+    assert_eq!(
+        range.months(),
+        ["2023-11", "2023-12", "2024-01", "2024-02"].map(|month| month.parse::<Period>().unwrap())
+    );
+    assert!(range.contains(date(2023, 11, 20)));
+    assert!(!range.contains(date(2024, 2, 2)));
+    assert!(DateRange::new(date(2024, 2, 1), date(2024, 1, 1)).is_err());
 }
 
 #[test]
-fn test_get_previous_month_year() {
-    let (previous_year, previous_month) = get_previous_month_year();
-    let now: DateTime<Utc> = Utc::now();
-    let expected_year_month = now
-        .checked_sub_signed(Duration::days(now.day() as i64))
-        .and_then(|dt| dt.checked_sub_signed(Duration::days(1)))
-        .map(|dt| (dt.year(), dt.month()))
-        .unwrap_or_else(|| (now.year(), now.month()));
-    assert_eq!((previous_year, previous_month), expected_year_month);
+fn test_resolve_date_range() {
+    let today = date(2024, 3, 1);
+    assert_eq!(resolve_date_range(None, false, None, None, today), Ok(None));
+    assert_eq!(
+        resolve_date_range(Some("2024-01"), false, None, None, today),
+        Ok(DateRange::month(2024, 1))
+    );
+    // NOTE: On the 1st, --previous is the month that just ended
+    assert_eq!(
+        resolve_date_range(None, true, None, None, today),
+        Ok(DateRange::month(2024, 2))
+    );
+    assert_eq!(
+        resolve_date_range(None, false, Some(date(2024, 1, 10)), None, today),
+        Ok(Some(DateRange::new(date(2024, 1, 10), today).unwrap()))
+    );
+    assert_eq!(
+        resolve_date_range(None, false, None, Some(date(2024, 2, 10)), today),
+        Ok(Some(
+            DateRange::new(date(2024, 2, 1), date(2024, 2, 10)).unwrap()
+        ))
+    );
+    assert!(resolve_date_range(None, false, Some(date(2024, 4, 1)), None, today).is_err());
+    assert!(resolve_date_range(Some("2024-01"), true, None, None, today).is_err());
 }
 
 #[test]
-fn test_get_previous_month_year_str() {
-    let (previous_month_str, previous_year_str) = get_previous_month_year_str();
-    let (previous_year, previous_month) = get_previous_month_year();
-    let expected_month_str = format!("{:02}", previous_month);
-    let expected_year_str = previous_year.to_string();
-    assert_eq!(previous_month_str, expected_month_str);
-    assert_eq!(previous_year_str, expected_year_str);
+fn test_resolve_backfill_range() {
+    let today = date(2024, 3, 15);
+    assert_eq!(
+        resolve_backfill_range(Some(2023), None, None, today),
+        DateRange::new(date(2023, 1, 1), date(2023, 12, 31))
+    );
+    // NOTE: The current year is only walked up to today
+    assert_eq!(
+        resolve_backfill_range(Some(2024), None, None, today),
+        DateRange::new(date(2024, 1, 1), today)
+    );
+    assert!(resolve_backfill_range(Some(2025), None, None, today).is_err());
+    assert_eq!(
+        resolve_backfill_range(None, Some(date(2023, 11, 5)), None, today),
+        DateRange::new(date(2023, 11, 5), today)
+    );
+    assert!(resolve_backfill_range(None, None, None, today).is_err());
+    assert!(resolve_backfill_range(Some(2023), Some(date(2023, 1, 1)), None, today).is_err());
 }
@@ -1,5 +1,18 @@
-pub mod parser;
+pub mod actions;
 pub mod attachment;
+pub mod connection;
+pub mod decode;
+#[cfg(test)]
+pub mod fake;
+pub mod filename;
 pub mod inbox;
+pub mod mailbox;
 pub mod main;
-
+pub mod mime;
+pub mod offline;
+pub mod parser;
+pub mod reconnect;
+pub mod source;
+#[cfg(test)]
+mod tests;
+pub mod watch;
@@ -0,0 +1,102 @@
+use serde::Deserialize;
+
+use crate::rules::search::compress_uid_set;
+
+use super::{
+    mailbox::encode_modified_utf7,
+    source::{MailSource, SourceResult},
+};
+
+/// What to do on the server with a message once its attachments are saved.
+#[derive(Debug, Default, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PostActions {
+    /// Custom keyword to set, e.g. `$AntworkerSaved`. Messages carrying it are skipped by later
+    /// runs, on any machine.
+    pub flag: Option<String>,
+    #[serde(default)]
+    pub mark_seen: bool,
+    pub copy_to: Option<String>,
+    pub move_to: Option<String>,
+    /// Without `MOVE` and `UIDPLUS`, finish `move_to` with a plain `EXPUNGE`. That also removes
+    /// every other message marked `\Deleted` in the mailbox, so it is off by default and moved
+    /// messages are only left marked `\Deleted`.
+    #[serde(default)]
+    pub expunge_without_uidplus: bool,
+}
+
+impl PostActions {
+    pub fn is_empty(&self) -> bool {
+        self.flag.is_none() && !self.mark_seen && self.copy_to.is_none() && self.move_to.is_none()
+    }
+
+    /// Extra SEARCH criteria that leave out messages an earlier run already handled.
+    pub fn search_criteria(&self) -> Option<String> {
+        self.flag.as_ref().map(|flag| format!("UNKEYWORD {}", flag))
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if let Some(flag) = &self.flag {
+            let is_atom = !flag.is_empty()
+                && !flag.starts_with('\\')
+                && flag
+                    .chars()
+                    .all(|c| c.is_ascii_graphic() && !"(){%*\"\\]".contains(c));
+            if !is_atom {
+                return Err(format!("flag '{}' is not a valid IMAP keyword", flag));
+            }
+        }
+        for folder in [&self.copy_to, &self.move_to].into_iter().flatten() {
+            if folder.trim().is_empty() {
+                return Err("copy_to and move_to must not be empty".to_string());
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Flag, copy and move the given messages in the currently selected mailbox.
+pub fn apply_post_actions(
+    source: &mut dyn MailSource,
+    post_actions: &PostActions,
+    uids: &[u32],
+) -> SourceResult<()> {
+    if uids.is_empty() || post_actions.is_empty() {
+        return Ok(());
+    }
+    let uid_set = compress_uid_set(uids);
+    let mut flags = Vec::new();
+    if let Some(flag) = &post_actions.flag {
+        flags.push(flag.as_str());
+    }
+    if post_actions.mark_seen {
+        flags.push("\\Seen");
+    }
+    if !flags.is_empty() {
+        source.add_flags(&uid_set, &flags)?;
+    }
+    if let Some(copy_to) = &post_actions.copy_to {
+        source.copy(&uid_set, &encode_modified_utf7(copy_to))?;
+    }
+    if let Some(move_to) = &post_actions.move_to {
+        let mailbox = encode_modified_utf7(move_to);
+        if source.has_capability("MOVE")? {
+            source.move_messages(&uid_set, &mailbox)?;
+        } else {
+            source.copy(&uid_set, &mailbox)?;
+            source.add_flags(&uid_set, &["\\Deleted"])?;
+            match source.has_capability("UIDPLUS")? {
+                true => source.expunge(Some(&uid_set))?,
+                // NOTE: A plain EXPUNGE also removes anything else marked \Deleted, e.g. by
+                // another client, so it needs the account to opt in
+                false if post_actions.expunge_without_uidplus => source.expunge(None)?,
+                false => eprintln!(
+                    "⚠️  The server supports neither MOVE nor UIDPLUS, UID {} copied to {} and \
+                     left marked \\Deleted",
+                    uid_set, move_to
+                ),
+            }
+        }
+    }
+    Ok(())
+}
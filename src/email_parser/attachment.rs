@@ -1,92 +1,361 @@
-use super::parser::EmailDetails;
-use crate::io::save_location::setup_save_location;
-use imap::Session;
-use indicatif::{MultiProgress, ProgressBar, ProgressIterator, ProgressStyle};
-use mailparse::{self, parse_mail, ParsedContentType, ParsedMail};
-use native_tls::TlsStream;
-use std::{
-    fs::File,
-    io::{Read, Write},
-    path::Path,
+use super::{
+    filename::sanitize_filename,
+    inbox::ProcessOptions,
+    mime::{find_attachments, FoundAttachment, MimeWalk},
+    parser::EmailDetails,
+    source::MailSource,
+};
+use crate::{
+    config::accounts::ConfigError,
+    error::{AntworkerError, Failure},
+    factories::credentials::EmailAccountBuilder,
+    io::{
+        dedupe::{link_duplicate, DedupeIndex, DuplicatePolicy, IndexEntry},
+        writer::{
+            link_without_collision, save_without_collision, sha256_hex, CollisionPolicy,
+            SaveOutcome, SaveReport,
+        },
+    },
+    rules::{
+        action::{RuleAction, TemplateValues},
+        define::FilterRules,
+    },
 };
+use indicatif::{MultiProgress, ProgressBar, ProgressIterator, ProgressStyle};
+use mailparse::{self, parse_mail, ParsedMail};
+use std::{fs, path::PathBuf, sync::Mutex};
+
+/// Account and mailbox a message was read from, recorded with every saved attachment.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MessageOrigin {
+    pub account: String,
+    pub mailbox: String,
+}
 
-pub fn get_and_save_attachments<S: Read + Write>(
-    email_details: &Vec<EmailDetails>,
-    imap_session: &mut Session<TlsStream<S>>,
+impl From<&EmailAccountBuilder> for MessageOrigin {
+    fn from(email_account: &EmailAccountBuilder) -> Self {
+        MessageOrigin {
+            account: email_account.account.clone(),
+            mailbox: email_account.mailbox.clone(),
+        }
+    }
+}
+
+impl MessageOrigin {
+    /// How a message is named in the failure summary.
+    pub fn describe(&self, uid: u32) -> String {
+        format!("{}/{} UID {}", self.account, self.mailbox, uid)
+    }
+}
+
+/// What happened to every attachment, or to the whole message when it could not be read, with
+/// the UID of the message.
+pub type MessageOutcomes = Vec<(u32, Result<SaveOutcome, AntworkerError>)>;
+
+/// Split outcomes into a report, failures named after the message they belong to.
+pub fn into_report(origin: &MessageOrigin, outcomes: MessageOutcomes) -> SaveReport {
+    let mut report = SaveReport::default();
+    for (uid, outcome) in outcomes {
+        match outcome {
+            Ok(outcome) => report.outcomes.push(outcome),
+            Err(error) => report.failures.push(Failure {
+                item: origin.describe(uid),
+                error,
+            }),
+        }
+    }
+    report
+}
+
+pub fn get_and_save_attachments(
+    email_account: &EmailAccountBuilder,
+    email_details: &[EmailDetails],
+    source: &mut dyn MailSource,
+    rules: &FilterRules,
+    dedupe_index: &Mutex<DedupeIndex>,
     multi_progress: &MultiProgress,
-) -> () {
+    options: &ProcessOptions,
+) -> MessageOutcomes {
+    let origin = MessageOrigin::from(email_account);
     let email_len = email_details.len();
     // Provide a custom bar style
     let pb_2 = multi_progress.add(ProgressBar::new(email_len as u64));
     pb_2.set_style(
         ProgressStyle::with_template("{spinner:.green} [{bar:40.red}] ({pos}/{len})").unwrap(),
     );
+    let mut outcomes = Vec::new();
     for email in email_details.iter().progress_with(pb_2) {
-        let uid = email.uid;
-        let save_location = setup_save_location(&email.subject).unwrap();
-        let message_stream = imap_session.uid_fetch(uid.to_string(), "BODY[]").unwrap();
-        for fetch_result in &message_stream {
-            let body = fetch_result.body().unwrap();
-            // Parse the MIME content
-            let mail = parse_mail(body).unwrap();
-            // Iterate through MIME parts
-            for part in mail.subparts.iter() {
-                let content_type = &part.ctype;
-                match content_type.mimetype.as_str() {
-                    "application/pdf" => {
-                        handle_pure_pdf(uid, content_type, part, &save_location).unwrap();
-                    }
-                    "multipart/mixed" => {
-                        handle_mixed(uid, part, &save_location).unwrap();
-                    }
-                    _ => {}
-                }
-            }
+        // NOTE: A message that cannot be fetched or parsed is reported, the others still go on
+        let saved = source
+            .fetch_body(email.uid, true)
+            .map_err(AntworkerError::imap)
+            .and_then(|body| {
+                let mail = parse_mail(&body)?;
+                Ok(save_mail_attachments(
+                    &origin,
+                    email,
+                    &mail,
+                    rules,
+                    dedupe_index,
+                    multi_progress,
+                    options,
+                ))
+            });
+        match saved {
+            Ok(saved) => outcomes.extend(saved),
+            Err(error) => outcomes.push((email.uid, Err(error))),
         }
     }
+    outcomes
 }
 
-fn handle_mixed(
-    uid: u32,
-    part: &ParsedMail,
-    save_location: &String,
-) -> Result<(), Box<dyn std::error::Error>> {
-    for sub_part in part.subparts.iter() {
-        let sub_part_content_type = &sub_part.ctype;
-        match sub_part_content_type.mimetype.as_str() {
-            "application/pdf" => {
-                handle_pure_pdf(uid, sub_part_content_type, sub_part, save_location).unwrap();
-            }
-            _ => {}
+/// Save the attachments of one parsed message where its matching rule says, wherever the
+/// message was read from.
+pub fn save_mail_attachments(
+    origin: &MessageOrigin,
+    email: &EmailDetails,
+    mail: &ParsedMail,
+    rules: &FilterRules,
+    dedupe_index: &Mutex<DedupeIndex>,
+    multi_progress: &MultiProgress,
+    options: &ProcessOptions,
+) -> MessageOutcomes {
+    let uid = email.uid;
+    // Walk the whole MIME tree
+    let walk = find_attachments(mail);
+    if options.explain {
+        let _ = multi_progress.println(format!(
+            "🔎 UID {} \"{}\"\n{}",
+            uid,
+            email.subject,
+            walk.explain()
+        ));
+    }
+    let Some((action, values)) = plan_message(
+        email,
+        &origin.account,
+        &walk,
+        rules,
+        options.attachments.transliterate,
+    ) else {
+        if options.explain {
+            let _ = multi_progress.println(format!("🔎 UID {} matches no rule, skipped", uid));
         }
+        return vec![];
+    };
+    let save_location = match prepare_save_location(&action, &values) {
+        Ok(save_location) => save_location,
+        Err(error) => return vec![(uid, Err(error))],
+    };
+    if options.explain {
+        let _ = multi_progress.println(format!(
+            "🔎 UID {} matches rule \"{}\" ({}) -> {}",
+            uid,
+            values.rule,
+            action.category,
+            save_location.display()
+        ));
     }
-    Ok(())
+    walk.attachments
+        .iter()
+        .map(|attachment| {
+            let outcome = save_attachment(
+                origin,
+                email,
+                attachment,
+                &action,
+                &values,
+                dedupe_index,
+                options,
+            );
+            (uid, outcome)
+        })
+        .collect()
 }
 
-fn handle_pure_pdf(
-    uid: u32,
-    content_type: &ParsedContentType,
-    part: &ParsedMail,
-    save_location: &String,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let filename = content_type
-        .params
-        .get("name")
-        .cloned()
-        .unwrap_or_else(|| format!("attachment_{}_unnamed.pdf", uid))
-        .replace(" ", "_")
-        .replace("/", "_");
-    let full_path_save_location = Path::new(save_location).join(&filename);
-    let binary_content = part
-        .get_body_raw()
-        .map_err(|e| eprintln!("Failed to get body raw: {}", e))
-        .expect("Failed to get body raw");
-    println!("Saving attachment: {:?}", full_path_save_location);
-    let mut file = File::create(full_path_save_location.clone())
-        .map_err(|e| eprintln!("Failed to create file: {}", e))
-        .expect("Failed to create file");
-    file.write_all(&binary_content)
-        .map_err(|e| eprintln!("Failed to write to file: {}", e))
-        .expect("Failed to write to file");
-    Ok(())
+/// A rule template that could not be filled in.
+fn template_error(values: &TemplateValues, error: String) -> AntworkerError {
+    AntworkerError::Config(ConfigError::Invalid(format!(
+        "rule \"{}\": {}",
+        values.rule, error
+    )))
+}
+
+fn prepare_save_location(
+    action: &RuleAction,
+    values: &TemplateValues,
+) -> Result<PathBuf, AntworkerError> {
+    let save_location = action
+        .save_location(values)
+        .map_err(|e| template_error(values, e))?;
+    fs::create_dir_all(&save_location).map_err(|e| AntworkerError::io(&save_location, e))?;
+    Ok(save_location)
+}
+
+/// The rule that takes a parsed message and the values its templates are filled with, `None`
+/// when no rule matches.
+pub fn plan_message(
+    email: &EmailDetails,
+    account: &str,
+    walk: &MimeWalk,
+    rules: &FilterRules,
+    transliterate: bool,
+) -> Option<(RuleAction, TemplateValues)> {
+    // NOTE: Attachment filename rules can only be settled now that the body is parsed
+    let filenames = walk
+        .attachments
+        .iter()
+        .filter_map(|attachment| attachment.filename.clone())
+        .collect();
+    let rule = rules.matching_rule(&email.facts(account, Some(filenames)));
+    if !rules.is_empty() && rule.is_none() {
+        return None;
+    }
+    let action = rule.map(|rule| rule.action.clone()).unwrap_or_default();
+    let values = TemplateValues::new(
+        email,
+        rule.map_or("", |rule| rule.name.as_str()),
+        action.category,
+        rules.timezone,
+        transliterate,
+    );
+    Some((action, values))
+}
+
+/// Name an attachment is saved under, before collisions are taken into account.
+pub fn target_filename(
+    email: &EmailDetails,
+    attachment: &FoundAttachment,
+    action: &RuleAction,
+    values: &TemplateValues,
+    transliterate: bool,
+) -> Result<String, String> {
+    let original = attachment
+        .filename
+        .as_ref()
+        .and_then(|filename| sanitize_filename(filename, transliterate))
+        .unwrap_or_else(|| format!("attachment_{}_unnamed.pdf", email.uid));
+    Ok(sanitize_filename(&action.filename(values, &original)?, transliterate).unwrap_or(original))
+}
+
+fn collision_prefix(
+    email: &EmailDetails,
+    values: &TemplateValues,
+    policy: CollisionPolicy,
+) -> String {
+    match policy {
+        CollisionPolicy::PrefixSender => email
+            .from
+            .first()
+            .and_then(|sender| sender.rsplit_once('@'))
+            .and_then(|(_, domain)| sanitize_filename(domain, false))
+            .unwrap_or_else(|| "unknown".to_string()),
+        _ => values.date.format("%Y-%m-%d").to_string(),
+    }
+}
+
+fn save_attachment(
+    origin: &MessageOrigin,
+    email: &EmailDetails,
+    attachment: &FoundAttachment,
+    action: &RuleAction,
+    values: &TemplateValues,
+    dedupe_index: &Mutex<DedupeIndex>,
+    options: &ProcessOptions,
+) -> Result<SaveOutcome, AntworkerError> {
+    let save_location = action
+        .save_location(values)
+        .map_err(|e| template_error(values, e))?;
+    let filename = target_filename(
+        email,
+        attachment,
+        action,
+        values,
+        options.attachments.transliterate,
+    )
+    .map_err(|e| template_error(values, e))?;
+    let policy = options.attachments.on_collision;
+    let prefix = collision_prefix(email, values, policy);
+    let sha256 = sha256_hex(&attachment.content);
+    // NOTE: The index is only locked to look the content up and to record it, so accounts write
+    // their files at the same time
+    let existing = dedupe_index
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .find(&sha256)
+        .map(|entry| entry.path.clone());
+    let outcome = match (options.attachments.duplicates, existing) {
+        (DuplicatePolicy::Skip, Some(existing)) => Ok(SaveOutcome::Duplicate(existing)),
+        (DuplicatePolicy::Link, Some(existing)) => link_without_collision(
+            &existing,
+            &save_location,
+            &filename,
+            &attachment.content,
+            policy,
+            &prefix,
+        ),
+        _ => save_without_collision(
+            &save_location,
+            &filename,
+            &attachment.content,
+            policy,
+            &prefix,
+        ),
+    }
+    .map_err(|e| AntworkerError::io(save_location.join(&filename), e))?;
+    if matches!(outcome, SaveOutcome::Duplicate(_)) {
+        return Ok(outcome);
+    }
+    let entry = IndexEntry {
+        account: origin.account.clone(),
+        mailbox: origin.mailbox.clone(),
+        uid: email.uid,
+        message_id: email.message_id.clone(),
+        category: action.category,
+        path: outcome.path().to_path_buf(),
+    };
+    record_saved(
+        &mut dedupe_index.lock().unwrap_or_else(|e| e.into_inner()),
+        &sha256,
+        outcome,
+        options.attachments.duplicates,
+        entry,
+    )
+}
+
+/// Record `entry`, the file just written for content `sha256`, in the index. When another
+/// account saved the same content while the file was written, the file is removed or
+/// hard-linked to that copy as `policy` says, and the outcome tells which.
+pub fn record_saved(
+    dedupe_index: &mut DedupeIndex,
+    sha256: &str,
+    outcome: SaveOutcome,
+    policy: DuplicatePolicy,
+    entry: IndexEntry,
+) -> Result<SaveOutcome, AntworkerError> {
+    let existing = dedupe_index
+        .find(sha256)
+        .map(|entry| entry.path.clone())
+        .filter(|existing| existing != outcome.path());
+    let outcome = match (policy, existing, outcome) {
+        (
+            DuplicatePolicy::Skip,
+            Some(existing),
+            SaveOutcome::New(path) | SaveOutcome::Renamed(path),
+        ) => {
+            fs::remove_file(&path).map_err(|e| AntworkerError::io(&path, e))?;
+            return Ok(SaveOutcome::Duplicate(existing));
+        }
+        (
+            DuplicatePolicy::Link,
+            Some(existing),
+            SaveOutcome::New(path) | SaveOutcome::Renamed(path),
+        ) => {
+            link_duplicate(&existing, &path).map_err(|e| AntworkerError::io(&path, e))?;
+            SaveOutcome::Linked(path)
+        }
+        (_, _, outcome) => outcome,
+    };
+    dedupe_index.insert(sha256, entry);
+    Ok(outcome)
 }
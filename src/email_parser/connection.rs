@@ -0,0 +1,273 @@
+use imap::{extensions::idle::SetReadTimeout, Client, Session};
+use native_tls::{Certificate, TlsConnector, TlsStream};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::{
+    fs,
+    io::{Read, Write},
+    net::{IpAddr, TcpStream, ToSocketAddrs},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use crate::{
+    auth::{
+        oauth::access_token,
+        sasl::{initial_response, OAuthAuthenticator},
+        tokens::TOKENS_PATH,
+    },
+    factories::credentials::EmailAccountBuilder,
+};
+
+/// How the connection to the IMAP server is secured.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Security {
+    /// TLS from the first byte, usually on port 993.
+    #[default]
+    Tls,
+    /// Plaintext upgraded with `STARTTLS`, usually on port 143.
+    Starttls,
+    /// No encryption at all, only allowed for servers on the loopback interface.
+    None,
+}
+
+impl Security {
+    pub fn default_port(&self) -> u16 {
+        match self {
+            Security::Tls => 993,
+            Security::Starttls | Security::None => 143,
+        }
+    }
+}
+
+/// Everything needed to open a connection besides the address.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct ConnectionSettings {
+    pub security: Security,
+    /// PEM bundle with extra certificate authorities to trust, e.g. a bridge's self-signed CA.
+    pub ca_file: Option<PathBuf>,
+    /// SHA-256 fingerprint of the server certificate. When set, it replaces chain validation.
+    pub fingerprint: Option<String>,
+}
+
+/// Whether `server` can only be reached through the loopback interface.
+pub fn is_loopback(server: &str) -> bool {
+    server.eq_ignore_ascii_case("localhost")
+        || server
+            .trim_matches(|c| c == '[' || c == ']')
+            .parse::<IpAddr>()
+            .is_ok_and(|ip| ip.is_loopback())
+}
+
+/// Normalize a fingerprint written as `AB:CD:…` or `abcd…` into lowercase hex, `None` if it is
+/// not a SHA-256 fingerprint.
+pub fn normalize_fingerprint(fingerprint: &str) -> Option<String> {
+    let hex: String = fingerprint
+        .chars()
+        .filter(|c| *c != ':')
+        .collect::<String>()
+        .to_lowercase();
+    match hex.len() == 64 && hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        true => Some(hex),
+        false => None,
+    }
+}
+
+/// Split a PEM bundle into its certificates.
+pub fn parse_ca_bundle(pem: &str) -> Result<Vec<Certificate>, native_tls::Error> {
+    const END: &str = "-----END CERTIFICATE-----";
+    pem.split_inclusive(END)
+        .filter(|block| block.contains(END))
+        .map(|block| Certificate::from_pem(block.trim().as_bytes()))
+        .collect()
+}
+
+/// Either side of a connection, so every security mode ends up with the same session type.
+#[derive(Debug)]
+pub enum ImapStream {
+    Tls(Box<TlsStream<TcpStream>>),
+    Plain(TcpStream),
+}
+
+impl Read for ImapStream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            ImapStream::Tls(stream) => stream.read(buf),
+            ImapStream::Plain(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for ImapStream {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            ImapStream::Tls(stream) => stream.write(buf),
+            ImapStream::Plain(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            ImapStream::Tls(stream) => stream.flush(),
+            ImapStream::Plain(stream) => stream.flush(),
+        }
+    }
+}
+
+impl SetReadTimeout for ImapStream {
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> imap::error::Result<()> {
+        match self {
+            ImapStream::Tls(stream) => stream.get_ref().set_read_timeout(timeout),
+            ImapStream::Plain(stream) => TcpStream::set_read_timeout(stream, timeout),
+        }
+        .map_err(imap::error::Error::Io)
+    }
+}
+
+pub type ImapSession = Session<ImapStream>;
+
+fn tls_connector(
+    settings: &ConnectionSettings,
+) -> Result<TlsConnector, Box<dyn std::error::Error>> {
+    let mut builder = TlsConnector::builder();
+    if let Some(ca_file) = &settings.ca_file {
+        let pem = fs::read_to_string(ca_file)
+            .map_err(|e| format!("Could not read CA bundle {}: {}", ca_file.display(), e))?;
+        for certificate in parse_ca_bundle(&pem)? {
+            builder.add_root_certificate(certificate);
+        }
+    }
+    if settings.fingerprint.is_some() {
+        // NOTE: The pinned fingerprint is checked right after the handshake instead
+        builder
+            .danger_accept_invalid_certs(true)
+            .danger_accept_invalid_hostnames(true);
+    }
+    Ok(builder.build()?)
+}
+
+/// SHA-256 of the DER encoded certificate in lowercase hex, what `openssl x509 -fingerprint
+/// -sha256` prints without colons.
+pub fn certificate_fingerprint(
+    certificate: &Certificate,
+) -> Result<String, Box<dyn std::error::Error>> {
+    Ok(format!("{:x}", Sha256::digest(certificate.to_der()?)))
+}
+
+fn check_fingerprint(
+    stream: &TlsStream<TcpStream>,
+    settings: &ConnectionSettings,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let Some(expected) = &settings.fingerprint else {
+        return Ok(());
+    };
+    let expected = normalize_fingerprint(expected)
+        .ok_or_else(|| format!("'{}' is not a SHA-256 fingerprint", expected))?;
+    let certificate = stream
+        .peer_certificate()?
+        .ok_or("Server did not present a certificate")?;
+    let actual = certificate_fingerprint(&certificate)?;
+    if actual != expected {
+        return Err(format!(
+            "Server certificate fingerprint {} does not match the pinned {}",
+            actual, expected
+        )
+        .into());
+    }
+    Ok(())
+}
+
+/// Read one CRLF terminated line without buffering past it, so nothing of a following TLS
+/// handshake is swallowed.
+fn read_line(stream: &mut TcpStream) -> std::io::Result<String> {
+    let mut line = Vec::new();
+    let mut byte = [0u8; 1];
+    while !line.ends_with(b"\n") {
+        if stream.read(&mut byte)? == 0 {
+            return Err(std::io::ErrorKind::UnexpectedEof.into());
+        }
+        line.push(byte[0]);
+    }
+    Ok(String::from_utf8_lossy(&line).trim_end().to_string())
+}
+
+/// Read the greeting and ask for `STARTTLS` on a fresh plaintext connection.
+fn starttls(stream: &mut TcpStream) -> Result<(), Box<dyn std::error::Error>> {
+    let greeting = read_line(stream)?;
+    if !greeting.starts_with("* OK") {
+        return Err(format!("Unexpected greeting: {}", greeting).into());
+    }
+    stream.write_all(b"a0 STARTTLS\r\n")?;
+    loop {
+        let line = read_line(stream)?;
+        if let Some(status) = line.strip_prefix("a0 ") {
+            return match status.starts_with("OK") {
+                true => Ok(()),
+                false => Err(format!("Server refused STARTTLS: {}", status).into()),
+            };
+        }
+    }
+}
+
+/// Open a connection secured the way the account asks for and log in.
+pub fn connect(
+    email_account: &EmailAccountBuilder,
+) -> Result<ImapSession, Box<dyn std::error::Error>> {
+    let settings = &email_account.connection;
+    let address = (&*email_account.server, email_account.port)
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| format!("Could not resolve {}", email_account.server))?;
+    if settings.security == Security::None && !address.ip().is_loopback() {
+        return Err(format!(
+            "Refusing to send credentials unencrypted to {}, security = \"none\" only works on loopback",
+            address
+        )
+        .into());
+    }
+    let mut tcp = TcpStream::connect(address)?;
+    let stream = match settings.security {
+        Security::None => ImapStream::Plain(tcp),
+        Security::Tls | Security::Starttls => {
+            if settings.security == Security::Starttls {
+                starttls(&mut tcp)?;
+            }
+            let tls = tls_connector(settings)?
+                .connect(&email_account.server, tcp)
+                .map_err(|e| format!("TLS handshake with {} failed: {}", address, e))?;
+            check_fingerprint(&tls, settings)?;
+            ImapStream::Tls(Box::new(tls))
+        }
+    };
+    let mut client = Client::new(stream);
+    // NOTE: After STARTTLS the server does not greet again
+    if settings.security != Security::Starttls {
+        client.read_greeting()?;
+    }
+    let imap_session = match &email_account.oauth2 {
+        Some(oauth2) => {
+            let token = access_token(
+                &email_account.account,
+                oauth2,
+                Path::new(TOKENS_PATH.as_str()),
+            )?;
+            let authenticator = OAuthAuthenticator {
+                response: initial_response(
+                    oauth2.mechanism,
+                    &email_account.email,
+                    &token,
+                    &email_account.server,
+                    email_account.port,
+                ),
+            };
+            client
+                .authenticate(oauth2.mechanism.name(), &authenticator)
+                .map_err(|e| e.0)?
+        }
+        None => client
+            .login(&email_account.email, &email_account.password)
+            .map_err(|e| e.0)?,
+    };
+    Ok(imap_session)
+}
@@ -0,0 +1,13 @@
+use mailparse::parse_header;
+
+/// Decode a raw header value such as an envelope subject or display name. RFC 2047 encoded words
+/// in any charset (Q and B encodings) are decoded and whitespace between adjacent encoded words
+/// is dropped, while plain text (underscores included) is left untouched.
+pub fn decode_header_value(raw: &[u8]) -> String {
+    let mut header = b"X: ".to_vec();
+    header.extend_from_slice(raw);
+    match parse_header(&header) {
+        Ok((parsed, _)) => parsed.get_value().trim().to_string(),
+        Err(_) => String::from_utf8_lossy(raw).trim().to_string(),
+    }
+}
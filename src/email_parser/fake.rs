@@ -0,0 +1,405 @@
+use chrono::NaiveDate;
+use mailparse::parse_mail;
+use std::{collections::BTreeMap, time::Duration};
+
+use super::{
+    parser::get_email_details_from_mail,
+    source::{FetchedDetails, ListedMailbox, MailSource, MailboxStatus, SourceResult},
+};
+use crate::error::AntworkerError;
+
+#[derive(Debug, Clone)]
+pub struct FakeMessage {
+    pub uid: u32,
+    pub raw: Vec<u8>,
+    pub flags: Vec<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct FakeMailbox {
+    pub uid_validity: u32,
+    pub uid_next: u32,
+    pub selectable: bool,
+    pub messages: Vec<FakeMessage>,
+}
+
+impl Default for FakeMailbox {
+    fn default() -> Self {
+        FakeMailbox {
+            uid_validity: 1,
+            uid_next: 1,
+            selectable: true,
+            messages: Vec::new(),
+        }
+    }
+}
+
+impl FakeMailbox {
+    fn append(&mut self, raw: Vec<u8>, flags: Vec<String>) -> u32 {
+        let uid = self.uid_next;
+        self.uid_next += 1;
+        self.messages.push(FakeMessage { uid, raw, flags });
+        uid
+    }
+}
+
+/// In-memory mail server for tests. Understands the subset of `SEARCH` the pipeline sends and
+/// records every command that changes a mailbox in `log`.
+#[derive(Debug, Default, Clone)]
+pub struct FakeMailSource {
+    pub mailboxes: BTreeMap<String, FakeMailbox>,
+    pub capabilities: Vec<String>,
+    pub log: Vec<String>,
+    /// UIDs whose body cannot be fetched, like a message the server fails to read.
+    pub unavailable: Vec<u32>,
+    /// UIDs fetched without an envelope, like a message the server cannot describe.
+    pub broken: Vec<u32>,
+    /// UIDs whose body fetch loses the connection.
+    pub dropped: Vec<u32>,
+    /// `(mailbox, raw message)` delivered one per IDLE or NOOP, the connection drops once they
+    /// run out.
+    pub incoming: Vec<(String, String)>,
+    selected: Option<String>,
+    read_only: bool,
+}
+
+impl FakeMailSource {
+    pub fn new(capabilities: &[&str]) -> Self {
+        FakeMailSource {
+            capabilities: capabilities.iter().map(|c| c.to_string()).collect(),
+            ..Default::default()
+        }
+    }
+
+    pub fn mailbox(&mut self, name: &str) -> &mut FakeMailbox {
+        self.mailboxes.entry(name.to_string()).or_default()
+    }
+
+    /// Deliver a message, returning its UID.
+    pub fn deliver(&mut self, mailbox: &str, raw: &str) -> u32 {
+        self.mailbox(mailbox)
+            .append(raw.as_bytes().to_vec(), Vec::new())
+    }
+
+    pub fn uids(&self, mailbox: &str) -> Vec<u32> {
+        self.mailboxes
+            .get(mailbox)
+            .map(|mailbox| mailbox.messages.iter().map(|m| m.uid).collect())
+            .unwrap_or_default()
+    }
+
+    pub fn flags(&self, mailbox: &str, uid: u32) -> Vec<String> {
+        self.mailboxes
+            .get(mailbox)
+            .and_then(|mailbox| mailbox.messages.iter().find(|m| m.uid == uid))
+            .map(|message| message.flags.clone())
+            .unwrap_or_default()
+    }
+
+    fn open(&mut self, mailbox: &str, read_only: bool) -> SourceResult<MailboxStatus> {
+        let found = self
+            .mailboxes
+            .get(mailbox)
+            .filter(|found| found.selectable)
+            .ok_or_else(|| format!("NO Mailbox {} does not exist", mailbox))?;
+        let status = MailboxStatus {
+            uid_validity: Some(found.uid_validity),
+            uid_next: Some(found.uid_next),
+        };
+        self.selected = Some(mailbox.to_string());
+        self.read_only = read_only;
+        Ok(status)
+    }
+
+    /// Deliver the next incoming message, or lose the connection when there is none left.
+    fn wait(&mut self, command: &str) -> SourceResult<()> {
+        if self.incoming.is_empty() {
+            return Err(Box::new(imap::error::Error::ConnectionLost));
+        }
+        let (mailbox, raw) = self.incoming.remove(0);
+        self.deliver(&mailbox, &raw);
+        self.log.push(command.to_string());
+        Ok(())
+    }
+
+    fn selected(&mut self) -> SourceResult<&mut FakeMailbox> {
+        let name = self.selected.clone().ok_or("BAD No mailbox selected")?;
+        Ok(self.mailboxes.get_mut(&name).unwrap())
+    }
+
+    fn writable(&mut self) -> SourceResult<&mut FakeMailbox> {
+        if self.read_only {
+            return Err("NO Mailbox is read-only".into());
+        }
+        self.selected()
+    }
+
+    fn selected_uids(&mut self, uid_set: &str) -> SourceResult<Vec<u32>> {
+        let mailbox = self.selected()?;
+        let highest = mailbox.messages.iter().map(|m| m.uid).max().unwrap_or(0);
+        Ok(mailbox
+            .messages
+            .iter()
+            .map(|m| m.uid)
+            .filter(|uid| in_uid_set(uid_set, *uid, highest))
+            .collect())
+    }
+}
+
+/// Whether `uid` is in an IMAP sequence set like `1:3,7,9:*`, `*` being the `highest` UID.
+fn in_uid_set(uid_set: &str, uid: u32, highest: u32) -> bool {
+    let bound = |value: &str| match value {
+        "*" => Some(highest),
+        _ => value.parse::<u32>().ok(),
+    };
+    uid_set.split(',').any(|range| {
+        let (start, end) = range.split_once(':').unwrap_or((range, range));
+        match (bound(start), bound(end)) {
+            // NOTE: `n:m` is the same as `m:n`, so `n:*` always contains the highest UID
+            (Some(start), Some(end)) => (start.min(end)..=start.max(end)).contains(&uid),
+            _ => false,
+        }
+    })
+}
+
+fn tokenize(query: &str) -> Vec<String> {
+    let mut tokens = Vec::new();
+    let mut chars = query.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            ' ' => continue,
+            '"' => {
+                let mut token = String::new();
+                while let Some(c) = chars.next() {
+                    match c {
+                        '\\' => token.extend(chars.next()),
+                        '"' => break,
+                        _ => token.push(c),
+                    }
+                }
+                tokens.push(token);
+            }
+            _ => {
+                let mut token = c.to_string();
+                while let Some(c) = chars.next_if(|c| *c != ' ') {
+                    token.push(c);
+                }
+                tokens.push(token);
+            }
+        }
+    }
+    tokens
+}
+
+fn parse_imap_date(value: &str) -> SourceResult<NaiveDate> {
+    Ok(NaiveDate::parse_from_str(value, "%d-%b-%Y")?)
+}
+
+/// Evaluate one search key, consuming its arguments from `tokens`.
+fn matches_key(
+    tokens: &mut std::vec::IntoIter<String>,
+    message: &FakeMessage,
+    highest: u32,
+) -> SourceResult<bool> {
+    let key = tokens.next().ok_or("BAD Missing search key")?;
+    let mut argument = || {
+        tokens
+            .next()
+            .ok_or(format!("BAD {} needs an argument", key))
+    };
+    let mail = parse_mail(&message.raw)?;
+    let email = get_email_details_from_mail(&mail, message.uid)?;
+    Ok(match key.to_uppercase().as_str() {
+        "ALL" => true,
+        "UID" => in_uid_set(&argument()?, message.uid, highest),
+        "FROM" => {
+            let needle = argument()?.to_lowercase();
+            email
+                .from
+                .iter()
+                .any(|from| from.to_lowercase().contains(&needle))
+        }
+        "SENTSINCE" => email.date.date_naive() >= parse_imap_date(&argument()?)?,
+        "SENTBEFORE" => email.date.date_naive() < parse_imap_date(&argument()?)?,
+        "UNKEYWORD" => !message.flags.contains(&argument()?),
+        "OR" => {
+            let left = matches_key(tokens, message, highest)?;
+            let right = matches_key(tokens, message, highest)?;
+            left || right
+        }
+        other => return Err(format!("BAD Unsupported search key {}", other).into()),
+    })
+}
+
+impl MailSource for FakeMailSource {
+    fn has_capability(&mut self, capability: &str) -> SourceResult<bool> {
+        Ok(self
+            .capabilities
+            .iter()
+            .any(|c| c.eq_ignore_ascii_case(capability)))
+    }
+
+    fn list(&mut self) -> SourceResult<Vec<ListedMailbox>> {
+        Ok(self
+            .mailboxes
+            .iter()
+            .map(|(name, mailbox)| ListedMailbox {
+                name: name.clone(),
+                selectable: mailbox.selectable,
+            })
+            .collect())
+    }
+
+    fn select(&mut self, mailbox: &str) -> SourceResult<MailboxStatus> {
+        self.open(mailbox, false)
+    }
+
+    fn examine(&mut self, mailbox: &str) -> SourceResult<MailboxStatus> {
+        self.open(mailbox, true)
+    }
+
+    fn search(&mut self, query: &str) -> SourceResult<Vec<u32>> {
+        let mailbox = self.selected()?;
+        let highest = mailbox.messages.iter().map(|m| m.uid).max().unwrap_or(0);
+        let mut uids = Vec::new();
+        for message in mailbox.messages.iter() {
+            // NOTE: Top-level keys are ANDed
+            let mut tokens = tokenize(query).into_iter();
+            let mut matches = true;
+            while tokens.len() > 0 {
+                matches &= matches_key(&mut tokens, message, highest)?;
+            }
+            if matches {
+                uids.push(message.uid);
+            }
+        }
+        Ok(uids)
+    }
+
+    fn fetch_details(&mut self, uid_set: &str) -> SourceResult<Vec<FetchedDetails>> {
+        let uids = self.selected_uids(uid_set)?;
+        let broken = self.broken.clone();
+        let mailbox = self.selected()?;
+        Ok(mailbox
+            .messages
+            .iter()
+            .filter(|message| uids.contains(&message.uid))
+            .map(|message| {
+                let details = match broken.contains(&message.uid) {
+                    true => Err(AntworkerError::imap(format!(
+                        "message UID {} has no envelope",
+                        message.uid
+                    ))),
+                    false => parse_mail(&message.raw)
+                        .map_err(AntworkerError::from)
+                        .and_then(|mail| get_email_details_from_mail(&mail, message.uid)),
+                };
+                (Some(message.uid), details)
+            })
+            .collect())
+    }
+
+    fn fetch_body(&mut self, uid: u32, peek: bool) -> SourceResult<Vec<u8>> {
+        if self.dropped.contains(&uid) {
+            return Err(Box::new(imap::error::Error::ConnectionLost));
+        }
+        if self.unavailable.contains(&uid) {
+            return Err(format!("NO [UNAVAILABLE] Message UID {} cannot be read", uid).into());
+        }
+        let read_only = self.read_only;
+        let message = self
+            .selected()?
+            .messages
+            .iter_mut()
+            .find(|message| message.uid == uid)
+            .ok_or_else(|| format!("Message UID {} has no body", uid))?;
+        if !peek && !read_only && !message.flags.iter().any(|flag| flag == "\\Seen") {
+            message.flags.push("\\Seen".to_string());
+        }
+        Ok(message.raw.clone())
+    }
+
+    fn add_flags(&mut self, uid_set: &str, flags: &[&str]) -> SourceResult<()> {
+        let uids = self.selected_uids(uid_set)?;
+        for message in self.writable()?.messages.iter_mut() {
+            if !uids.contains(&message.uid) {
+                continue;
+            }
+            for flag in flags {
+                if !message.flags.iter().any(|f| f == flag) {
+                    message.flags.push(flag.to_string());
+                }
+            }
+        }
+        self.log
+            .push(format!("STORE {} +FLAGS ({})", uid_set, flags.join(" ")));
+        Ok(())
+    }
+
+    fn copy(&mut self, uid_set: &str, mailbox: &str) -> SourceResult<()> {
+        let uids = self.selected_uids(uid_set)?;
+        let copies: Vec<FakeMessage> = self
+            .selected()?
+            .messages
+            .iter()
+            .filter(|message| uids.contains(&message.uid))
+            .cloned()
+            .collect();
+        let target = self
+            .mailboxes
+            .get_mut(mailbox)
+            .ok_or_else(|| format!("NO [TRYCREATE] Mailbox {} does not exist", mailbox))?;
+        for copy in copies {
+            target.append(copy.raw, copy.flags);
+        }
+        self.log.push(format!("COPY {} {}", uid_set, mailbox));
+        Ok(())
+    }
+
+    fn move_messages(&mut self, uid_set: &str, mailbox: &str) -> SourceResult<()> {
+        if !self.has_capability("MOVE")? {
+            return Err("BAD MOVE is not supported".into());
+        }
+        let uids = self.selected_uids(uid_set)?;
+        self.copy(uid_set, mailbox)?;
+        self.log.pop();
+        self.writable()?
+            .messages
+            .retain(|message| !uids.contains(&message.uid));
+        self.log.push(format!("MOVE {} {}", uid_set, mailbox));
+        Ok(())
+    }
+
+    fn expunge(&mut self, uid_set: Option<&str>) -> SourceResult<()> {
+        let uids = match uid_set {
+            Some(uid_set) => Some(self.selected_uids(uid_set)?),
+            None => None,
+        };
+        self.writable()?.messages.retain(|message| {
+            let deleted = message.flags.iter().any(|flag| flag == "\\Deleted");
+            !deleted
+                || uids
+                    .as_ref()
+                    .is_some_and(|uids| !uids.contains(&message.uid))
+        });
+        self.log.push(match uid_set {
+            Some(uid_set) => format!("UID EXPUNGE {}", uid_set),
+            None => "EXPUNGE".to_string(),
+        });
+        Ok(())
+    }
+
+    fn idle(&mut self, _keepalive: Duration) -> SourceResult<()> {
+        self.wait("IDLE")
+    }
+
+    fn noop(&mut self) -> SourceResult<()> {
+        self.wait("NOOP")
+    }
+
+    fn logout(&mut self) -> SourceResult<()> {
+        self.selected = None;
+        self.log.push("LOGOUT".to_string());
+        Ok(())
+    }
+}
@@ -0,0 +1,130 @@
+use mailparse::ParsedMail;
+
+use super::decode::decode_header_value;
+
+/// Leaves room for a collision suffix while staying below the common 255 byte limit.
+const MAX_FILENAME_BYTES: usize = 200;
+const WINDOWS_RESERVED_CHARS: &str = "<>:\"/\\|?*";
+const WINDOWS_RESERVED_NAMES: [&str; 22] = [
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// The attachment's filename as sent, preferring `Content-Disposition` (with RFC 2231 charsets
+/// and continuations already merged by mailparse) over the legacy `Content-Type` name. Encoded
+/// words some clients put inside quoted parameters are decoded as well.
+pub fn resolve_filename(part: &ParsedMail) -> Option<String> {
+    let disposition = part.get_content_disposition();
+    let raw = disposition
+        .params
+        .get("filename")
+        .or_else(|| part.ctype.params.get("name"))
+        .cloned()
+        .or_else(|| {
+            // NOTE: mailparse keeps `filename*` as is when it does not know the charset
+            disposition
+                .params
+                .get("filename*")
+                .map(|value| percent_decode(value.splitn(3, '\'').last().unwrap_or_default()))
+        })?;
+    let decoded = decode_header_value(raw.as_bytes());
+    match decoded.is_empty() {
+        true => None,
+        false => Some(decoded),
+    }
+}
+
+fn percent_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut index = 0;
+    while index < bytes.len() {
+        let hex = bytes
+            .get(index + 1..index + 3)
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match (bytes[index], hex) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                index += 3;
+            }
+            (byte, _) => {
+                decoded.push(byte);
+                index += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).to_string()
+}
+
+pub fn transliterate_polish(name: &str) -> String {
+    name.chars()
+        .map(|c| match c {
+            'ą' => 'a',
+            'ć' => 'c',
+            'ę' => 'e',
+            'ł' => 'l',
+            'ń' => 'n',
+            'ó' => 'o',
+            'ś' => 's',
+            'ź' | 'ż' => 'z',
+            'Ą' => 'A',
+            'Ć' => 'C',
+            'Ę' => 'E',
+            'Ł' => 'L',
+            'Ń' => 'N',
+            'Ó' => 'O',
+            'Ś' => 'S',
+            'Ź' | 'Ż' => 'Z',
+            c => c,
+        })
+        .collect()
+}
+
+/// Turn a sender-controlled name into a safe single path component: no directories, no `..`,
+/// no control or Windows-reserved characters and no more than `MAX_FILENAME_BYTES`. Returns
+/// `None` when nothing usable is left.
+pub fn sanitize_filename(name: &str, transliterate: bool) -> Option<String> {
+    // Only the last path component counts, whichever separator the sender used
+    let base_name = name.rsplit(['/', '\\']).next().unwrap_or_default();
+    let base_name = match transliterate {
+        true => transliterate_polish(base_name),
+        false => base_name.to_string(),
+    };
+    let cleaned: String = base_name
+        .chars()
+        .filter(|c| !c.is_control())
+        .map(|c| match c {
+            c if c.is_whitespace() || WINDOWS_RESERVED_CHARS.contains(c) => '_',
+            c => c,
+        })
+        .fold(String::new(), |mut cleaned, c| {
+            // Collapse the runs of underscores left behind by replaced characters
+            if !(c == '_' && cleaned.ends_with('_')) {
+                cleaned.push(c);
+            }
+            cleaned
+        });
+    // Leading dots would hide the file or form `..`, trailing ones are dropped by Windows
+    let cleaned = cleaned.trim_matches(|c| c == '.' || c == '_');
+    if cleaned.is_empty() {
+        return None;
+    }
+    let (stem, extension) = match cleaned.rsplit_once('.') {
+        Some((stem, extension)) if !stem.is_empty() && extension.len() <= 10 => (
+            stem.trim_end_matches(['.', '_']).to_string(),
+            format!(".{}", extension),
+        ),
+        _ => (cleaned.to_string(), String::new()),
+    };
+    let stem = match WINDOWS_RESERVED_NAMES.contains(&stem.to_uppercase().as_str()) {
+        true => format!("_{}", stem),
+        false => stem,
+    };
+    let max_stem_bytes = MAX_FILENAME_BYTES.saturating_sub(extension.len());
+    let mut end = stem.len().min(max_stem_bytes);
+    while !stem.is_char_boundary(end) {
+        end -= 1;
+    }
+    Some(format!("{}{}", &stem[..end], extension))
+}
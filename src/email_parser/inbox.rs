@@ -1,51 +1,304 @@
-use std::{collections::HashMap, time::Duration};
+use chrono_tz::Tz;
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::{sync::Semaphore, task::JoinSet};
 
-use imap::Session;
 use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
-use native_tls::TlsStream;
 
 use crate::{
-    factories::credentials::EmailAccountBuilder, rules::define::define_rules,
+    config::{
+        accounts::{AccountConfig, AttachmentsConfig},
+        secrets::SecretsConfig,
+    },
+    datemath::{date::DateRange, period::Period},
+    error::{AntworkerError, Failure},
+    factories::credentials::EmailAccountBuilder,
+    io::{dedupe::DedupeIndex, writer::SaveReport},
+    retry::RetryPolicy,
+    rules::{define::define_rules, file::Rule, search::compress_uid_set},
+    state::store::{state_key, UidStore},
 };
 
 use super::{
-    attachment::get_and_save_attachments,
-    parser::{fetch_emails, get_email_details, EmailDetails},
+    actions::apply_post_actions,
+    attachment::{get_and_save_attachments, into_report, MessageOrigin},
+    connection::connect,
+    mailbox::resolve_mailboxes,
+    main::with_stored_uid_set,
+    parser::{get_email_details, search_emails},
+    reconnect::ReconnectingSource,
+    source::MailSource,
 };
 
-async fn connect(
+#[derive(Debug, Default, Clone)]
+pub struct ProcessOptions {
+    /// Print why every MIME part was saved or skipped.
+    pub explain: bool,
+    pub attachments: AttachmentsConfig,
+    /// Validated rules from the rules file, in file order.
+    pub rules: Vec<Rule>,
+    /// How dropped IMAP connections are retried.
+    pub retry: RetryPolicy,
+    /// Calendar the timeframe and the date templates follow.
+    pub timezone: Tz,
+    /// Only messages sent within these days are considered, any message without it. Regular
+    /// runs only apply it while a mailbox has no stored UID marker yet.
+    pub timeframe: Option<DateRange>,
+    /// Search the whole mailbox instead of what arrived since the last run and leave the stored
+    /// UID marker alone, for runs over a period other than the current month.
+    pub rescan: bool,
+}
+
+impl ProcessOptions {
+    /// One set of options per month of the timeframe when rescanning, so a long period is
+    /// searched a month at a time. Otherwise just these options.
+    pub fn by_month(&self) -> Vec<ProcessOptions> {
+        match (self.rescan, self.timeframe) {
+            (true, Some(timeframe)) => timeframe
+                .months()
+                .into_iter()
+                .map(|period| ProcessOptions {
+                    timeframe: Some(DateRange {
+                        from: period.first_day().max(timeframe.from),
+                        to: period.last_day().min(timeframe.to),
+                    }),
+                    ..self.clone()
+                })
+                .collect(),
+            _ => vec![self.clone()],
+        }
+    }
+}
+
+/// Select the account's mailbox, save attachments of every message that arrived since the last
+/// run and move the stored UID marker forward. Messages that fail are reported and stored with
+/// it, so the next run tries them again without handling the others twice.
+pub fn process_new_messages(
+    source: &mut dyn MailSource,
     email_account: &EmailAccountBuilder,
-) -> imap::error::Result<Session<TlsStream<std::net::TcpStream>>> {
-    let tls = native_tls::TlsConnector::builder().build().unwrap();
-    let client = imap::connect(
-        (&*email_account.server, email_account.port), // Pass a reference to credentials.server
-        &email_account.server,                        // Pass a reference to credentials.server
-        &tls,
-    )?;
-    let imap_session = client
-        .login(&email_account.email, &email_account.password)
-        .map_err(|e| e.0)?;
-    Ok(imap_session)
+    uid_store: &Mutex<UidStore>,
+    dedupe_index: &Mutex<DedupeIndex>,
+    multi_progress: &MultiProgress,
+    options: &ProcessOptions,
+) -> Result<SaveReport, AntworkerError> {
+    let mailbox = source
+        .select(&email_account.mailbox)
+        .map_err(AntworkerError::imap)?;
+    let key = state_key(
+        &email_account.server,
+        &email_account.email,
+        &email_account.mailbox,
+    );
+    let uid_validity = mailbox.uid_validity.unwrap_or_default();
+    // NOTE: A changed UIDVALIDITY invalidates every stored UID, so start over from scratch
+    let (last_uid, retried_uids) = match options.rescan {
+        true => (0, Vec::new()),
+        false => {
+            let uid_store = uid_store.lock().unwrap_or_else(|e| e.into_inner());
+            (
+                uid_store.last_uid(&key, uid_validity),
+                uid_store.failed_uids(&key, uid_validity),
+            )
+        }
+    };
+    let uid_set = match last_uid {
+        0 => "1:*",
+        _ => email_account.uid_set.as_str(),
+    };
+    // NOTE: Everything above the marker is new whatever date it was sent on, e.g. last month's
+    // invoice delivered late, and the marker moves past it below. Only rescans and the first
+    // run of a mailbox, which would otherwise walk all of it, are limited to the timeframe.
+    let timeframe = match (options.rescan, last_uid) {
+        (false, 1..) => None,
+        _ => options.timeframe,
+    };
+    let rules = define_rules(&options.rules, timeframe, options.timezone);
+    let uids = search_emails(source, &rules, uid_set, &email_account.post_actions)
+        .map_err(AntworkerError::imap)?;
+    // NOTE: Everything below UIDNEXT existed when the mailbox was selected and has now been
+    // either searched out or considered, so it never needs to be looked at again.
+    let highest_uid = match mailbox.uid_next {
+        Some(uid_next) => uid_next.saturating_sub(1),
+        None => uids.iter().copied().max().unwrap_or_default(),
+    };
+    // NOTE: `n:*` always matches the newest message even when n is above it, skip it if seen
+    let candidates: Vec<u32> = uids
+        .into_iter()
+        .filter(|uid| *uid > last_uid || retried_uids.contains(uid))
+        .collect();
+    let mut fetched = Vec::new();
+    if !candidates.is_empty() {
+        fetched = get_email_details(
+            source,
+            &compress_uid_set(&candidates),
+            &rules,
+            &email_account.account,
+        )
+        .map_err(AntworkerError::imap)?;
+    }
+    // NOTE: A message whose envelope cannot be read is reported like one whose body cannot be
+    // fetched, the rest of the batch still goes on
+    let origin = MessageOrigin::from(email_account);
+    let mut email_details = Vec::new();
+    let mut unreadable = Vec::new();
+    let mut unidentified = Vec::new();
+    for (uid, details) in fetched {
+        match (uid, details) {
+            (_, Ok(email)) => email_details.push(email),
+            (Some(uid), Err(error)) => unreadable.push((uid, Err(error))),
+            (None, Err(error)) => unidentified.push(Failure {
+                item: format!("{}/{}", origin.account, origin.mailbox),
+                error,
+            }),
+        }
+    }
+    let mut outcomes = get_and_save_attachments(
+        email_account,
+        &email_details,
+        source,
+        &rules,
+        dedupe_index,
+        multi_progress,
+        options,
+    );
+    outcomes.extend(unreadable);
+    dedupe_index
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .save()
+        .map_err(|e| AntworkerError::Io(format!("could not save the dedupe index: {}", e)))?;
+    let failed_uids: Vec<u32> = outcomes
+        .iter()
+        .filter(|(_, outcome)| outcome.is_err())
+        .map(|(uid, _)| *uid)
+        .collect();
+    // NOTE: Duplicates count as handled too, their content is already on disk
+    let mut saved_uids: Vec<u32> = outcomes
+        .iter()
+        .map(|(uid, _)| *uid)
+        .filter(|uid| !failed_uids.contains(uid))
+        .collect();
+    saved_uids.dedup();
+    apply_post_actions(source, &email_account.post_actions, &saved_uids)
+        .map_err(AntworkerError::imap)?;
+    if !options.rescan {
+        let mut uid_store = uid_store.lock().unwrap_or_else(|e| e.into_inner());
+        uid_store.record(&key, uid_validity, highest_uid.max(last_uid), &failed_uids);
+        uid_store
+            .save()
+            .map_err(|e| AntworkerError::Io(format!("could not save the UID state: {}", e)))?;
+    }
+    let mut report = into_report(&origin, outcomes);
+    report.failures.extend(unidentified);
+    Ok(report)
 }
 
-async fn process_inbox(
+fn process_inbox(
+    inbox_name: &str,
     email_account: &EmailAccountBuilder,
+    uid_store: &Mutex<UidStore>,
+    dedupe_index: &Mutex<DedupeIndex>,
+    progress_bar: &ProgressBar,
     multi_progress: &MultiProgress,
-) -> Result<Vec<EmailDetails>, Box<dyn std::error::Error>> {
-    let mut imap_session = connect(email_account).await?;
-    let messages = fetch_emails(&mut imap_session, &email_account.uid_set)?;
-    let rules = define_rules();
-    let email_details = get_email_details(&messages, &rules)?;
-    get_and_save_attachments(&email_details, &mut imap_session, &multi_progress);
-    imap_session.logout()?;
-    Ok(email_details)
+    options: &ProcessOptions,
+) -> Result<SaveReport, AntworkerError> {
+    let mut imap_session = ReconnectingSource::connect(options.retry, || connect(email_account))
+        .map_err(AntworkerError::imap)?;
+    let mut report = SaveReport::default();
+    let mailboxes = resolve_mailboxes(&mut imap_session, &email_account.mailboxes)
+        .map_err(AntworkerError::imap)?;
+    for mailbox in mailboxes {
+        let mailbox_account = with_stored_uid_set(
+            email_account.clone().mailbox(&mailbox.raw),
+            &uid_store.lock().unwrap_or_else(|e| e.into_inner()),
+        );
+        for month_options in options.by_month() {
+            progress_bar.set_message(match (options.rescan, month_options.timeframe) {
+                (true, Some(timeframe)) => format!(
+                    "📥 Processing inbox: {}/{} {}",
+                    inbox_name,
+                    mailbox.decoded,
+                    Period::of(timeframe.from)
+                ),
+                _ => format!("📥 Processing inbox: {}/{}", inbox_name, mailbox.decoded),
+            });
+            report.extend(process_new_messages(
+                &mut imap_session,
+                &mailbox_account,
+                uid_store,
+                dedupe_index,
+                multi_progress,
+                &month_options,
+            )?);
+        }
+    }
+    imap_session.logout().map_err(AntworkerError::imap)?;
+    Ok(report)
 }
 
-pub async fn process_all_inboxes(
-    inboxes: HashMap<&str, EmailAccountBuilder>,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let m = MultiProgress::new();
-    let pb = m.add(ProgressBar::new_spinner());
+/// Outcome of every account, keyed by account name.
+pub type AccountResults = BTreeMap<String, Result<SaveReport, String>>;
+
+/// Run `job` for every account on the blocking thread pool, at most `concurrency` at a time.
+/// The password of an account is only looked up by its own task. An account whose password
+/// cannot be resolved, that fails or panics is recorded and does not stop the others. Locks it
+/// poisoned by panicking are still taken by the others, they only miss its own last update.
+pub async fn run_accounts<F>(
+    accounts: BTreeMap<String, AccountConfig>,
+    secrets: Option<SecretsConfig>,
+    concurrency: usize,
+    job: F,
+) -> AccountResults
+where
+    F: Fn(&str, &EmailAccountBuilder) -> Result<SaveReport, String> + Send + Sync + 'static,
+{
+    let job = Arc::new(job);
+    let secrets = Arc::new(secrets);
+    let semaphore = Arc::new(Semaphore::new(concurrency.max(1)));
+    let mut tasks = JoinSet::new();
+    let mut names = BTreeMap::new();
+    for (name, account) in accounts {
+        let job = Arc::clone(&job);
+        let secrets = Arc::clone(&secrets);
+        let semaphore = Arc::clone(&semaphore);
+        let task_name = name.clone();
+        let handle = tasks.spawn(async move {
+            let _permit = semaphore.acquire_owned().await;
+            let task_name = name.clone();
+            let result = tokio::task::spawn_blocking(move || {
+                let email_account = account
+                    .builder(&task_name, secrets.as_ref().as_ref())
+                    .map_err(|e| e.to_string())?;
+                job(&task_name, &email_account)
+            })
+            .await
+            .unwrap_or_else(|e| Err(format!("processing panicked: {}", e)));
+            (name, result)
+        });
+        names.insert(handle.id(), task_name);
+    }
+    let mut results = BTreeMap::new();
+    while let Some(joined) = tasks.join_next_with_id().await {
+        match joined {
+            Ok((_, (name, result))) => {
+                results.insert(name, result);
+            }
+            // NOTE: The account still shows up in the summary, and fails the run
+            Err(e) => {
+                if let Some(name) = names.remove(&e.id()) {
+                    results.insert(name, Err(format!("processing stopped: {}", e)));
+                }
+            }
+        }
+    }
+    results
+}
+
+fn spinner(multi_progress: &MultiProgress) -> ProgressBar {
+    let pb = multi_progress.add(ProgressBar::new_spinner());
     pb.enable_steady_tick(Duration::from_millis(120));
     pb.set_style(
         ProgressStyle::with_template("{spinner:.blue} {msg}")
@@ -60,11 +313,73 @@ pub async fn process_all_inboxes(
                 "▪▪▪▪▪",
             ]),
     );
-    for (inbox_name, credentials) in inboxes.iter() {
-        let inbox_name_str = format!("📥 Processing inbox: {}", inbox_name);
-        pb.set_message(inbox_name_str.clone());
-        process_inbox(credentials, &m).await?;
+    pb
+}
+
+/// Process every account, `concurrency` of them at the same time, each with its own spinner.
+/// Fails with the number of accounts and messages that could not be processed.
+pub async fn process_all_inboxes(
+    accounts: BTreeMap<String, AccountConfig>,
+    secrets: Option<SecretsConfig>,
+    uid_store: Arc<Mutex<UidStore>>,
+    dedupe_index: Arc<Mutex<DedupeIndex>>,
+    options: &ProcessOptions,
+    concurrency: usize,
+) -> Result<(), AntworkerError> {
+    let multi_progress = MultiProgress::new();
+    let options = options.clone();
+    let job_progress = multi_progress.clone();
+    let results = run_accounts(
+        accounts,
+        secrets,
+        concurrency,
+        move |inbox_name, email_account| {
+            let pb = spinner(&job_progress);
+            pb.set_message(format!("📥 Processing inbox: {}", inbox_name));
+            let result = process_inbox(
+                inbox_name,
+                email_account,
+                &uid_store,
+                &dedupe_index,
+                &pb,
+                &job_progress,
+                &options,
+            )
+            .map_err(|e| e.to_string());
+            match &result {
+                Ok(_) => {
+                    pb.finish_with_message(format!("🏁 Done processing inbox: {}", inbox_name))
+                }
+                Err(_) => {
+                    pb.finish_with_message(format!("❌ Failed processing inbox: {}", inbox_name))
+                }
+            }
+            result
+        },
+    )
+    .await;
+    let mut report = SaveReport::default();
+    let mut failed_accounts = 0;
+    for (inbox_name, result) in results {
+        match result {
+            Ok(account_report) => {
+                println!(
+                    "✅ {}: {} attachments, {} failed",
+                    inbox_name,
+                    account_report.outcomes.len(),
+                    account_report.failures.len()
+                );
+                report.extend(account_report);
+            }
+            Err(e) => {
+                println!("❌ {}: {}", inbox_name, e);
+                failed_accounts += 1;
+            }
+        }
+    }
+    println!("{}", report);
+    match failed_accounts + report.failures.len() {
+        0 => Ok(()),
+        failed => Err(AntworkerError::Incomplete(failed)),
     }
-    pb.finish_with_message("🏁 Done processing emails");
-    Ok(())
 }
@@ -0,0 +1,148 @@
+use base64::{
+    alphabet::IMAP_MUTF7,
+    engine::{DecodePaddingMode, GeneralPurpose, GeneralPurposeConfig},
+    Engine,
+};
+
+use super::source::{MailSource, SourceResult};
+
+const MUTF7: GeneralPurpose = GeneralPurpose::new(
+    &IMAP_MUTF7,
+    GeneralPurposeConfig::new()
+        .with_encode_padding(false)
+        .with_decode_padding_mode(DecodePaddingMode::Indifferent),
+);
+
+/// A selectable mailbox as reported by `LIST`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MailboxName {
+    /// Name exactly as the server knows it, used for SELECT and as the UID state key.
+    pub raw: String,
+    /// Human readable name with modified UTF-7 decoded.
+    pub decoded: String,
+}
+
+/// Decode a modified UTF-7 mailbox name (RFC 3501, section 5.1.3), e.g. `Wys&AUI-ane` into
+/// `Wysłane`. Malformed shifted sequences are kept verbatim.
+pub fn decode_modified_utf7(name: &str) -> String {
+    let mut decoded = String::new();
+    let mut rest = name;
+    while let Some(start) = rest.find('&') {
+        decoded.push_str(&rest[..start]);
+        let shifted = &rest[start + 1..];
+        let Some(end) = shifted.find('-') else {
+            decoded.push_str(&rest[start..]);
+            return decoded;
+        };
+        let encoded = &shifted[..end];
+        match encoded {
+            "" => decoded.push('&'),
+            _ => match decode_utf16_base64(encoded) {
+                Some(text) => decoded.push_str(&text),
+                None => decoded.push_str(&rest[start..start + end + 2]),
+            },
+        }
+        rest = &shifted[end + 1..];
+    }
+    decoded.push_str(rest);
+    decoded
+}
+
+/// Encode a mailbox name into modified UTF-7 so it can be sent to the server.
+pub fn encode_modified_utf7(name: &str) -> String {
+    let mut encoded = String::new();
+    let mut pending: Vec<u16> = Vec::new();
+    for c in name.chars() {
+        if (' '..='~').contains(&c) {
+            flush_utf16_base64(&mut encoded, &mut pending);
+            match c {
+                '&' => encoded.push_str("&-"),
+                _ => encoded.push(c),
+            }
+        } else {
+            let mut buffer = [0; 2];
+            pending.extend_from_slice(c.encode_utf16(&mut buffer));
+        }
+    }
+    flush_utf16_base64(&mut encoded, &mut pending);
+    encoded
+}
+
+fn flush_utf16_base64(encoded: &mut String, pending: &mut Vec<u16>) {
+    if pending.is_empty() {
+        return;
+    }
+    let bytes: Vec<u8> = pending.iter().flat_map(|unit| unit.to_be_bytes()).collect();
+    encoded.push('&');
+    encoded.push_str(&MUTF7.encode(bytes));
+    encoded.push('-');
+    pending.clear();
+}
+
+fn decode_utf16_base64(encoded: &str) -> Option<String> {
+    let bytes = MUTF7.decode(encoded).ok()?;
+    if bytes.len() % 2 != 0 {
+        return None;
+    }
+    let units: Vec<u16> = bytes
+        .chunks(2)
+        .map(|pair| u16::from_be_bytes([pair[0], pair[1]]))
+        .collect();
+    String::from_utf16(&units).ok()
+}
+
+/// Glob match where `*` matches any run of characters (hierarchy delimiters included) and `?`
+/// matches exactly one. `INBOX` is case-insensitive as required by RFC 3501.
+pub fn matches_pattern(pattern: &str, name: &str) -> bool {
+    if pattern.eq_ignore_ascii_case("INBOX") {
+        return name.eq_ignore_ascii_case("INBOX");
+    }
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    glob_match(&pattern, &name)
+}
+
+fn glob_match(pattern: &[char], name: &[char]) -> bool {
+    match (pattern.first(), name.first()) {
+        (None, None) => true,
+        (Some('*'), _) => {
+            glob_match(&pattern[1..], name) || (!name.is_empty() && glob_match(pattern, &name[1..]))
+        }
+        (Some('?'), Some(_)) => glob_match(&pattern[1..], &name[1..]),
+        (Some(p), Some(n)) if p == n => glob_match(&pattern[1..], &name[1..]),
+        _ => false,
+    }
+}
+
+/// Resolve the configured mailbox names and glob patterns against the server's `LIST`, keeping
+/// the configured order and skipping duplicates and `\Noselect` folders.
+pub fn resolve_mailboxes(
+    source: &mut dyn MailSource,
+    patterns: &[String],
+) -> SourceResult<Vec<MailboxName>> {
+    let available: Vec<MailboxName> = source
+        .list()?
+        .into_iter()
+        .filter(|mailbox| mailbox.selectable)
+        .map(|mailbox| MailboxName {
+            decoded: decode_modified_utf7(&mailbox.name),
+            raw: mailbox.name,
+        })
+        .collect();
+    let mut resolved: Vec<MailboxName> = Vec::new();
+    for pattern in patterns {
+        let matching: Vec<&MailboxName> = available
+            .iter()
+            .filter(|mailbox| matches_pattern(pattern, &mailbox.decoded))
+            .collect();
+        if matching.is_empty() {
+            eprintln!("No mailbox matches '{}', skipping", pattern);
+        }
+        for mailbox in matching {
+            if !resolved.contains(mailbox) {
+                resolved.push(mailbox.clone());
+            }
+        }
+    }
+    Ok(resolved)
+}
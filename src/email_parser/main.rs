@@ -1,46 +1,104 @@
 use crate::{
-    factories::credentials::EmailAccountBuilder, COMPANY_EMAIL,
-    COMPANY_EMAIL_PASSWORD, COMPANY_EMAIL_PORT, COMPANY_EMAIL_SERVER, PRIVATE_EMAIL,
-    PRIVATE_EMAIL_PASSWORD, S_EMAIL, S_EMAIL_PASSWORD,
+    config::accounts::{Config, CONFIG_PATH},
+    datemath::{
+        clock::{Clock, SystemClock},
+        date::{get_current_month_range, DateRange},
+    },
+    error::AntworkerError,
+    factories::credentials::EmailAccountBuilder,
+    io::dedupe::{DedupeIndex, INDEX_PATH},
+    rules::{
+        define::define_rules,
+        file::{load_rules, RULES_PATH},
+    },
+    state::store::{state_key, UidStore, STATE_PATH},
+};
+use indicatif::MultiProgress;
+use std::{
+    path::Path,
+    sync::{Arc, Mutex},
 };
-use std::collections::HashMap;
 
-use super::inbox::process_all_inboxes;
+use super::{
+    inbox::{process_all_inboxes, ProcessOptions},
+    offline::{process_archive, MailArchive},
+};
 
-pub async fn process_emails() -> Result<(), Box<dyn std::error::Error>> {
-    let mut inboxes = HashMap::new();
-    let latest_uid_company: i32 = 1;
-    let latest_uid_private: i32 = 1;
-    let latest_uid_s: i32 = 1;
-    let company_credentials = EmailAccountBuilder::new(
-        &COMPANY_EMAIL_SERVER,
-        *COMPANY_EMAIL_PORT,
-        &COMPANY_EMAIL,
-        &COMPANY_EMAIL_PASSWORD,
-    )
-    .uid_set(format!("{}:*", latest_uid_company.to_string()).as_str())
-    .build();
-    let private_credentials = EmailAccountBuilder::new(
-        &COMPANY_EMAIL_SERVER,
-        *COMPANY_EMAIL_PORT,
-        &PRIVATE_EMAIL,
-        &PRIVATE_EMAIL_PASSWORD,
+pub fn with_stored_uid_set(
+    account: EmailAccountBuilder,
+    uid_store: &UidStore,
+) -> EmailAccountBuilder {
+    let key = state_key(&account.server, &account.email, &account.mailbox);
+    let uid_set = uid_store.uid_set(&key);
+    account.uid_set(&uid_set).build()
+}
+
+/// Save attachments of messages sent within `timeframe`. Without one, only what arrived since
+/// the last run is looked at, limited to the current month on the first run of a mailbox.
+pub async fn process_emails(
+    explain: bool,
+    timeframe: Option<DateRange>,
+    clock: SystemClock,
+) -> Result<(), AntworkerError> {
+    let config = Config::load(Path::new(CONFIG_PATH.as_str()))?;
+    let options = ProcessOptions {
+        explain,
+        attachments: config.attachments.clone(),
+        rules: load_rules(Path::new(RULES_PATH.as_str()))?,
+        retry: config.retry,
+        timezone: clock.timezone(),
+        timeframe: Some(timeframe.unwrap_or_else(|| get_current_month_range(&clock))),
+        rescan: timeframe.is_some(),
+    };
+    let uid_store = UidStore::load(Path::new(STATE_PATH.as_str()))
+        .map_err(|e| AntworkerError::io(STATE_PATH.as_str(), e))?;
+    let dedupe_index = DedupeIndex::load(Path::new(INDEX_PATH.as_str()))
+        .map_err(|e| AntworkerError::io(INDEX_PATH.as_str(), e))?;
+    process_all_inboxes(
+        config.accounts.clone(),
+        config.secrets.clone(),
+        Arc::new(Mutex::new(uid_store)),
+        Arc::new(Mutex::new(dedupe_index)),
+        &options,
+        config.concurrency,
     )
-    .uid_set(format!("{}:*", latest_uid_private.to_string()).as_str())
-    .build();
-    let s_credentials = EmailAccountBuilder::new(
-        &COMPANY_EMAIL_SERVER,
-        *COMPANY_EMAIL_PORT,
-        &S_EMAIL,
-        &S_EMAIL_PASSWORD,
+    .await
+}
+
+/// Save attachments from an exported `.eml` directory, mbox file or Maildir. Every message of the
+/// archive is considered, whichever month it is from.
+pub fn import_emails(path: &Path, account: &str, clock: SystemClock) -> Result<(), AntworkerError> {
+    let archive = MailArchive::detect(path).map_err(|e| AntworkerError::io(path, e))?;
+    // NOTE: Accounts are not needed offline, the config only adds attachment settings
+    let attachments = match Path::new(CONFIG_PATH.as_str()).exists() {
+        true => Config::load(Path::new(CONFIG_PATH.as_str()))?.attachments,
+        false => Default::default(),
+    };
+    let options = ProcessOptions {
+        explain: false,
+        attachments,
+        rules: load_rules(Path::new(RULES_PATH.as_str()))?,
+        timezone: clock.timezone(),
+        ..Default::default()
+    };
+    let rules = define_rules(&options.rules, None, options.timezone);
+    let dedupe_index = Mutex::new(
+        DedupeIndex::load(Path::new(INDEX_PATH.as_str()))
+            .map_err(|e| AntworkerError::io(INDEX_PATH.as_str(), e))?,
+    );
+    println!("📦 Importing {:?}", archive);
+    let report = process_archive(
+        &archive,
+        account,
+        &rules,
+        &dedupe_index,
+        &MultiProgress::new(),
+        &options,
     )
-    .uid_set(format!("{}:*", latest_uid_s.to_string()).as_str())
-    .build();
-    inboxes.insert("company", company_credentials);
-    inboxes.insert("private", private_credentials);
-    inboxes.insert("s", s_credentials);
-    process_all_inboxes(inboxes).await.unwrap_or_else(|e| {
-        eprintln!("Error processing all inboxes: {:?}", e);
-    });
-    Ok(())
+    .map_err(|e| AntworkerError::io(path, e))?;
+    println!("{}", report);
+    match report.failures.len() {
+        0 => Ok(()),
+        failed => Err(AntworkerError::Incomplete(failed)),
+    }
 }
@@ -0,0 +1,161 @@
+use mailparse::{parse_mail, DispositionType, ParsedMail};
+
+use super::filename::resolve_filename;
+
+const PDF_MAGIC: &[u8] = b"%PDF-";
+
+/// A PDF found somewhere in the MIME tree, decoded and ready to be written.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FoundAttachment {
+    pub path: String,
+    pub filename: Option<String>,
+    pub content: Vec<u8>,
+}
+
+/// Why a MIME part was taken or skipped, printed by `antworker emails --explain`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PartTrace {
+    pub path: String,
+    pub mimetype: String,
+    pub taken: bool,
+    pub reason: String,
+}
+
+#[derive(Debug, Default)]
+pub struct MimeWalk {
+    pub attachments: Vec<FoundAttachment>,
+    pub trace: Vec<PartTrace>,
+}
+
+impl MimeWalk {
+    fn record(&mut self, path: &str, mimetype: &str, taken: bool, reason: String) {
+        self.trace.push(PartTrace {
+            path: path.to_string(),
+            mimetype: mimetype.to_string(),
+            taken,
+            reason,
+        });
+    }
+
+    pub fn explain(&self) -> String {
+        self.trace
+            .iter()
+            .map(|trace| {
+                format!(
+                    "  {:<10} {:<28} {} {}",
+                    match trace.path.as_str() {
+                        "" => "(root)",
+                        path => path,
+                    },
+                    trace.mimetype,
+                    match trace.taken {
+                        true => "✅",
+                        false => "⏭️ ",
+                    },
+                    trace.reason
+                )
+            })
+            .collect::<Vec<String>>()
+            .join("\n")
+    }
+}
+
+/// Walk the whole MIME tree, descending into every multipart container and forwarded
+/// `message/rfc822` part, and collect the PDFs it carries.
+pub fn find_attachments(mail: &ParsedMail) -> MimeWalk {
+    let mut walk = MimeWalk::default();
+    walk_part(mail, "", &mut walk);
+    walk
+}
+
+fn child_path(path: &str, index: usize) -> String {
+    match path {
+        "" => (index + 1).to_string(),
+        _ => format!("{}.{}", path, index + 1),
+    }
+}
+
+fn walk_part(part: &ParsedMail, path: &str, walk: &mut MimeWalk) {
+    let mimetype = part.ctype.mimetype.to_lowercase();
+    if mimetype.starts_with("multipart/") {
+        walk.record(
+            path,
+            &mimetype,
+            false,
+            format!("container with {} part(s)", part.subparts.len()),
+        );
+        for (index, sub_part) in part.subparts.iter().enumerate() {
+            walk_part(sub_part, &child_path(path, index), walk);
+        }
+        return;
+    }
+    if mimetype == "message/rfc822" {
+        let forwarded = part
+            .get_body_raw()
+            .map_err(|e| e.to_string())
+            .and_then(|body| {
+                let mail = parse_mail(&body).map_err(|e| e.to_string())?;
+                walk.record(path, &mimetype, false, "forwarded message".to_string());
+                walk_part(&mail, &child_path(path, 0), walk);
+                Ok(())
+            });
+        if let Err(e) = forwarded {
+            walk.record(
+                path,
+                &mimetype,
+                false,
+                format!("unreadable forwarded message: {}", e),
+            );
+        }
+        return;
+    }
+
+    let filename = resolve_filename(part);
+    let is_attachment = part.get_content_disposition().disposition == DispositionType::Attachment;
+    let content = match part.get_body_raw() {
+        Ok(content) => content,
+        Err(e) => {
+            walk.record(path, &mimetype, false, format!("undecodable body: {}", e));
+            return;
+        }
+    };
+    let has_pdf_extension = filename
+        .as_ref()
+        .is_some_and(|name| name.to_lowercase().ends_with(".pdf"));
+    let reason = if mimetype == "application/pdf" {
+        Some("application/pdf content type".to_string())
+    } else if has_pdf_extension {
+        Some(format!("{} with .pdf filename", mimetype))
+    } else if content.starts_with(PDF_MAGIC) {
+        Some("content starts with %PDF- magic bytes".to_string())
+    } else {
+        None
+    };
+    let name = filename.clone().unwrap_or_else(|| "unnamed".to_string());
+    match reason {
+        Some(reason) => {
+            let disposition = match is_attachment {
+                true => "attachment",
+                false => "inline",
+            };
+            walk.record(
+                path,
+                &mimetype,
+                true,
+                format!("{} ({}, {})", reason, disposition, name),
+            );
+            walk.attachments.push(FoundAttachment {
+                path: path.to_string(),
+                filename,
+                content,
+            });
+        }
+        None => {
+            let reason = match (is_attachment, &filename) {
+                (true, _) | (_, Some(_)) => format!("not a PDF ({})", name),
+                _ => "message body".to_string(),
+            };
+            walk.record(path, &mimetype, false, reason);
+        }
+    }
+}
@@ -0,0 +1,228 @@
+use indicatif::MultiProgress;
+use mailparse::parse_mail;
+use std::{
+    fs::{self, File},
+    io::{BufRead, BufReader},
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use super::{
+    attachment::{into_report, save_mail_attachments, MessageOrigin},
+    inbox::ProcessOptions,
+    parser::{get_email_details_from_mail, matches_envelope},
+};
+use crate::{
+    error::{AntworkerError, Failure},
+    io::{dedupe::DedupeIndex, writer::SaveReport},
+    rules::define::FilterRules,
+};
+
+/// Account name rules see for archived messages when no other one is given.
+pub const IMPORT_ACCOUNT: &str = "import";
+
+/// Exported mail that can be processed without a server.
+#[derive(Debug, Clone, PartialEq)]
+pub enum MailArchive {
+    /// A directory of `.eml` files, or a single one.
+    Eml(PathBuf),
+    /// A single mbox file, e.g. a Thunderbird folder.
+    Mbox(PathBuf),
+    /// A Maildir with `cur` and `new` subdirectories, e.g. from offlineimap.
+    Maildir(PathBuf),
+}
+
+impl MailArchive {
+    /// Tell the format from what is on disk.
+    pub fn detect(path: &Path) -> Result<MailArchive, String> {
+        if path.is_dir() {
+            return Ok(
+                match path.join("cur").is_dir() || path.join("new").is_dir() {
+                    true => MailArchive::Maildir(path.to_path_buf()),
+                    false => MailArchive::Eml(path.to_path_buf()),
+                },
+            );
+        }
+        if path.is_file() {
+            return Ok(match path.extension().is_some_and(|ext| ext == "eml") {
+                true => MailArchive::Eml(path.to_path_buf()),
+                false => MailArchive::Mbox(path.to_path_buf()),
+            });
+        }
+        Err(format!(
+            "{} is neither a file nor a directory",
+            path.display()
+        ))
+    }
+
+    pub fn path(&self) -> &Path {
+        match self {
+            MailArchive::Eml(path) | MailArchive::Mbox(path) | MailArchive::Maildir(path) => path,
+        }
+    }
+
+    /// Every raw message of the archive, in a stable order. Messages are read one at a time as
+    /// the iterator advances, so an archive of any size fits in memory.
+    pub fn messages(&self) -> Result<Messages<'_>, AntworkerError> {
+        match self {
+            MailArchive::Eml(path) if path.is_file() => {
+                Ok(Box::new(read_files(vec![path.clone()])))
+            }
+            MailArchive::Eml(path) => Ok(Box::new(read_files(files_in(path, Some("eml"))?))),
+            MailArchive::Mbox(path) => {
+                let file = File::open(path).map_err(|e| AntworkerError::io(path, e))?;
+                Ok(Box::new(MboxMessages::new(BufReader::new(file)).map(
+                    move |message| message.map_err(|e| AntworkerError::io(path, e)),
+                )))
+            }
+            MailArchive::Maildir(path) => {
+                let mut files = Vec::new();
+                // NOTE: `tmp` holds messages still being delivered
+                for dir in ["cur", "new"] {
+                    if path.join(dir).is_dir() {
+                        files.extend(files_in(&path.join(dir), None)?);
+                    }
+                }
+                Ok(Box::new(read_files(files)))
+            }
+        }
+    }
+}
+
+/// Raw messages of an archive. A message that cannot be read does not end the iteration.
+pub type Messages<'a> = Box<dyn Iterator<Item = Result<Vec<u8>, AntworkerError>> + 'a>;
+
+fn files_in(dir: &Path, extension: Option<&str>) -> Result<Vec<PathBuf>, AntworkerError> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(dir).map_err(|e| AntworkerError::io(dir, e))? {
+        let path = entry.map_err(|e| AntworkerError::io(dir, e))?.path();
+        let hidden = path
+            .file_name()
+            .is_some_and(|name| name.to_string_lossy().starts_with('.'));
+        let wanted =
+            extension.is_none_or(|extension| path.extension().is_some_and(|ext| ext == extension));
+        if path.is_file() && !hidden && wanted {
+            files.push(path);
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+fn read_files(files: Vec<PathBuf>) -> impl Iterator<Item = Result<Vec<u8>, AntworkerError>> {
+    files
+        .into_iter()
+        .map(|path| fs::read(&path).map_err(|e| AntworkerError::io(&path, e)))
+}
+
+/// Splits an mbox into messages while reading it line by line. A line starting with `From `
+/// opens a message, `>From ` lines are unescaped the mboxrd way.
+pub struct MboxMessages<R> {
+    reader: R,
+    current: Option<Vec<u8>>,
+    done: bool,
+}
+
+impl<R: BufRead> MboxMessages<R> {
+    pub fn new(reader: R) -> Self {
+        MboxMessages {
+            reader,
+            current: None,
+            done: false,
+        }
+    }
+}
+
+impl<R: BufRead> Iterator for MboxMessages<R> {
+    type Item = std::io::Result<Vec<u8>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut line = Vec::new();
+        while !self.done {
+            line.clear();
+            match self.reader.read_until(b'\n', &mut line) {
+                Ok(0) => self.done = true,
+                Ok(_) if line.starts_with(b"From ") => {
+                    if let Some(message) = self.current.replace(Vec::new()) {
+                        return Some(Ok(message));
+                    }
+                }
+                Ok(_) => {
+                    let Some(message) = self.current.as_mut() else {
+                        continue;
+                    };
+                    let quotes = line.iter().take_while(|byte| **byte == b'>').count();
+                    match quotes > 0 && line[quotes..].starts_with(b"From ") {
+                        true => message.extend_from_slice(&line[1..]),
+                        false => message.extend_from_slice(&line),
+                    }
+                }
+                // NOTE: The rest of a broken mbox cannot be split reliably, stop at the error
+                Err(e) => {
+                    self.done = true;
+                    return Some(Err(e));
+                }
+            }
+        }
+        self.current.take().map(Ok)
+    }
+}
+
+/// Run the attachment pipeline over every message of an archive. Messages get their position
+/// in the archive as UID, rules see `account` as the account they came from.
+pub fn process_archive(
+    archive: &MailArchive,
+    account: &str,
+    rules: &FilterRules,
+    dedupe_index: &Mutex<DedupeIndex>,
+    multi_progress: &MultiProgress,
+    options: &ProcessOptions,
+) -> Result<SaveReport, Box<dyn std::error::Error>> {
+    let origin = MessageOrigin {
+        account: account.to_string(),
+        mailbox: archive.path().to_string_lossy().to_string(),
+    };
+    let mut report = SaveReport::default();
+    for (index, raw) in archive.messages()?.enumerate() {
+        let uid = index as u32 + 1;
+        let failure = |error| Failure {
+            item: origin.describe(uid),
+            error,
+        };
+        let raw = match raw {
+            Ok(raw) => raw,
+            Err(error) => {
+                report.failures.push(failure(error));
+                continue;
+            }
+        };
+        let parsed = parse_mail(&raw)
+            .map_err(AntworkerError::from)
+            .and_then(|mail| Ok((get_email_details_from_mail(&mail, uid)?, mail)));
+        let (email, mail) = match parsed {
+            Ok(parsed) => parsed,
+            Err(error) => {
+                report.failures.push(failure(error));
+                continue;
+            }
+        };
+        if !matches_envelope(rules, &email, account) {
+            continue;
+        }
+        let outcomes = save_mail_attachments(
+            &origin,
+            &email,
+            &mail,
+            rules,
+            dedupe_index,
+            multi_progress,
+            options,
+        );
+        report.extend(into_report(&origin, outcomes));
+    }
+    dedupe_index
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .save()?;
+    Ok(report)
+}
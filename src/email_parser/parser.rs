@@ -1,20 +1,27 @@
 use chrono::{DateTime, Utc};
-use imap::{
-    types::{Fetch, ZeroCopy},
-    Session,
-};
-use native_tls::TlsStream;
-use quoted_printable::{decode, ParseMode};
+use imap::types::Fetch;
+use imap_proto::types::Address;
+use mailparse::{addrparse_header, MailAddr, MailHeaderMap, ParsedMail, SingleInfo};
 use serde::{Deserialize, Serialize};
 use std::fmt::{self, Debug, Formatter};
-use std::io::{Read, Write};
 
-use crate::rules::define::FilterRules;
+use super::{
+    actions::PostActions,
+    decode::decode_header_value,
+    source::{FetchedDetails, MailSource, SourceResult},
+};
+use crate::{
+    error::AntworkerError,
+    rules::{define::FilterRules, matcher::MessageFacts},
+};
 
 #[derive(Default, Serialize, Deserialize, Clone)]
 pub struct EmailDetails {
     pub subject: String,
     pub from: Vec<String>,
+    pub sender_name: Option<String>,
+    pub recipients: Vec<String>,
+    pub message_id: Option<String>,
     pub date: DateTime<Utc>,
     pub uid: u32,
 }
@@ -24,97 +31,197 @@ impl Debug for EmailDetails {
         writeln!(f, "EmailDetails {{")?;
         writeln!(f, "  subject: {}", self.subject)?;
         writeln!(f, "  from: {:?}", self.from)?;
+        writeln!(f, "  sender_name: {:?}", self.sender_name)?;
+        writeln!(f, "  recipients: {:?}", self.recipients)?;
+        writeln!(f, "  message_id: {:?}", self.message_id)?;
         writeln!(f, "  date: {}", self.date)?;
         writeln!(f, "  uid: {}", self.uid)?;
         write!(f, "}}")
     }
 }
 
-pub fn fetch_emails<S: Read + Write>(
-    imap_session: &mut Session<TlsStream<S>>,
+impl EmailDetails {
+    /// What rules get to see of this message. Pass `attachments` once the body was parsed.
+    pub fn facts(&self, account: &str, attachments: Option<Vec<String>>) -> MessageFacts {
+        MessageFacts {
+            account: account.to_string(),
+            from: self.from.clone(),
+            display_name: self.sender_name.clone(),
+            subject: self.subject.clone(),
+            recipients: self.recipients.clone(),
+            attachments,
+        }
+    }
+}
+
+fn format_addresses(addresses: Option<&Vec<Address>>) -> Vec<String> {
+    addresses.map_or_else(Vec::new, |addresses| {
+        addresses
+            .iter()
+            .map(|address| {
+                format!(
+                    "{}@{}",
+                    String::from_utf8_lossy(address.mailbox.unwrap_or_default()),
+                    String::from_utf8_lossy(address.host.unwrap_or_default())
+                )
+            })
+            .collect()
+    })
+}
+
+pub fn search_emails(
+    source: &mut dyn MailSource,
+    rules: &FilterRules,
     uid_set: &str,
-) -> imap::error::Result<ZeroCopy<Vec<Fetch>>> {
-    imap_session.select("INBOX")?;
-    let messages = imap_session.uid_fetch(&uid_set, "ALL")?;
-    Ok(messages)
+    post_actions: &PostActions,
+) -> SourceResult<Vec<u32>> {
+    let mut query = rules.search_query(uid_set);
+    if let Some(criteria) = post_actions.search_criteria() {
+        query = format!("{} {}", query, criteria);
+    }
+    source.search(&query)
 }
 
+/// Details of a fetched message as the server's envelope describes it.
+pub fn get_envelope_details(msg: &Fetch) -> Result<EmailDetails, AntworkerError> {
+    let uid = msg.uid.ok_or_else(|| {
+        AntworkerError::imap(format!(
+            "message {} was fetched without its UID",
+            msg.message
+        ))
+    })?;
+    let envelope = msg
+        .envelope()
+        .ok_or_else(|| AntworkerError::imap(format!("message UID {} has no envelope", uid)))?;
+    // NOTE: Extract subject
+    let subject = envelope
+        .subject
+        .map(decode_header_value)
+        .unwrap_or_default();
+    // NOTE: Extract date
+    let raw_date = envelope
+        .date
+        .map(|date_bytes| String::from_utf8_lossy(date_bytes).to_string());
+    // NOTE: Without a readable Date header, when the server received the message is the best
+    // guess of the month it belongs to
+    let date = raw_date
+        .as_deref()
+        .and_then(parse_date)
+        .or_else(|| msg.internal_date().map(|date| date.with_timezone(&Utc)))
+        .ok_or_else(|| AntworkerError::imap(format!("message UID {} has no readable date", uid)))?;
+    // NOTE: Extract email
+    let from = format_addresses(envelope.from.as_ref());
+    // NOTE: Extract sender display name
+    let sender_name = envelope
+        .from
+        .as_ref()
+        .and_then(|from_addresses| from_addresses.first())
+        .and_then(|address| address.name)
+        .map(decode_header_value)
+        .filter(|name| !name.is_empty());
+    // NOTE: Extract recipients
+    let mut recipients = format_addresses(envelope.to.as_ref());
+    recipients.extend(format_addresses(envelope.cc.as_ref()));
+    // NOTE: Extract Message-ID
+    let message_id = envelope
+        .message_id
+        .map(|message_id| String::from_utf8_lossy(message_id).to_string());
+    Ok(EmailDetails {
+        date,
+        subject,
+        from,
+        sender_name,
+        recipients,
+        message_id,
+        uid,
+    })
+}
+
+/// Whether the rules may still take a message whose body was not looked at yet.
+pub fn matches_envelope(rules: &FilterRules, email: &EmailDetails, account: &str) -> bool {
+    rules.is_empty()
+        || (rules.in_timeframe(&email.date) && rules.may_match(&email.facts(account, None)))
+}
+
+/// Envelope details of the messages in `uid_set` the rules may take. Messages whose details
+/// could not be read are kept, so they get reported.
 pub fn get_email_details(
-    messages: &ZeroCopy<Vec<Fetch>>,
+    source: &mut dyn MailSource,
+    uid_set: &str,
     rules: &FilterRules,
-) -> imap::error::Result<Vec<EmailDetails>> {
-    let email_details: Vec<EmailDetails> = messages
-        .iter()
-        .filter_map(|msg| {
-            // Apply filtering rules
-            if !rules.is_empty() && !rules.matches(msg) {
-                return None;
-            }
-            let uid = msg.uid.unwrap();
-            let envelope = msg.envelope().expect("message did not have an envelope!");
-            // NOTE: Extract subject
-            let subject = envelope
-                .subject
-                .map(|subject_bytes| {
-                    decode(subject_bytes, ParseMode::Robust)
-                        .map(|v| String::from_utf8_lossy(&v).to_string())
-                        .unwrap_or_else(|e| {
-                            eprintln!("Failed to decode subject: {}", e);
-                            String::new()
-                        })
-                        .replace("=?UTF-8?Q?", "")
-                        .replace("?= ", "")
-                        .replace("_", " ")
-                })
-                .unwrap_or_else(|| String::new());
-            // NOTE: Extract date
-            let raw_date = envelope
-                .date
-                .map(|date_bytes| String::from_utf8_lossy(&date_bytes).to_string());
-            let date = parse_date(&raw_date.unwrap_or_else(|| String::new()));
-            // NOTE: Extract email
-            let from: Vec<String> =
-                envelope
-                    .from
-                    .as_ref()
-                    .map_or_else(Vec::new, |from_addresses| {
-                        from_addresses
-                            .iter()
-                            .map(|address| {
-                                format!(
-                                    "{}@{}",
-                                    String::from_utf8_lossy(address.mailbox.unwrap_or_default())
-                                        .to_string(),
-                                    String::from_utf8_lossy(address.host.unwrap_or_default())
-                                        .to_string()
-                                )
-                            })
-                            .collect()
-                    });
-            Some(EmailDetails {
-                date,
-                subject,
-                from,
-                uid,
-            })
+    account: &str,
+) -> SourceResult<Vec<FetchedDetails>> {
+    let email_details: Vec<FetchedDetails> = source
+        .fetch_details(uid_set)?
+        .into_iter()
+        // Apply filtering rules, attachments are only known once the body is fetched
+        .filter(|(_, details)| {
+            details
+                .as_ref()
+                .map_or(true, |email| matches_envelope(rules, email, account))
         })
         .collect();
     Ok(email_details)
 }
 
-pub fn parse_date(date_str: &str) -> DateTime<Utc> {
+fn header_addresses(mail: &ParsedMail, key: &str) -> Vec<SingleInfo> {
+    mail.headers
+        .get_all_headers(key)
+        .into_iter()
+        .filter_map(|header| addrparse_header(header).ok())
+        .flat_map(|addresses| addresses.into_inner())
+        .flat_map(|address| match address {
+            MailAddr::Single(info) => vec![info],
+            MailAddr::Group(group) => group.addrs,
+        })
+        .collect()
+}
+
+/// The same details `get_email_details` reads from the IMAP envelope, taken from the headers of
+/// a message that is already downloaded, e.g. an `.eml` file. Fails when the `Date` header is
+/// missing or cannot be parsed, there is nothing else to tell the month from.
+pub fn get_email_details_from_mail(
+    mail: &ParsedMail,
+    uid: u32,
+) -> Result<EmailDetails, AntworkerError> {
+    let from = header_addresses(mail, "From");
+    let mut recipients = header_addresses(mail, "To");
+    recipients.extend(header_addresses(mail, "Cc"));
+    let date = mail
+        .headers
+        .get_first_value("Date")
+        .and_then(|date| parse_date(date.trim()))
+        .ok_or_else(|| {
+            AntworkerError::Mime("the Date header is missing or cannot be parsed".to_string())
+        })?;
+    Ok(EmailDetails {
+        subject: mail
+            .headers
+            .get_first_value("Subject")
+            .map(|subject| subject.trim().to_string())
+            .unwrap_or_default(),
+        from: from.iter().map(|info| info.addr.clone()).collect(),
+        sender_name: from
+            .first()
+            .and_then(|info| info.display_name.clone())
+            .filter(|name| !name.is_empty()),
+        recipients: recipients.into_iter().map(|info| info.addr).collect(),
+        message_id: mail.headers.get_first_value("Message-ID"),
+        date,
+        uid,
+    })
+}
+
+/// The moment a `Date` header names, `None` when it cannot be parsed.
+pub fn parse_date(date_str: &str) -> Option<DateTime<Utc>> {
     // HACK: This is kind of a quacky solution.
     // Attempt to parse the date string using RFC 2822 format
-    let date = DateTime::parse_from_rfc2822(date_str)
+    DateTime::parse_from_rfc2822(date_str)
         .map(|dt| dt.with_timezone(&Utc))
         .or_else(|_| {
             // If parsing with RFC 2822 fails, try a custom format with full timezone name
             let date_format = "%a %b %d %H:%M:%S %Z %Y";
             DateTime::parse_from_str(date_str, date_format).map(|dt| dt.with_timezone(&Utc))
         })
-        .unwrap_or_else(|_| {
-            // If all parsing attempts fail, fallback to current time in UTC
-            Utc::now()
-        });
-    date
+        .ok()
 }
@@ -0,0 +1,171 @@
+use std::{error::Error, time::Duration};
+
+use super::source::{FetchedDetails, ListedMailbox, MailSource, MailboxStatus, SourceResult};
+use crate::retry::{retry, RetryPolicy};
+
+/// Whether an error means the connection is gone rather than the server refusing the command.
+pub fn is_connection_error(error: &(dyn Error + 'static)) -> bool {
+    if error.is::<std::io::Error>() {
+        return true;
+    }
+    matches!(
+        error.downcast_ref::<imap::error::Error>(),
+        Some(imap::error::Error::Io(_) | imap::error::Error::ConnectionLost)
+    )
+}
+
+/// A mail source that opens a new session when the connection drops, selects the mailbox again
+/// and repeats the command that failed, so processing resumes from the message it was at.
+pub struct ReconnectingSource<S, F> {
+    policy: RetryPolicy,
+    connect: F,
+    session: Option<S>,
+    /// Mailbox selected last, whether read-only and its UIDVALIDITY.
+    selected: Option<(String, bool, Option<u32>)>,
+}
+
+impl<S: MailSource, F: FnMut() -> SourceResult<S>> ReconnectingSource<S, F> {
+    /// Connect right away, retrying only dropped connections like every later command.
+    pub fn connect(policy: RetryPolicy, mut connect: F) -> SourceResult<Self> {
+        let session = retry(
+            &policy,
+            "Connecting",
+            |e| is_connection_error(e.as_ref()),
+            &mut connect,
+        )?;
+        Ok(ReconnectingSource {
+            policy,
+            connect,
+            session: Some(session),
+            selected: None,
+        })
+    }
+
+    pub fn session(&mut self) -> Option<&mut S> {
+        self.session.as_mut()
+    }
+
+    /// The current session, a new one when the last connection dropped.
+    fn connected(&mut self) -> SourceResult<&mut S> {
+        match self.session {
+            Some(ref mut session) => Ok(session),
+            None => self.reconnect(),
+        }
+    }
+
+    fn reconnect(&mut self) -> SourceResult<&mut S> {
+        let mut session = (self.connect)()?;
+        if let Some((mailbox, read_only, uid_validity)) = &self.selected {
+            let status = match read_only {
+                true => session.examine(mailbox)?,
+                false => session.select(mailbox)?,
+            };
+            // NOTE: The UIDs the caller holds mean nothing anymore, do not carry on with them
+            if status.uid_validity != *uid_validity {
+                return Err(
+                    format!("UIDVALIDITY of {} changed while reconnecting", mailbox).into(),
+                );
+            }
+        }
+        Ok(self.session.insert(session))
+    }
+
+    fn run<T>(
+        &mut self,
+        what: &str,
+        mut command: impl FnMut(&mut S) -> SourceResult<T>,
+    ) -> SourceResult<T> {
+        let policy = self.policy;
+        retry::<T, Box<dyn Error>>(
+            &policy,
+            what,
+            |e| is_connection_error(e.as_ref()),
+            || {
+                command(self.connected()?).inspect_err(|e| {
+                    if is_connection_error(e.as_ref()) {
+                        self.session = None;
+                    }
+                })
+            },
+        )
+    }
+
+    fn open(&mut self, mailbox: &str, read_only: bool) -> SourceResult<MailboxStatus> {
+        let status = self.run("Selecting the mailbox", |session| match read_only {
+            true => session.examine(mailbox),
+            false => session.select(mailbox),
+        })?;
+        self.selected = Some((mailbox.to_string(), read_only, status.uid_validity));
+        Ok(status)
+    }
+}
+
+impl<S: MailSource, F: FnMut() -> SourceResult<S>> MailSource for ReconnectingSource<S, F> {
+    fn has_capability(&mut self, capability: &str) -> SourceResult<bool> {
+        self.run("Reading capabilities", |session| {
+            session.has_capability(capability)
+        })
+    }
+
+    fn list(&mut self) -> SourceResult<Vec<ListedMailbox>> {
+        self.run("Listing mailboxes", |session| session.list())
+    }
+
+    fn select(&mut self, mailbox: &str) -> SourceResult<MailboxStatus> {
+        self.open(mailbox, false)
+    }
+
+    fn examine(&mut self, mailbox: &str) -> SourceResult<MailboxStatus> {
+        self.open(mailbox, true)
+    }
+
+    fn search(&mut self, query: &str) -> SourceResult<Vec<u32>> {
+        self.run("Searching", |session| session.search(query))
+    }
+
+    fn fetch_details(&mut self, uid_set: &str) -> SourceResult<Vec<FetchedDetails>> {
+        self.run("Fetching envelopes", |session| {
+            session.fetch_details(uid_set)
+        })
+    }
+
+    fn fetch_body(&mut self, uid: u32, peek: bool) -> SourceResult<Vec<u8>> {
+        self.run(&format!("Fetching UID {}", uid), |session| {
+            session.fetch_body(uid, peek)
+        })
+    }
+
+    fn add_flags(&mut self, uid_set: &str, flags: &[&str]) -> SourceResult<()> {
+        self.run("Flagging", |session| session.add_flags(uid_set, flags))
+    }
+
+    // NOTE: COPY, MOVE and EXPUNGE may have happened before the connection dropped, repeating
+    // them could copy twice or lose track of messages, so they are never retried.
+    fn copy(&mut self, uid_set: &str, mailbox: &str) -> SourceResult<()> {
+        self.connected()?.copy(uid_set, mailbox)
+    }
+
+    fn move_messages(&mut self, uid_set: &str, mailbox: &str) -> SourceResult<()> {
+        self.connected()?.move_messages(uid_set, mailbox)
+    }
+
+    fn expunge(&mut self, uid_set: Option<&str>) -> SourceResult<()> {
+        self.connected()?.expunge(uid_set)
+    }
+
+    // NOTE: IDLE can last half an hour, the watch loop reconnects on its own when it drops
+    fn idle(&mut self, keepalive: Duration) -> SourceResult<()> {
+        self.connected()?.idle(keepalive)
+    }
+
+    fn noop(&mut self) -> SourceResult<()> {
+        self.run("Polling", |session| session.noop())
+    }
+
+    fn logout(&mut self) -> SourceResult<()> {
+        match self.session.take() {
+            Some(mut session) => session.logout(),
+            None => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,148 @@
+use imap::{extensions::idle::SetReadTimeout, types::NameAttribute, Session};
+use std::{
+    io::{Read, Write},
+    time::Duration,
+};
+
+use super::parser::{get_envelope_details, EmailDetails};
+use crate::error::AntworkerError;
+
+pub type SourceResult<T> = Result<T, Box<dyn std::error::Error>>;
+
+/// Envelope details of one fetched message or why they could not be read, with the UID of the
+/// message when the server sent one.
+pub type FetchedDetails = (Option<u32>, Result<EmailDetails, AntworkerError>);
+
+/// Mailbox state reported when a mailbox is selected or examined.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct MailboxStatus {
+    pub uid_validity: Option<u32>,
+    pub uid_next: Option<u32>,
+}
+
+/// A mailbox as listed by the server, `name` is still modified UTF-7.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ListedMailbox {
+    pub name: String,
+    pub selectable: bool,
+}
+
+/// Everything the pipeline needs from a mail server. UIDs and UID sets refer to the mailbox
+/// selected last.
+pub trait MailSource {
+    fn has_capability(&mut self, capability: &str) -> SourceResult<bool>;
+    fn list(&mut self) -> SourceResult<Vec<ListedMailbox>>;
+    fn select(&mut self, mailbox: &str) -> SourceResult<MailboxStatus>;
+    /// Select read-only, nothing done afterwards changes the mailbox.
+    fn examine(&mut self, mailbox: &str) -> SourceResult<MailboxStatus>;
+    /// UIDs matching an IMAP `SEARCH` query.
+    fn search(&mut self, query: &str) -> SourceResult<Vec<u32>>;
+    /// Envelope details of every message in `uid_set`, a message that cannot be read does not
+    /// fail the others.
+    fn fetch_details(&mut self, uid_set: &str) -> SourceResult<Vec<FetchedDetails>>;
+    /// The full raw message. With `peek` the message is not marked `\Seen`.
+    fn fetch_body(&mut self, uid: u32, peek: bool) -> SourceResult<Vec<u8>>;
+    fn add_flags(&mut self, uid_set: &str, flags: &[&str]) -> SourceResult<()>;
+    fn copy(&mut self, uid_set: &str, mailbox: &str) -> SourceResult<()>;
+    /// `UID MOVE`, only available with the `MOVE` capability.
+    fn move_messages(&mut self, uid_set: &str, mailbox: &str) -> SourceResult<()>;
+    /// Remove messages marked `\Deleted`, only those in `uid_set` when given (`UIDPLUS`).
+    fn expunge(&mut self, uid_set: Option<&str>) -> SourceResult<()>;
+    /// Wait in `IDLE` until the selected mailbox changes, re-issuing it every `keepalive`.
+    fn idle(&mut self, keepalive: Duration) -> SourceResult<()>;
+    fn noop(&mut self) -> SourceResult<()>;
+    fn logout(&mut self) -> SourceResult<()>;
+}
+
+impl<T: Read + Write + SetReadTimeout> MailSource for Session<T> {
+    fn has_capability(&mut self, capability: &str) -> SourceResult<bool> {
+        Ok(self.capabilities()?.has_str(capability))
+    }
+
+    fn list(&mut self) -> SourceResult<Vec<ListedMailbox>> {
+        let names = Session::list(self, Some(""), Some("*"))?;
+        Ok(names
+            .iter()
+            .map(|name| ListedMailbox {
+                name: name.name().to_string(),
+                selectable: !name.attributes().contains(&NameAttribute::NoSelect),
+            })
+            .collect())
+    }
+
+    fn select(&mut self, mailbox: &str) -> SourceResult<MailboxStatus> {
+        let mailbox = Session::select(self, mailbox)?;
+        Ok(MailboxStatus {
+            uid_validity: mailbox.uid_validity,
+            uid_next: mailbox.uid_next,
+        })
+    }
+
+    fn examine(&mut self, mailbox: &str) -> SourceResult<MailboxStatus> {
+        let mailbox = Session::examine(self, mailbox)?;
+        Ok(MailboxStatus {
+            uid_validity: mailbox.uid_validity,
+            uid_next: mailbox.uid_next,
+        })
+    }
+
+    fn search(&mut self, query: &str) -> SourceResult<Vec<u32>> {
+        Ok(self.uid_search(query)?.into_iter().collect())
+    }
+
+    fn fetch_details(&mut self, uid_set: &str) -> SourceResult<Vec<FetchedDetails>> {
+        let messages = self.uid_fetch(uid_set, "ALL")?;
+        Ok(messages
+            .iter()
+            .map(|message| (message.uid, get_envelope_details(message)))
+            .collect())
+    }
+
+    fn fetch_body(&mut self, uid: u32, peek: bool) -> SourceResult<Vec<u8>> {
+        let query = match peek {
+            true => "BODY.PEEK[]",
+            false => "BODY[]",
+        };
+        let messages = self.uid_fetch(uid.to_string(), query)?;
+        let body = messages
+            .iter()
+            .find_map(|message| message.body())
+            .ok_or_else(|| format!("Message UID {} has no body", uid))?;
+        Ok(body.to_vec())
+    }
+
+    fn add_flags(&mut self, uid_set: &str, flags: &[&str]) -> SourceResult<()> {
+        self.uid_store(uid_set, format!("+FLAGS.SILENT ({})", flags.join(" ")))?;
+        Ok(())
+    }
+
+    fn copy(&mut self, uid_set: &str, mailbox: &str) -> SourceResult<()> {
+        Ok(self.uid_copy(uid_set, mailbox)?)
+    }
+
+    fn move_messages(&mut self, uid_set: &str, mailbox: &str) -> SourceResult<()> {
+        Ok(self.uid_mv(uid_set, mailbox)?)
+    }
+
+    fn expunge(&mut self, uid_set: Option<&str>) -> SourceResult<()> {
+        match uid_set {
+            Some(uid_set) => self.uid_expunge(uid_set)?,
+            None => Session::expunge(self)?,
+        };
+        Ok(())
+    }
+
+    fn idle(&mut self, keepalive: Duration) -> SourceResult<()> {
+        let mut idle = Session::idle(self)?;
+        idle.set_keepalive(keepalive);
+        Ok(idle.wait_keepalive()?)
+    }
+
+    fn noop(&mut self) -> SourceResult<()> {
+        Ok(Session::noop(self)?)
+    }
+
+    fn logout(&mut self) -> SourceResult<()> {
+        Ok(Session::logout(self)?)
+    }
+}
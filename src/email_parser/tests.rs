@@ -0,0 +1,1590 @@
+use crate::email_parser::{
+    actions::{apply_post_actions, PostActions},
+    attachment::{get_and_save_attachments, record_saved},
+    connection::{
+        certificate_fingerprint, connect, is_loopback, normalize_fingerprint, parse_ca_bundle,
+        ConnectionSettings, Security,
+    },
+    decode::decode_header_value,
+    fake::FakeMailSource,
+    filename::{resolve_filename, sanitize_filename, transliterate_polish},
+    inbox::{process_new_messages, run_accounts, ProcessOptions},
+    mailbox::{
+        decode_modified_utf7, encode_modified_utf7, matches_pattern, resolve_mailboxes, MailboxName,
+    },
+    main::with_stored_uid_set,
+    mime::find_attachments,
+    offline::{process_archive, MailArchive, MboxMessages},
+    parser::{
+        get_email_details, get_email_details_from_mail, parse_date, search_emails, EmailDetails,
+    },
+    reconnect::ReconnectingSource,
+    source::{FetchedDetails, MailSource, SourceResult},
+    watch::{watch_inbox, WatchContext},
+};
+use crate::{
+    config::accounts::{AccountConfig, Config},
+    datemath::{
+        clock::FixedClock,
+        date::{get_current_month_range, DateRange},
+    },
+    factories::credentials::EmailAccountBuilder,
+    io::{
+        dedupe::{DedupeIndex, DuplicatePolicy, IndexEntry},
+        writer::SaveOutcome,
+    },
+    retry::RetryPolicy,
+    rules::{
+        define::define_rules,
+        file::{parse_rules, Rule},
+    },
+    state::store::{state_key, UidStore},
+};
+use chrono::{NaiveDate, TimeZone};
+use chrono_tz::Europe::Warsaw;
+use indicatif::MultiProgress;
+use mailparse::parse_mail;
+use std::{
+    cell::Cell,
+    collections::BTreeMap,
+    fs,
+    io::{BufRead, BufReader, Write},
+    net::TcpListener,
+    path::Path,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::Duration,
+};
+
+#[test]
+fn test_decode_modified_utf7() {
+    assert_eq!(decode_modified_utf7("INBOX"), "INBOX");
+    assert_eq!(decode_modified_utf7("Wys&AUI-ane"), "Wysłane");
+    assert_eq!(decode_modified_utf7("Kopie robocze"), "Kopie robocze");
+    assert_eq!(
+        decode_modified_utf7("Faktury &- rachunki"),
+        "Faktury & rachunki"
+    );
+    assert_eq!(
+        decode_modified_utf7("&ZeVnLIqe-"),
+        "\u{65e5}\u{672c}\u{8a9e}"
+    );
+    assert_eq!(decode_modified_utf7("Ksi&ARk-gowo&AVsBBw-"), "Księgowość");
+}
+
+#[test]
+fn test_decode_modified_utf7_malformed() {
+    assert_eq!(decode_modified_utf7("Broken&AUI"), "Broken&AUI");
+    assert_eq!(decode_modified_utf7("Bad&!!!-name"), "Bad&!!!-name");
+}
+
+#[test]
+fn test_encode_modified_utf7() {
+    assert_eq!(encode_modified_utf7("INBOX"), "INBOX");
+    assert_eq!(encode_modified_utf7("Wysłane"), "Wys&AUI-ane");
+    assert_eq!(
+        encode_modified_utf7("Faktury & rachunki"),
+        "Faktury &- rachunki"
+    );
+    assert_eq!(encode_modified_utf7("Księgowość"), "Ksi&ARk-gowo&AVsBBw-");
+    assert_eq!(
+        decode_modified_utf7(&encode_modified_utf7("Archiwum/Zażółć")),
+        "Archiwum/Zażółć"
+    );
+}
+
+#[test]
+fn test_matches_pattern() {
+    assert!(matches_pattern("INBOX", "INBOX"));
+    assert!(matches_pattern("inbox", "INBOX"));
+    assert!(matches_pattern("Faktury/*", "Faktury/2024"));
+    assert!(matches_pattern("Faktury/*", "Faktury/2024/Styczeń"));
+    assert!(!matches_pattern("Faktury/*", "Faktury"));
+    assert!(matches_pattern("Faktury*", "Faktury"));
+    assert!(matches_pattern("Rok 202?", "Rok 2024"));
+    assert!(!matches_pattern("Rok 202?", "Rok 20245"));
+    assert!(matches_pattern("[Gmail]/All Mail", "[Gmail]/All Mail"));
+    assert!(!matches_pattern("[Gmail]/All Mail", "[Gmail]/Spam"));
+}
+
+#[test]
+fn test_post_actions_search_criteria() {
+    let post_actions = PostActions {
+        flag: Some("$AntworkerSaved".to_string()),
+        ..PostActions::default()
+    };
+    assert_eq!(
+        post_actions.search_criteria(),
+        Some("UNKEYWORD $AntworkerSaved".to_string())
+    );
+    assert_eq!(PostActions::default().search_criteria(), None);
+}
+
+#[test]
+fn test_post_actions_validate() {
+    let valid = PostActions {
+        flag: Some("$AntworkerSaved".to_string()),
+        mark_seen: true,
+        move_to: Some("Archiwum".to_string()),
+        ..PostActions::default()
+    };
+    assert!(valid.validate().is_ok());
+    assert!(!valid.is_empty());
+    assert!(PostActions::default().is_empty());
+
+    for flag in ["", "\\Seen", "two words", "(paren"] {
+        let invalid = PostActions {
+            flag: Some(flag.to_string()),
+            ..PostActions::default()
+        };
+        assert!(invalid.validate().is_err(), "{} should be rejected", flag);
+    }
+
+    let empty_folder = PostActions {
+        copy_to: Some(" ".to_string()),
+        ..PostActions::default()
+    };
+    assert!(empty_folder.validate().is_err());
+}
+
+#[test]
+fn test_decode_header_value_plain() {
+    assert_eq!(decode_header_value(b"Invoice 2024_01"), "Invoice 2024_01");
+    assert_eq!(decode_header_value(b""), "");
+}
+
+#[test]
+fn test_decode_header_value_q_encoding() {
+    assert_eq!(
+        decode_header_value(b"=?UTF-8?Q?Faktura_za_stycze=C5=84?="),
+        "Faktura za styczeń"
+    );
+    assert_eq!(
+        decode_header_value(b"=?ISO-8859-2?Q?Wyci=B1g_z_rachunku?="),
+        "Wyciąg z rachunku"
+    );
+    assert_eq!(
+        decode_header_value(b"=?windows-1250?Q?Wyci=B9g_z_rachunku?="),
+        "Wyciąg z rachunku"
+    );
+}
+
+#[test]
+fn test_decode_header_value_b_encoding() {
+    assert_eq!(
+        decode_header_value(b"=?UTF-8?B?WmHFvMOzxYLEhyBnxJnFm2zEhSBqYcW6xYQ=?="),
+        "Zażółć gęślą jaźń"
+    );
+    assert_eq!(decode_header_value(b"=?iso-8859-2?b?V3ljabFn?="), "Wyciąg");
+}
+
+#[test]
+fn test_decode_header_value_adjacent_words() {
+    // Whitespace between adjacent encoded words is not part of the text
+    assert_eq!(
+        decode_header_value(b"=?UTF-8?Q?Faktura_nr_?= =?UTF-8?Q?1/2024?="),
+        "Faktura nr 1/2024"
+    );
+    // but whitespace next to plain text is
+    assert_eq!(
+        decode_header_value(b"Re: =?UTF-8?Q?Wyci=C4=85g?= z rachunku"),
+        "Re: Wyciąg z rachunku"
+    );
+}
+
+const NESTED_EMAIL: &str = "From: billing@vendor.com\r
+Subject: Invoice\r
+Content-Type: multipart/alternative; boundary=\"alt\"\r
+\r
+--alt\r
+Content-Type: text/plain\r
+\r
+Plain body\r
+--alt\r
+Content-Type: multipart/related; boundary=\"rel\"\r
+\r
+--rel\r
+Content-Type: text/html\r
+\r
+<p>Html body</p>\r
+--rel\r
+Content-Type: application/pdf; name=\"invoice.pdf\"\r
+Content-Transfer-Encoding: base64\r
+\r
+JVBERi0xLjQK\r
+--rel--\r
+--alt--\r
+";
+
+const FORWARDED_EMAIL: &str = "From: me@company.com\r
+Subject: Fwd: Invoice\r
+Content-Type: multipart/mixed; boundary=\"outer\"\r
+\r
+--outer\r
+Content-Type: text/plain\r
+\r
+See below\r
+--outer\r
+Content-Type: message/rfc822\r
+\r
+From: billing@vendor.com\r
+Subject: Invoice\r
+Content-Type: multipart/mixed; boundary=\"inner\"\r
+\r
+--inner\r
+Content-Type: text/plain\r
+\r
+Invoice attached\r
+--inner\r
+Content-Type: application/octet-stream\r
+Content-Disposition: attachment; filename=\"faktura.PDF\"\r
+Content-Transfer-Encoding: base64\r
+\r
+JVBERi0xLjQK\r
+--inner\r
+Content-Type: image/png\r
+Content-Disposition: attachment; filename=\"logo.png\"\r
+Content-Transfer-Encoding: base64\r
+\r
+iVBORw0KGgo=\r
+--inner--\r
+--outer\r
+Content-Type: application/octet-stream\r
+Content-Transfer-Encoding: base64\r
+\r
+JVBERi0xLjQK\r
+--outer--\r
+";
+
+#[test]
+fn test_find_attachments_nested_in_related() {
+    let mail = parse_mail(NESTED_EMAIL.as_bytes()).unwrap();
+    let walk = find_attachments(&mail);
+    assert_eq!(walk.attachments.len(), 1);
+    assert_eq!(walk.attachments[0].path, "2.2");
+    assert_eq!(walk.attachments[0].filename.as_deref(), Some("invoice.pdf"));
+    assert!(walk.attachments[0].content.starts_with(b"%PDF-"));
+}
+
+#[test]
+fn test_find_attachments_forwarded_and_octet_stream() {
+    let mail = parse_mail(FORWARDED_EMAIL.as_bytes()).unwrap();
+    let walk = find_attachments(&mail);
+    let found: Vec<(&str, Option<&str>)> = walk
+        .attachments
+        .iter()
+        .map(|attachment| (attachment.path.as_str(), attachment.filename.as_deref()))
+        .collect();
+    assert_eq!(found, vec![("2.1.2", Some("faktura.PDF")), ("3", None)]);
+}
+
+#[test]
+fn test_find_attachments_trace() {
+    let mail = parse_mail(FORWARDED_EMAIL.as_bytes()).unwrap();
+    let walk = find_attachments(&mail);
+    let trace: Vec<(&str, bool)> = walk
+        .trace
+        .iter()
+        .map(|trace| (trace.path.as_str(), trace.taken))
+        .collect();
+    assert_eq!(
+        trace,
+        vec![
+            ("", false),
+            ("1", false),
+            ("2", false),
+            ("2.1", false),
+            ("2.1.1", false),
+            ("2.1.2", true),
+            ("2.1.3", false),
+            ("3", true),
+        ]
+    );
+    assert_eq!(walk.trace[1].reason, "message body");
+    assert_eq!(walk.trace[6].reason, "not a PDF (logo.png)");
+    assert_eq!(
+        walk.trace[7].reason,
+        "content starts with %PDF- magic bytes (inline, unnamed)"
+    );
+    assert!(walk.explain().contains("2.1.2"));
+}
+
+fn single_part(headers: &str) -> String {
+    format!("{}\r\n\r\nJVBERi0xLjQK\r\n", headers)
+}
+
+#[test]
+fn test_resolve_filename_prefers_disposition() {
+    let raw = single_part(
+        "Content-Type: application/pdf; name=\"legacy.pdf\"\r\nContent-Disposition: attachment; filename=\"faktura.pdf\"",
+    );
+    let mail = parse_mail(raw.as_bytes()).unwrap();
+    assert_eq!(resolve_filename(&mail).as_deref(), Some("faktura.pdf"));
+}
+
+#[test]
+fn test_resolve_filename_rfc2231() {
+    let raw = single_part(
+        "Content-Type: application/pdf\r\nContent-Disposition: attachment; filename*=UTF-8''Faktura%20VAT%20%C5%81%C3%B3d%C5%BA.pdf",
+    );
+    let mail = parse_mail(raw.as_bytes()).unwrap();
+    assert_eq!(
+        resolve_filename(&mail).as_deref(),
+        Some("Faktura VAT Łódź.pdf")
+    );
+}
+
+#[test]
+fn test_resolve_filename_rfc2231_continuations() {
+    let raw = single_part(
+        "Content-Type: application/pdf\r\nContent-Disposition: attachment;\r\n filename*0*=UTF-8''Faktura_; filename*1*=%C5%BC%C3%B3%C5%82ta.pdf",
+    );
+    let mail = parse_mail(raw.as_bytes()).unwrap();
+    assert_eq!(
+        resolve_filename(&mail).as_deref(),
+        Some("Faktura_żółta.pdf")
+    );
+}
+
+#[test]
+fn test_resolve_filename_encoded_word() {
+    let raw = single_part("Content-Type: application/pdf; name=\"=?UTF-8?B?V3ljacSFZy5wZGY=?=\"");
+    let mail = parse_mail(raw.as_bytes()).unwrap();
+    assert_eq!(resolve_filename(&mail).as_deref(), Some("Wyciąg.pdf"));
+}
+
+#[test]
+fn test_resolve_filename_missing() {
+    let raw = single_part("Content-Type: application/pdf");
+    let mail = parse_mail(raw.as_bytes()).unwrap();
+    assert_eq!(resolve_filename(&mail), None);
+}
+
+#[test]
+fn test_sanitize_filename() {
+    assert_eq!(
+        sanitize_filename("Faktura VAT 1.pdf", false).as_deref(),
+        Some("Faktura_VAT_1.pdf")
+    );
+    assert_eq!(
+        sanitize_filename("../../etc/passwd", false).as_deref(),
+        Some("passwd")
+    );
+    assert_eq!(
+        sanitize_filename("C:\\Users\\..\\invoice.pdf", false).as_deref(),
+        Some("invoice.pdf")
+    );
+    assert_eq!(
+        sanitize_filename("in\u{0}vo\u{1b}ice<1>|?.pdf", false).as_deref(),
+        Some("invoice_1.pdf")
+    );
+    assert_eq!(sanitize_filename("..", false), None);
+    assert_eq!(sanitize_filename("   ", false), None);
+    assert_eq!(
+        sanitize_filename(".hidden.pdf", false).as_deref(),
+        Some("hidden.pdf")
+    );
+    assert_eq!(
+        sanitize_filename("CON.pdf", false).as_deref(),
+        Some("_CON.pdf")
+    );
+}
+
+#[test]
+fn test_sanitize_filename_length() {
+    let long_name = format!("{}.pdf", "ż".repeat(150));
+    let sanitized = sanitize_filename(&long_name, false).unwrap();
+    assert!(sanitized.len() <= 200);
+    assert!(sanitized.ends_with("ż.pdf"));
+}
+
+#[test]
+fn test_sanitize_filename_transliterate() {
+    assert_eq!(
+        sanitize_filename("Faktura Łódź źdźbło.pdf", true).as_deref(),
+        Some("Faktura_Lodz_zdzblo.pdf")
+    );
+    assert_eq!(
+        transliterate_polish("ZAŻÓŁĆ GĘŚLĄ JAŹŃ"),
+        "ZAZOLC GESLA JAZN"
+    );
+}
+
+#[test]
+fn test_get_email_details_from_mail() {
+    let email = "From: =?UTF-8?Q?Biuro_Rachunkowe_=C5=81=C3=B3d=C5=BA?= <biuro@accountant.pl>\r
+To: me@company.com, \"Invoices\" <invoices@company.com>\r
+Cc: boss@company.com\r
+Subject: =?UTF-8?Q?Faktura_za_stycze=C5=84?=\r
+Date: Mon, 15 Jan 2024 10:00:00 +0100\r
+Message-ID: <1@accountant.pl>\r
+\r
+Body\r
+";
+    let mail = parse_mail(email.as_bytes()).unwrap();
+    let details = get_email_details_from_mail(&mail, 3).unwrap();
+    assert_eq!(details.uid, 3);
+    assert_eq!(details.subject, "Faktura za styczeń");
+    assert_eq!(details.from, vec!["biuro@accountant.pl"]);
+    assert_eq!(
+        details.sender_name.as_deref(),
+        Some("Biuro Rachunkowe Łódź")
+    );
+    assert_eq!(
+        details.recipients,
+        vec!["me@company.com", "invoices@company.com", "boss@company.com"]
+    );
+    assert_eq!(details.message_id.as_deref(), Some("<1@accountant.pl>"));
+    assert_eq!(details.date.to_rfc3339(), "2024-01-15T09:00:00+00:00");
+}
+
+#[test]
+fn test_parse_date() {
+    assert_eq!(
+        parse_date("Mon, 15 Jan 2024 10:00:00 +0100")
+            .unwrap()
+            .to_rfc3339(),
+        "2024-01-15T09:00:00+00:00"
+    );
+    // NOTE: No stand-in date, the caller decides what a broken header means
+    assert!(parse_date("sometime in January").is_none());
+    assert!(parse_date("").is_none());
+}
+
+const MBOX: &str = "From billing@vendor.com Mon Jan 15 10:00:00 2024\n\
+From: billing@vendor.com\n\
+Subject: First\n\
+\n\
+>From the desk of billing\n\
+\n\
+From MAILER-DAEMON Tue Jan 16 10:00:00 2024\n\
+From: other@vendor.com\n\
+Subject: Second\n\
+\n\
+Hello\n";
+
+#[test]
+fn test_mbox_messages() {
+    let messages: Vec<Vec<u8>> = MboxMessages::new(MBOX.as_bytes())
+        .collect::<std::io::Result<_>>()
+        .unwrap();
+    assert_eq!(messages.len(), 2);
+    let first = String::from_utf8(messages[0].clone()).unwrap();
+    assert!(first.starts_with("From: billing@vendor.com\n"));
+    assert!(first.contains("\nFrom the desk of billing\n"));
+    assert!(String::from_utf8(messages[1].clone())
+        .unwrap()
+        .contains("Subject: Second"));
+}
+
+#[test]
+fn test_mail_archive_detect() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let maildir = temp_dir.path().join("Faktury");
+    fs::create_dir_all(maildir.join("cur")).unwrap();
+    fs::create_dir_all(maildir.join("tmp")).unwrap();
+    fs::write(maildir.join("cur/1:2,S"), "Subject: read\r\n\r\n").unwrap();
+    fs::write(maildir.join("tmp/2"), "Subject: partial\r\n\r\n").unwrap();
+    let archive = MailArchive::detect(&maildir).unwrap();
+    assert_eq!(archive, MailArchive::Maildir(maildir.clone()));
+    assert_eq!(archive.messages().unwrap().count(), 1);
+
+    let mbox = temp_dir.path().join("Inbox");
+    fs::write(&mbox, MBOX).unwrap();
+    assert_eq!(MailArchive::detect(&mbox).unwrap(), MailArchive::Mbox(mbox));
+    assert_eq!(
+        MailArchive::detect(temp_dir.path()).unwrap(),
+        MailArchive::Eml(temp_dir.path().to_path_buf())
+    );
+    assert!(MailArchive::detect(&temp_dir.path().join("missing")).is_err());
+}
+
+fn vendor_rules(destination: &Path) -> Vec<Rule> {
+    parse_rules(&format!(
+        "[[rule]]\nname = \"vendor\"\nmatch = {{ sender_domain = \"vendor.com\" }}\n\
+         action = {{ destination = {:?} }}",
+        destination.to_string_lossy()
+    ))
+    .unwrap()
+}
+
+/// `NESTED_EMAIL` sent on `date`, an RFC 2822 date.
+fn nested_email_on(date: &str) -> String {
+    NESTED_EMAIL.replace(
+        "Subject: Invoice\r\n",
+        &format!("Subject: Invoice\r\nDate: {}\r\n", date),
+    )
+}
+
+#[test]
+fn test_process_archive() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let archive_dir = temp_dir.path().join("export");
+    fs::create_dir_all(&archive_dir).unwrap();
+    let nested = NESTED_EMAIL.replace(
+        "Subject: Invoice\r\n",
+        "Subject: Invoice\r\nDate: Mon, 15 Jan 2024 10:00:00 +0000\r\n",
+    );
+    fs::write(archive_dir.join("1.eml"), &nested).unwrap();
+    // NOTE: The same message exported twice is only saved once
+    fs::write(archive_dir.join("2.eml"), &nested).unwrap();
+    fs::write(
+        archive_dir.join("3.eml"),
+        "From: someone@else.com\r\nSubject: Hi\r\nDate: Mon, 15 Jan 2024 11:00:00 +0000\r\n\r\nHello\r\n",
+    )
+    .unwrap();
+    // NOTE: Without a date there is no month to file the invoice under
+    fs::write(archive_dir.join("4.eml"), NESTED_EMAIL).unwrap();
+    let destination = temp_dir.path().join("invoices");
+    let rules = vendor_rules(&destination);
+    let filter_rules = define_rules(&rules, None, Warsaw);
+    let dedupe_index = Mutex::new(DedupeIndex::load(&temp_dir.path().join("index.json")).unwrap());
+    let report = process_archive(
+        &MailArchive::detect(&archive_dir).unwrap(),
+        "import",
+        &filter_rules,
+        &dedupe_index,
+        &MultiProgress::new(),
+        &ProcessOptions {
+            rules,
+            ..Default::default()
+        },
+    )
+    .unwrap();
+    let saved = destination.join("2024/2024_01/invoice.pdf");
+    assert_eq!(
+        report.outcomes,
+        vec![
+            SaveOutcome::New(saved.clone()),
+            SaveOutcome::Duplicate(saved.clone())
+        ]
+    );
+    assert!(fs::read(&saved).unwrap().starts_with(b"%PDF"));
+    assert!(temp_dir.path().join("index.json").exists());
+    assert_eq!(report.failures.len(), 1);
+    assert!(report.failures[0].item.ends_with("UID 4"));
+    assert!(report.failures[0].error.to_string().contains("Date header"));
+}
+
+const JANUARY: &str = "Mon, 15 Jan 2024 10:00:00 +0000";
+
+fn january_inbox() -> FakeMailSource {
+    let mut source = FakeMailSource::new(&["IMAP4rev1"]);
+    source.deliver("INBOX", &nested_email_on(JANUARY));
+    source.deliver(
+        "INBOX",
+        &format!(
+            "From: someone@else.com\r\nSubject: Hi\r\nDate: {}\r\n\r\nHello\r\n",
+            JANUARY
+        ),
+    );
+    source.deliver("INBOX", &nested_email_on("Thu, 15 Feb 2024 10:00:00 +0000"));
+    source
+}
+
+#[test]
+fn test_search_emails() {
+    let mut source = january_inbox();
+    let rules = define_rules(
+        &vendor_rules(Path::new("/tmp")),
+        DateRange::month(2024, 1),
+        Warsaw,
+    );
+    let post_actions = PostActions {
+        flag: Some("$AntworkerSaved".to_string()),
+        ..Default::default()
+    };
+    source.select("INBOX").unwrap();
+    assert_eq!(
+        search_emails(&mut source, &rules, "1:*", &post_actions).unwrap(),
+        vec![1]
+    );
+    // NOTE: Messages an earlier run flagged are left out
+    source.add_flags("1", &["$AntworkerSaved"]).unwrap();
+    assert!(search_emails(&mut source, &rules, "1:*", &post_actions)
+        .unwrap()
+        .is_empty());
+}
+
+/// Details of fetched messages that all could be read.
+fn read_details(fetched: Vec<FetchedDetails>) -> Vec<EmailDetails> {
+    fetched
+        .into_iter()
+        .map(|(_, details)| details.unwrap())
+        .collect()
+}
+
+#[test]
+fn test_get_email_details() {
+    let mut source = january_inbox();
+    let mut rules = define_rules(
+        &vendor_rules(Path::new("/tmp")),
+        DateRange::month(2024, 1),
+        Warsaw,
+    );
+    source.examine("INBOX").unwrap();
+    let details = read_details(get_email_details(&mut source, "1:*", &rules, "work").unwrap());
+    assert_eq!(details.len(), 1);
+    assert_eq!(details[0].uid, 1);
+    assert_eq!(details[0].from, vec!["billing@vendor.com"]);
+    assert_eq!(details[0].subject, "Invoice");
+    // NOTE: No rules keep every message
+    rules.rules.clear();
+    assert_eq!(
+        get_email_details(&mut source, "2:3", &rules, "work")
+            .unwrap()
+            .len(),
+        2
+    );
+}
+
+#[test]
+fn test_get_and_save_attachments() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let destination = temp_dir.path().join("invoices");
+    let rules = vendor_rules(&destination);
+    let filter_rules = define_rules(&rules, None, Warsaw);
+    let mut source = january_inbox();
+    source.select("INBOX").unwrap();
+    let details =
+        read_details(get_email_details(&mut source, "1:*", &filter_rules, "work").unwrap());
+    let dedupe_index = Mutex::new(DedupeIndex::load(&temp_dir.path().join("index.json")).unwrap());
+    let outcomes: Vec<(u32, SaveOutcome)> = get_and_save_attachments(
+        &EmailAccountBuilder::new("imap.example.com", 993, "me@example.com", "secret"),
+        &details,
+        &mut source,
+        &filter_rules,
+        &dedupe_index,
+        &MultiProgress::new(),
+        &ProcessOptions {
+            rules,
+            ..Default::default()
+        },
+    )
+    .into_iter()
+    .map(|(uid, outcome)| (uid, outcome.unwrap()))
+    .collect();
+    let january = destination.join("2024/2024_01/invoice.pdf");
+    let february = destination.join("2024/2024_02/invoice.pdf");
+    assert_eq!(
+        outcomes,
+        vec![
+            (1, SaveOutcome::New(january.clone())),
+            (3, SaveOutcome::Duplicate(january.clone()))
+        ]
+    );
+    assert!(fs::read(&january).unwrap().starts_with(b"%PDF"));
+    assert!(!february.exists());
+    // NOTE: Bodies are peeked at, marking them read is up to the post actions
+    assert!(source.flags("INBOX", 1).is_empty());
+    assert!(source.flags("INBOX", 2).is_empty());
+}
+
+#[test]
+fn test_apply_post_actions_move() {
+    let post_actions = PostActions {
+        flag: Some("$AntworkerSaved".to_string()),
+        move_to: Some("Faktury".to_string()),
+        ..Default::default()
+    };
+    let mut source = january_inbox();
+    source.mailbox("Faktury");
+    source.capabilities.push("MOVE".to_string());
+    source.select("INBOX").unwrap();
+    apply_post_actions(&mut source, &post_actions, &[1, 3]).unwrap();
+    assert_eq!(
+        source.log,
+        vec!["STORE 1,3 +FLAGS ($AntworkerSaved)", "MOVE 1,3 Faktury"]
+    );
+    assert_eq!(source.uids("INBOX"), vec![2]);
+    assert_eq!(source.uids("Faktury"), vec![1, 2]);
+    assert_eq!(source.flags("Faktury", 1), vec!["$AntworkerSaved"]);
+}
+
+#[test]
+fn test_apply_post_actions_move_without_capability() {
+    let post_actions = PostActions {
+        mark_seen: true,
+        move_to: Some("Faktury".to_string()),
+        ..Default::default()
+    };
+    let mut source = january_inbox();
+    source.mailbox("Faktury");
+    source.capabilities.push("UIDPLUS".to_string());
+    source.select("INBOX").unwrap();
+    // NOTE: Someone else's deleted message must survive the UID EXPUNGE
+    source.add_flags("2", &["\\Deleted"]).unwrap();
+    source.log.clear();
+    apply_post_actions(&mut source, &post_actions, &[1]).unwrap();
+    assert_eq!(
+        source.log,
+        vec![
+            "STORE 1 +FLAGS (\\Seen)",
+            "COPY 1 Faktury",
+            "STORE 1 +FLAGS (\\Deleted)",
+            "UID EXPUNGE 1"
+        ]
+    );
+    assert_eq!(source.uids("INBOX"), vec![2, 3]);
+    assert_eq!(source.flags("Faktury", 1), vec!["\\Seen"]);
+}
+
+#[test]
+fn test_apply_post_actions_move_without_uidplus() {
+    let mut post_actions = PostActions {
+        move_to: Some("Faktury".to_string()),
+        ..Default::default()
+    };
+    let mut source = january_inbox();
+    source.mailbox("Faktury");
+    source.select("INBOX").unwrap();
+    source.add_flags("2", &["\\Deleted"]).unwrap();
+    source.log.clear();
+    // NOTE: A plain EXPUNGE would take message 2 with it, so the moved one is only marked
+    apply_post_actions(&mut source, &post_actions, &[1]).unwrap();
+    assert_eq!(
+        source.log,
+        vec!["COPY 1 Faktury", "STORE 1 +FLAGS (\\Deleted)"]
+    );
+    assert_eq!(source.uids("INBOX"), vec![1, 2, 3]);
+    assert_eq!(source.uids("Faktury"), vec![1]);
+    // NOTE: Unless the account opted in
+    post_actions.expunge_without_uidplus = true;
+    source.log.clear();
+    apply_post_actions(&mut source, &post_actions, &[3]).unwrap();
+    assert_eq!(
+        source.log,
+        vec!["COPY 3 Faktury", "STORE 3 +FLAGS (\\Deleted)", "EXPUNGE"]
+    );
+    assert_eq!(source.uids("INBOX"), Vec::<u32>::new());
+}
+
+#[test]
+fn test_resolve_mailboxes() {
+    let mut source = FakeMailSource::new(&[]);
+    source.mailbox("Faktury").selectable = false;
+    for name in ["INBOX", "Faktury/2023", "Faktury/2024", "Wys&AUI-ane"] {
+        source.mailbox(name);
+    }
+    let patterns: Vec<String> = ["Faktury*", "inbox", "Wysłane", "Faktury/2024", "Missing"]
+        .iter()
+        .map(|pattern| pattern.to_string())
+        .collect();
+    let resolved: Vec<MailboxName> = resolve_mailboxes(&mut source, &patterns).unwrap();
+    assert_eq!(
+        resolved
+            .iter()
+            .map(|mailbox| (mailbox.raw.as_str(), mailbox.decoded.as_str()))
+            .collect::<Vec<_>>(),
+        vec![
+            ("Faktury/2023", "Faktury/2023"),
+            ("Faktury/2024", "Faktury/2024"),
+            ("INBOX", "INBOX"),
+            ("Wys&AUI-ane", "Wysłane"),
+        ]
+    );
+}
+
+/// Sent in the month `march_clock` stands still in.
+const MARCH: &str = "Fri, 15 Mar 2024 10:00:00 +0000";
+
+/// "Now" for the pipeline tests, pinned so they do not depend on the month they run in.
+fn march_clock() -> FixedClock {
+    FixedClock(Warsaw.with_ymd_and_hms(2024, 3, 20, 12, 0, 0).unwrap())
+}
+
+#[test]
+fn test_process_new_messages() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let destination = temp_dir.path().join("invoices");
+    // NOTE: The pipeline only looks at the current month
+    let options = ProcessOptions {
+        rules: vendor_rules(&destination),
+        timeframe: Some(get_current_month_range(&march_clock())),
+        ..Default::default()
+    };
+    let mut source = FakeMailSource::new(&["IMAP4rev1"]);
+    source.deliver("INBOX", &nested_email_on(MARCH));
+    source.deliver("INBOX", &nested_email_on(JANUARY));
+    let uid_store = Mutex::new(UidStore::load(&temp_dir.path().join("state.json")).unwrap());
+    let dedupe_index = Mutex::new(DedupeIndex::load(&temp_dir.path().join("index.json")).unwrap());
+    let account = EmailAccountBuilder::new("imap.example.com", 993, "me@example.com", "secret");
+    let run = |source: &mut FakeMailSource| {
+        let account = with_stored_uid_set(account.clone(), &uid_store.lock().unwrap());
+        process_new_messages(
+            source,
+            &account,
+            &uid_store,
+            &dedupe_index,
+            &MultiProgress::new(),
+            &options,
+        )
+        .unwrap()
+    };
+    let saved = destination.join("2024/2024_03");
+    assert_eq!(
+        run(&mut source).outcomes,
+        vec![SaveOutcome::New(saved.join("invoice.pdf"))]
+    );
+    // NOTE: Nothing new arrived, so the second run does not fetch anything
+    assert!(run(&mut source).outcomes.is_empty());
+    source.deliver("INBOX", &nested_email_on(MARCH));
+    assert_eq!(
+        run(&mut source).outcomes,
+        vec![SaveOutcome::Duplicate(saved.join("invoice.pdf"))]
+    );
+    // NOTE: A new UIDVALIDITY starts over, the content is already indexed
+    source.mailbox("INBOX").uid_validity = 2;
+    assert_eq!(
+        run(&mut source).outcomes,
+        vec![
+            SaveOutcome::Duplicate(saved.join("invoice.pdf")),
+            SaveOutcome::Duplicate(saved.join("invoice.pdf"))
+        ]
+    );
+    assert!(temp_dir.path().join("state.json").exists());
+}
+
+#[test]
+fn test_process_new_messages_late_previous_month() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let destination = temp_dir.path().join("invoices");
+    let options = ProcessOptions {
+        rules: vendor_rules(&destination),
+        timeframe: Some(get_current_month_range(&march_clock())),
+        ..Default::default()
+    };
+    let mut source = FakeMailSource::new(&["IMAP4rev1"]);
+    source.deliver("INBOX", &nested_email_on(MARCH));
+    let uid_store = Mutex::new(UidStore::load(&temp_dir.path().join("state.json")).unwrap());
+    let dedupe_index = Mutex::new(DedupeIndex::load(&temp_dir.path().join("index.json")).unwrap());
+    let account = EmailAccountBuilder::new("imap.example.com", 993, "me@example.com", "secret");
+    let run = |source: &mut FakeMailSource| {
+        let account = with_stored_uid_set(account.clone(), &uid_store.lock().unwrap());
+        process_new_messages(
+            source,
+            &account,
+            &uid_store,
+            &dedupe_index,
+            &MultiProgress::new(),
+            &options,
+        )
+        .unwrap()
+    };
+    assert_eq!(run(&mut source).outcomes.len(), 1);
+    // NOTE: A February invoice delivered in March is new, even though it is not from this month
+    source.deliver(
+        "INBOX",
+        &nested_email_on("Thu, 29 Feb 2024 10:00:00 +0000").replace("JVBERi0xLjQK", "JVBERi0xLjUK"),
+    );
+    assert_eq!(
+        run(&mut source).outcomes,
+        vec![SaveOutcome::New(
+            destination.join("2024/2024_02/invoice.pdf")
+        )]
+    );
+    let key = state_key("imap.example.com", "me@example.com", "INBOX");
+    assert_eq!(uid_store.lock().unwrap().get(&key).unwrap().last_uid, 2);
+}
+
+#[test]
+fn test_process_new_messages_rescan() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let destination = temp_dir.path().join("invoices");
+    let mut options = ProcessOptions {
+        rules: vendor_rules(&destination),
+        timeframe: Some(get_current_month_range(&march_clock())),
+        ..Default::default()
+    };
+    let mut source = FakeMailSource::new(&["IMAP4rev1"]);
+    // NOTE: Another PDF, so the January invoice is not a duplicate of this month's
+    source.deliver(
+        "INBOX",
+        &nested_email_on(JANUARY).replace("JVBERi0xLjQK", "JVBERi0xLjUK"),
+    );
+    source.deliver("INBOX", &nested_email_on(MARCH));
+    let uid_store = Mutex::new(UidStore::load(&temp_dir.path().join("state.json")).unwrap());
+    let dedupe_index = Mutex::new(DedupeIndex::load(&temp_dir.path().join("index.json")).unwrap());
+    let account = EmailAccountBuilder::new("imap.example.com", 993, "me@example.com", "secret");
+    let mut run = |options: &ProcessOptions| {
+        let account = with_stored_uid_set(account.clone(), &uid_store.lock().unwrap());
+        process_new_messages(
+            &mut source,
+            &account,
+            &uid_store,
+            &dedupe_index,
+            &MultiProgress::new(),
+            options,
+        )
+        .unwrap()
+    };
+    assert_eq!(run(&options).outcomes.len(), 1);
+    // NOTE: January is below the marker, only a rescan finds it, and leaves the marker alone
+    options.timeframe = DateRange::month(2024, 1);
+    assert!(run(&options).outcomes.is_empty());
+    options.rescan = true;
+    assert_eq!(
+        run(&options).outcomes,
+        vec![SaveOutcome::New(
+            destination.join("2024/2024_01/invoice.pdf")
+        )]
+    );
+    let key = state_key("imap.example.com", "me@example.com", "INBOX");
+    assert_eq!(uid_store.lock().unwrap().get(&key).unwrap().last_uid, 2);
+}
+
+#[test]
+fn test_process_options_by_month() {
+    let options = ProcessOptions {
+        timeframe: Some(
+            DateRange::new(
+                NaiveDate::from_ymd_opt(2023, 12, 10).unwrap(),
+                NaiveDate::from_ymd_opt(2024, 2, 5).unwrap(),
+            )
+            .unwrap(),
+        ),
+        rescan: true,
+        ..Default::default()
+    };
+    let timeframes: Vec<String> = options
+        .by_month()
+        .iter()
+        .map(|month| {
+            let timeframe = month.timeframe.unwrap();
+            format!("{} {}", timeframe.from, timeframe.to)
+        })
+        .collect();
+    assert_eq!(
+        timeframes,
+        [
+            "2023-12-10 2023-12-31",
+            "2024-01-01 2024-01-31",
+            "2024-02-01 2024-02-05"
+        ]
+    );
+    // NOTE: Regular runs only look at new messages, there is nothing to split
+    let options = ProcessOptions {
+        rescan: false,
+        ..options
+    };
+    assert_eq!(options.by_month().len(), 1);
+}
+
+#[test]
+fn test_backfill_saves_into_month_of_email() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let destination = temp_dir.path().join("invoices");
+    let options = ProcessOptions {
+        rules: vendor_rules(&destination),
+        timeframe: Some(
+            DateRange::new(
+                NaiveDate::from_ymd_opt(2023, 12, 1).unwrap(),
+                NaiveDate::from_ymd_opt(2024, 1, 31).unwrap(),
+            )
+            .unwrap(),
+        ),
+        timezone: Warsaw,
+        rescan: true,
+        ..Default::default()
+    };
+    let mut source = FakeMailSource::new(&["IMAP4rev1"]);
+    source.deliver(
+        "INBOX",
+        &nested_email_on("Fri, 15 Dec 2023 10:00:00 +0000").replace("JVBERi0xLjQK", "JVBERi0xLjMK"),
+    );
+    source.deliver("INBOX", &nested_email_on(JANUARY));
+    // NOTE: 00:30 on February 1st in Warsaw, outside the range although January in UTC
+    source.deliver(
+        "INBOX",
+        &nested_email_on("Wed, 31 Jan 2024 23:30:00 +0000").replace("JVBERi0xLjQK", "JVBERi0xLjUK"),
+    );
+    let uid_store = Mutex::new(UidStore::load(&temp_dir.path().join("state.json")).unwrap());
+    let dedupe_index = Mutex::new(DedupeIndex::load(&temp_dir.path().join("index.json")).unwrap());
+    let account = EmailAccountBuilder::new("imap.example.com", 993, "me@example.com", "secret");
+    let mut outcomes = Vec::new();
+    for month_options in options.by_month() {
+        let report = process_new_messages(
+            &mut source,
+            &account,
+            &uid_store,
+            &dedupe_index,
+            &MultiProgress::new(),
+            &month_options,
+        )
+        .unwrap();
+        outcomes.extend(report.outcomes);
+    }
+    assert_eq!(
+        outcomes,
+        vec![
+            SaveOutcome::New(destination.join("2023/2023_12/invoice.pdf")),
+            SaveOutcome::New(destination.join("2024/2024_01/invoice.pdf")),
+        ]
+    );
+    let key = state_key("imap.example.com", "me@example.com", "INBOX");
+    assert!(uid_store.lock().unwrap().get(&key).is_none());
+}
+
+#[test]
+fn test_process_new_messages_failure() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let destination = temp_dir.path().join("invoices");
+    let options = ProcessOptions {
+        rules: vendor_rules(&destination),
+        timeframe: Some(get_current_month_range(&march_clock())),
+        ..Default::default()
+    };
+    let mut source = FakeMailSource::new(&["IMAP4rev1"]);
+    for _ in 0..3 {
+        source.deliver("INBOX", &nested_email_on(MARCH));
+    }
+    source.unavailable.push(2);
+    let uid_store = Mutex::new(UidStore::load(&temp_dir.path().join("state.json")).unwrap());
+    let dedupe_index = Mutex::new(DedupeIndex::load(&temp_dir.path().join("index.json")).unwrap());
+    let account = EmailAccountBuilder::new("imap.example.com", 993, "me@example.com", "secret")
+        .account("work")
+        .post_actions(&PostActions {
+            flag: Some("$AntworkerSaved".to_string()),
+            ..Default::default()
+        });
+    let run = |source: &mut FakeMailSource| {
+        let account = with_stored_uid_set(account.clone(), &uid_store.lock().unwrap());
+        process_new_messages(
+            source,
+            &account,
+            &uid_store,
+            &dedupe_index,
+            &MultiProgress::new(),
+            &options,
+        )
+        .unwrap()
+    };
+    let report = run(&mut source);
+    assert_eq!(report.outcomes.len(), 2);
+    assert_eq!(report.failures.len(), 1);
+    assert_eq!(report.failures[0].item, "work/INBOX UID 2");
+    assert!(report.failures[0].error.to_string().contains("UNAVAILABLE"));
+    // NOTE: The failed message is left alone and stored to be tried again
+    assert!(source
+        .flags("INBOX", 1)
+        .contains(&"$AntworkerSaved".to_string()));
+    assert!(!source
+        .flags("INBOX", 2)
+        .contains(&"$AntworkerSaved".to_string()));
+    assert!(source
+        .flags("INBOX", 3)
+        .contains(&"$AntworkerSaved".to_string()));
+    let key = state_key("imap.example.com", "me@example.com", "INBOX");
+    assert_eq!(uid_store.lock().unwrap().get(&key).unwrap().last_uid, 3);
+    assert_eq!(uid_store.lock().unwrap().failed_uids(&key, 1), vec![2]);
+
+    source.unavailable.clear();
+    let report = run(&mut source);
+    assert!(report.failures.is_empty());
+    assert_eq!(report.outcomes.len(), 1);
+    assert_eq!(uid_store.lock().unwrap().get(&key).unwrap().last_uid, 3);
+    assert!(uid_store.lock().unwrap().failed_uids(&key, 1).is_empty());
+}
+
+#[test]
+fn test_process_new_messages_retry_copies_once() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let options = ProcessOptions {
+        rules: vendor_rules(&temp_dir.path().join("invoices")),
+        timeframe: Some(get_current_month_range(&march_clock())),
+        ..Default::default()
+    };
+    let mut source = FakeMailSource::new(&["IMAP4rev1", "UIDPLUS"]);
+    for _ in 0..3 {
+        source.deliver("INBOX", &nested_email_on(MARCH));
+    }
+    source.mailbox("Faktury");
+    source.unavailable.push(2);
+    let uid_store = Mutex::new(UidStore::load(&temp_dir.path().join("state.json")).unwrap());
+    let dedupe_index = Mutex::new(DedupeIndex::load(&temp_dir.path().join("index.json")).unwrap());
+    let account = EmailAccountBuilder::new("imap.example.com", 993, "me@example.com", "secret")
+        .post_actions(&PostActions {
+            copy_to: Some("Faktury".to_string()),
+            ..Default::default()
+        });
+    let run = |source: &mut FakeMailSource| {
+        let account = with_stored_uid_set(account.clone(), &uid_store.lock().unwrap());
+        process_new_messages(
+            source,
+            &account,
+            &uid_store,
+            &dedupe_index,
+            &MultiProgress::new(),
+            &options,
+        )
+        .unwrap()
+    };
+    assert_eq!(run(&mut source).failures.len(), 1);
+    assert_eq!(source.uids("Faktury").len(), 2);
+
+    source.unavailable.clear();
+    assert!(run(&mut source).failures.is_empty());
+    // NOTE: Only the retried message is copied, the two that succeeded before are not again
+    assert_eq!(source.uids("Faktury").len(), 3);
+    assert!(run(&mut source).is_empty());
+    assert_eq!(source.uids("Faktury").len(), 3);
+}
+
+#[test]
+fn test_process_new_messages_unreadable_envelope() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let options = ProcessOptions {
+        rules: vendor_rules(&temp_dir.path().join("invoices")),
+        timeframe: Some(get_current_month_range(&march_clock())),
+        ..Default::default()
+    };
+    let mut source = FakeMailSource::new(&["IMAP4rev1"]);
+    for _ in 0..3 {
+        source.deliver("INBOX", &nested_email_on(MARCH));
+    }
+    source.broken.push(2);
+    let uid_store = Mutex::new(UidStore::load(&temp_dir.path().join("state.json")).unwrap());
+    let dedupe_index = Mutex::new(DedupeIndex::load(&temp_dir.path().join("index.json")).unwrap());
+    let account = EmailAccountBuilder::new("imap.example.com", 993, "me@example.com", "secret")
+        .account("work");
+    let report = process_new_messages(
+        &mut source,
+        &account,
+        &uid_store,
+        &dedupe_index,
+        &MultiProgress::new(),
+        &options,
+    )
+    .unwrap();
+    // NOTE: The rest of the batch is still saved, the broken message is retried next run
+    assert_eq!(report.outcomes.len(), 2);
+    assert_eq!(report.failures.len(), 1);
+    assert_eq!(report.failures[0].item, "work/INBOX UID 2");
+    assert!(report.failures[0].error.to_string().contains("no envelope"));
+    let key = state_key("imap.example.com", "me@example.com", "INBOX");
+    assert_eq!(uid_store.lock().unwrap().failed_uids(&key, 1), vec![2]);
+}
+
+#[test]
+fn test_record_saved_after_concurrent_save() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let mut dedupe_index = DedupeIndex::load(&temp_dir.path().join("index.json")).unwrap();
+    let entry = |path: &Path| IndexEntry {
+        account: "work".to_string(),
+        mailbox: "INBOX".to_string(),
+        uid: 1,
+        message_id: None,
+        category: Default::default(),
+        path: path.to_path_buf(),
+    };
+    let first = temp_dir.path().join("first.pdf");
+    fs::write(&first, "%PDF").unwrap();
+    let outcome = record_saved(
+        &mut dedupe_index,
+        "hash",
+        SaveOutcome::New(first.clone()),
+        DuplicatePolicy::Skip,
+        entry(&first),
+    )
+    .unwrap();
+    assert_eq!(outcome, SaveOutcome::New(first.clone()));
+    // NOTE: Another account recorded the same content while this copy was written
+    let second = temp_dir.path().join("second.pdf");
+    fs::write(&second, "%PDF").unwrap();
+    let outcome = record_saved(
+        &mut dedupe_index,
+        "hash",
+        SaveOutcome::New(second.clone()),
+        DuplicatePolicy::Skip,
+        entry(&second),
+    )
+    .unwrap();
+    assert_eq!(outcome, SaveOutcome::Duplicate(first.clone()));
+    assert!(!second.exists());
+    fs::write(&second, "%PDF").unwrap();
+    let outcome = record_saved(
+        &mut dedupe_index,
+        "hash",
+        SaveOutcome::New(second.clone()),
+        DuplicatePolicy::Link,
+        entry(&second),
+    )
+    .unwrap();
+    assert_eq!(outcome, SaveOutcome::Linked(second.clone()));
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        assert_eq!(
+            fs::metadata(&first).unwrap().ino(),
+            fs::metadata(&second).unwrap().ino()
+        );
+    }
+}
+
+fn quick_retry() -> RetryPolicy {
+    RetryPolicy {
+        max_attempts: 3,
+        initial_backoff_ms: 0,
+        max_backoff_ms: 0,
+        jitter: false,
+    }
+}
+
+/// A source whose first session drops the connection when UID 2 is fetched, later sessions get
+/// `reconnected` applied.
+fn flaky_source(
+    connections: &Cell<u32>,
+    reconnected: fn(&mut FakeMailSource),
+) -> ReconnectingSource<FakeMailSource, impl FnMut() -> SourceResult<FakeMailSource> + '_> {
+    let template = january_inbox();
+    ReconnectingSource::connect(quick_retry(), move || {
+        connections.set(connections.get() + 1);
+        let mut session = template.clone();
+        match connections.get() {
+            1 => session.dropped.push(2),
+            _ => reconnected(&mut session),
+        }
+        Ok(session)
+    })
+    .unwrap()
+}
+
+#[test]
+fn test_reconnecting_source_resumes() {
+    let connections = Cell::new(0);
+    let mut source = flaky_source(&connections, |_| {});
+    source.select("INBOX").unwrap();
+    for uid in 1..=3 {
+        assert!(!source.fetch_body(uid, false).unwrap().is_empty());
+    }
+    assert_eq!(connections.get(), 2);
+    // NOTE: The new session selected INBOX again and went on from UID 2
+    let session = source.session().unwrap();
+    assert_eq!(session.flags("INBOX", 2), vec!["\\Seen"]);
+    assert_eq!(session.flags("INBOX", 3), vec!["\\Seen"]);
+}
+
+#[test]
+fn test_reconnecting_source_uid_validity_changed() {
+    let connections = Cell::new(0);
+    let mut source = flaky_source(&connections, |session| {
+        session.mailbox("INBOX").uid_validity = 2
+    });
+    source.select("INBOX").unwrap();
+    let error = source.fetch_body(2, false).unwrap_err();
+    assert!(error.to_string().contains("UIDVALIDITY"), "{}", error);
+}
+
+#[test]
+fn test_reconnecting_source_command_error() {
+    let connections = Cell::new(0);
+    let mut source = flaky_source(&connections, |_| {});
+    source.session().unwrap().unavailable.push(1);
+    source.select("INBOX").unwrap();
+    assert!(source.fetch_body(1, false).is_err());
+    assert_eq!(connections.get(), 1);
+}
+
+const SELF_SIGNED: &str = "-----BEGIN CERTIFICATE-----
+MIIBgDCCASWgAwIBAgIUNi9g5TD2Pc4dD2gC6+iwTqlMqt8wCgYIKoZIzj0EAwIw
+FDESMBAGA1UEAwwJMTI3LjAuMC4xMCAXDTI2MTAxODEwMTAxOFoYDzIxMjYwOTI0
+MTAxMDE4WjAUMRIwEAYDVQQDDAkxMjcuMC4wLjEwWTATBgcqhkjOPQIBBggqhkjO
+PQMBBwNCAAR+cvhdK5ykg5EsM2gXBl0vsjb/r8L94IvXStoTnVu2z8wmWKgTFpme
+E814LsuM5HalNLLsCy3t0G2XK8Qqnt1fo1MwUTAdBgNVHQ4EFgQUdKN73e+eCIPu
+80TY4mPwY9D9ypEwHwYDVR0jBBgwFoAUdKN73e+eCIPu80TY4mPwY9D9ypEwDwYD
+VR0TAQH/BAUwAwEB/zAKBggqhkjOPQQDAgNJADBGAiEAvKYWU617Qubh9kUVB7Nn
+oHegJfptlC27iar9yqM1QNACIQCwtolEKv2Lp7wfce9uuumNZAwTkuCK94rEcQXT
+7hI3Rg==
+-----END CERTIFICATE-----
+";
+
+#[test]
+fn test_is_loopback() {
+    assert!(is_loopback("localhost"));
+    assert!(is_loopback("127.0.0.1"));
+    assert!(is_loopback("[::1]"));
+    assert!(!is_loopback("192.168.1.10"));
+    assert!(!is_loopback("imap.gmail.com"));
+}
+
+#[test]
+fn test_certificate_fingerprint() {
+    let bundle = parse_ca_bundle(&format!("# bridge\n{}{}", SELF_SIGNED, SELF_SIGNED)).unwrap();
+    assert_eq!(bundle.len(), 2);
+    let fingerprint = certificate_fingerprint(&bundle[0]).unwrap();
+    // NOTE: As printed by `openssl x509 -noout -fingerprint -sha256`
+    assert_eq!(
+        normalize_fingerprint(
+            "90:52:CF:82:D2:4F:09:56:99:3F:30:F8:23:68:D8:98:49:C6:FD:FE:98:C6:BE:CF:02:73:FB:FE:22:79:26:8A"
+        ),
+        Some(fingerprint)
+    );
+    assert_eq!(normalize_fingerprint("90:52:CF"), None);
+    assert!(
+        parse_ca_bundle("-----BEGIN CERTIFICATE-----\nnope\n-----END CERTIFICATE-----").is_err()
+    );
+}
+
+/// Serve one IMAP conversation on loopback, answering every tagged command with `reply`.
+fn serve_imap(greeting: &'static str, reply: &'static str) -> u16 {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        stream.write_all(greeting.as_bytes()).unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut line = String::new();
+        while reader.read_line(&mut line).unwrap_or(0) > 0 {
+            let tag = line.split(' ').next().unwrap_or_default().to_string();
+            if stream
+                .write_all(format!("{} {}\r\n", tag, reply).as_bytes())
+                .is_err()
+            {
+                break;
+            }
+            line.clear();
+        }
+    });
+    port
+}
+
+fn loopback_account(port: u16, security: Security) -> EmailAccountBuilder {
+    EmailAccountBuilder::new("127.0.0.1", port, "me@proton.me", "bridge-secret")
+        .connection(&ConnectionSettings {
+            security,
+            ..Default::default()
+        })
+        .build()
+}
+
+#[test]
+fn test_connect_plaintext_loopback() {
+    let port = serve_imap("* OK Bridge ready\r\n", "OK done");
+    assert!(connect(&loopback_account(port, Security::None)).is_ok());
+}
+
+#[test]
+fn test_connect_starttls_refused() {
+    let port = serve_imap("* OK Bridge ready\r\n", "NO STARTTLS disabled");
+    let error = connect(&loopback_account(port, Security::Starttls))
+        .err()
+        .unwrap();
+    assert!(error.to_string().contains("refused STARTTLS"), "{}", error);
+}
+
+#[test]
+fn test_connect_plaintext_remote() {
+    let account = EmailAccountBuilder::new("192.0.2.1", 143, "me@company.com", "secret")
+        .connection(&ConnectionSettings {
+            security: Security::None,
+            ..Default::default()
+        })
+        .build();
+    let error = connect(&account).err().unwrap();
+    assert!(error.to_string().contains("Refusing"), "{}", error);
+}
+
+/// Accounts parsed from `[accounts.<name>]` sections that only set the password option.
+fn accounts_with_passwords(passwords: &[(&str, &str)]) -> BTreeMap<String, AccountConfig> {
+    let config: String = passwords
+        .iter()
+        .map(|(name, password)| {
+            format!(
+                "[accounts.{}]\nserver = \"127.0.0.1\"\nlogin = \"{}\"\n{}\n",
+                name, name, password
+            )
+        })
+        .collect();
+    Config::parse(&config).unwrap().accounts
+}
+
+#[tokio::test]
+async fn test_run_accounts() {
+    let inboxes = accounts_with_passwords(
+        &["a", "b", "broken", "c", "d"].map(|name| (name, "password = \"secret\"")),
+    );
+    let running = Arc::new(AtomicUsize::new(0));
+    let peak = Arc::new(AtomicUsize::new(0));
+    let (job_running, job_peak) = (Arc::clone(&running), Arc::clone(&peak));
+    let results = run_accounts(inboxes, None, 2, move |name, _| {
+        let now = job_running.fetch_add(1, Ordering::SeqCst) + 1;
+        job_peak.fetch_max(now, Ordering::SeqCst);
+        thread::sleep(Duration::from_millis(50));
+        job_running.fetch_sub(1, Ordering::SeqCst);
+        match name {
+            "broken" => Err("authentication failed".to_string()),
+            "c" => panic!("boom"),
+            _ => Ok(Default::default()),
+        }
+    })
+    .await;
+    assert_eq!(results.len(), 5);
+    assert_eq!(peak.load(Ordering::SeqCst), 2);
+    assert_eq!(
+        results["broken"].as_ref().unwrap_err(),
+        "authentication failed"
+    );
+    assert!(results["c"].as_ref().unwrap_err().contains("panicked"));
+    assert!(["a", "b", "d"].iter().all(|name| results[*name].is_ok()));
+}
+
+#[test]
+fn test_process_new_messages_after_poisoned_lock() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let options = ProcessOptions {
+        rules: vendor_rules(&temp_dir.path().join("invoices")),
+        timeframe: Some(get_current_month_range(&march_clock())),
+        ..Default::default()
+    };
+    let mut source = FakeMailSource::new(&["IMAP4rev1"]);
+    source.deliver("INBOX", &nested_email_on(MARCH));
+    let uid_store = Arc::new(Mutex::new(
+        UidStore::load(&temp_dir.path().join("state.json")).unwrap(),
+    ));
+    let dedupe_index = Arc::new(Mutex::new(
+        DedupeIndex::load(&temp_dir.path().join("index.json")).unwrap(),
+    ));
+    // NOTE: Another account panicked while holding both locks
+    let (poisoned_store, poisoned_index) = (Arc::clone(&uid_store), Arc::clone(&dedupe_index));
+    let _ = thread::spawn(move || {
+        let _store = poisoned_store.lock().unwrap();
+        let _index = poisoned_index.lock().unwrap();
+        panic!("boom");
+    })
+    .join();
+    assert!(uid_store.is_poisoned() && dedupe_index.is_poisoned());
+    let report = process_new_messages(
+        &mut source,
+        &EmailAccountBuilder::new("imap.example.com", 993, "me@example.com", "secret"),
+        &uid_store,
+        &dedupe_index,
+        &MultiProgress::new(),
+        &options,
+    )
+    .unwrap();
+    assert_eq!(report.outcomes.len(), 1);
+}
+
+#[tokio::test]
+async fn test_run_accounts_password_failure() {
+    let inboxes = accounts_with_passwords(&[
+        ("broken", "password_command = \"exit 1\""),
+        ("work", "password = \"secret\""),
+    ]);
+    let processed = Arc::new(Mutex::new(Vec::new()));
+    let job_processed = Arc::clone(&processed);
+    let results = run_accounts(inboxes, None, 2, move |name, email_account| {
+        job_processed
+            .lock()
+            .unwrap()
+            .push((name.to_string(), email_account.password.clone()));
+        Ok(Default::default())
+    })
+    .await;
+    // NOTE: The password is looked up by the account's own task, a failure stays with it
+    assert!(results["broken"]
+        .as_ref()
+        .unwrap_err()
+        .contains("account 'broken'"));
+    assert!(results["work"].is_ok());
+    assert_eq!(
+        *processed.lock().unwrap(),
+        vec![("work".to_string(), "secret".to_string())]
+    );
+}
+
+#[test]
+fn test_watch_inbox() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let destination = temp_dir.path().join("invoices");
+    let context = WatchContext {
+        uid_store: Arc::new(Mutex::new(
+            UidStore::load(&temp_dir.path().join("state.json")).unwrap(),
+        )),
+        dedupe_index: Arc::new(Mutex::new(
+            DedupeIndex::load(&temp_dir.path().join("index.json")).unwrap(),
+        )),
+        multi_progress: MultiProgress::new(),
+        options: ProcessOptions {
+            rules: vendor_rules(&destination),
+            ..Default::default()
+        },
+        clock: Arc::new(FixedClock(
+            Warsaw.with_ymd_and_hms(2024, 1, 20, 12, 0, 0).unwrap(),
+        )),
+    };
+    let mut source = FakeMailSource::new(&["IMAP4rev1", "IDLE"]);
+    source.deliver("INBOX", &nested_email_on(JANUARY));
+    // NOTE: Arrives while idling, with another PDF so it is not skipped as a duplicate
+    source.incoming.push((
+        "INBOX".to_string(),
+        nested_email_on(JANUARY).replace("JVBERi0xLjQK", "JVBERi0xLjUK"),
+    ));
+    let mut attempts = 0;
+    let connect = || {
+        attempts += 1;
+        match attempts {
+            3 => Ok(source.clone()),
+            _ => Err("Connection refused".into()),
+        }
+    };
+    // NOTE: Two refused connections, a session that drops after the IDLE, one more refusal
+    let mut delays = Vec::new();
+    let mut sleep = |delay: Duration| {
+        delays.push(delay.as_secs());
+        delays.len() < 3
+    };
+    let account = EmailAccountBuilder::new("imap.example.com", 993, "me@example.com", "secret");
+    watch_inbox("work/INBOX", connect, &account, &context, &mut sleep);
+    assert_eq!(delays, vec![5, 10, 5]);
+    assert_eq!(
+        fs::read_dir(destination.join("2024/2024_01"))
+            .unwrap()
+            .count(),
+        2
+    );
+    let key = state_key("imap.example.com", "me@example.com", "INBOX");
+    let uid_store = context.uid_store.lock().unwrap();
+    assert_eq!(uid_store.get(&key).unwrap().last_uid, 2);
+}
+
+#[test]
+fn test_process_new_messages_leaves_seen_to_post_actions() {
+    for mark_seen in [false, true] {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let options = ProcessOptions {
+            rules: vendor_rules(&temp_dir.path().join("invoices")),
+            timeframe: DateRange::month(2024, 1),
+            ..Default::default()
+        };
+        let uid_store = Mutex::new(UidStore::load(&temp_dir.path().join("state.json")).unwrap());
+        let dedupe_index =
+            Mutex::new(DedupeIndex::load(&temp_dir.path().join("index.json")).unwrap());
+        let mut source = FakeMailSource::new(&["IMAP4rev1"]);
+        source.deliver("INBOX", &nested_email_on(JANUARY));
+        let post_actions = PostActions {
+            flag: Some("$AntworkerSaved".to_string()),
+            mark_seen,
+            ..Default::default()
+        };
+        let account = EmailAccountBuilder::new("imap.example.com", 993, "me@example.com", "secret")
+            .post_actions(&post_actions)
+            .build();
+        let report = process_new_messages(
+            &mut source,
+            &account,
+            &uid_store,
+            &dedupe_index,
+            &MultiProgress::new(),
+            &options,
+        )
+        .unwrap();
+        assert_eq!(report.outcomes.len(), 1);
+        let flags = source.flags("INBOX", 1);
+        assert!(flags.contains(&"$AntworkerSaved".to_string()));
+        assert_eq!(flags.contains(&"\\Seen".to_string()), mark_seen);
+    }
+}
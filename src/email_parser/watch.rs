@@ -0,0 +1,222 @@
+use std::{
+    path::Path,
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration,
+};
+
+use indicatif::MultiProgress;
+
+use crate::{
+    config::accounts::{Config, CONFIG_PATH},
+    datemath::{
+        clock::{Clock, SystemClock},
+        date::get_current_month_range,
+    },
+    factories::credentials::EmailAccountBuilder,
+    io::dedupe::{DedupeIndex, INDEX_PATH},
+    rules::file::{load_rules, RULES_PATH},
+    state::store::{UidStore, STATE_PATH},
+};
+
+use super::{
+    connection::connect,
+    inbox::{process_new_messages, ProcessOptions},
+    mailbox::resolve_mailboxes,
+    main::with_stored_uid_set,
+    source::MailSource,
+};
+
+/// RFC 2177 asks clients to re-issue IDLE at least every 29 minutes.
+const IDLE_KEEPALIVE: Duration = Duration::from_secs(29 * 60);
+/// How often servers without IDLE support are polled with NOOP.
+const POLL_INTERVAL: Duration = Duration::from_secs(60);
+const INITIAL_BACKOFF: Duration = Duration::from_secs(5);
+const MAX_BACKOFF: Duration = Duration::from_secs(5 * 60);
+
+/// State every watched mailbox shares.
+#[derive(Clone)]
+pub struct WatchContext {
+    pub uid_store: Arc<Mutex<UidStore>>,
+    pub dedupe_index: Arc<Mutex<DedupeIndex>>,
+    pub multi_progress: MultiProgress,
+    pub options: ProcessOptions,
+    pub clock: Arc<dyn Clock + Send + Sync>,
+}
+
+/// Keep one session open and save attachments of every message as soon as it arrives. Returns
+/// an error once the session is gone, `Ok` when `sleep` stops the watch.
+pub fn watch_session(
+    inbox_name: &str,
+    source: &mut dyn MailSource,
+    email_account: &EmailAccountBuilder,
+    context: &WatchContext,
+    sleep: &mut dyn FnMut(Duration) -> bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let has_idle = source.has_capability("IDLE")?;
+    println!(
+        "👀 Watching inbox: {} ({})",
+        inbox_name,
+        match has_idle {
+            true => "IDLE",
+            false => "polling",
+        }
+    );
+    loop {
+        // NOTE: Refresh the UID set so every round only asks for what arrived since the last one
+        let email_account = with_stored_uid_set(
+            email_account.clone(),
+            &context.uid_store.lock().unwrap_or_else(|e| e.into_inner()),
+        );
+        // NOTE: A watch can outlive a month, every round looks at the month it runs in
+        let options = ProcessOptions {
+            timeframe: Some(get_current_month_range(context.clock.as_ref())),
+            ..context.options.clone()
+        };
+        let report = process_new_messages(
+            source,
+            &email_account,
+            &context.uid_store,
+            &context.dedupe_index,
+            &context.multi_progress,
+            &options,
+        )?;
+        if !report.is_empty() {
+            println!("📨 New attachments in inbox: {}\n{}", inbox_name, report);
+        }
+        match has_idle {
+            true => source.idle(IDLE_KEEPALIVE)?,
+            false => {
+                if !sleep(POLL_INTERVAL) {
+                    return Ok(());
+                }
+                source.noop()?;
+            }
+        }
+    }
+}
+
+/// Call `attempt` until it succeeds, waiting `INITIAL_BACKOFF` after the first failure and twice
+/// as long after every further one, up to `MAX_BACKOFF`. `None` once `sleep` stops the watch.
+fn with_backoff<T>(
+    inbox_name: &str,
+    sleep: &mut dyn FnMut(Duration) -> bool,
+    mut attempt: impl FnMut() -> Result<T, Box<dyn std::error::Error>>,
+) -> Option<T> {
+    let mut backoff = INITIAL_BACKOFF;
+    loop {
+        match attempt() {
+            Ok(value) => return Some(value),
+            Err(e) => eprintln!(
+                "Could not connect to inbox {}: {}. Retrying in {}s",
+                inbox_name,
+                e,
+                backoff.as_secs()
+            ),
+        }
+        if !sleep(backoff) {
+            return None;
+        }
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    }
+}
+
+/// Watch one mailbox for good, reconnecting with backoff whenever the session is lost. Returns
+/// only when `sleep` stops the watch.
+pub fn watch_inbox<S: MailSource>(
+    inbox_name: &str,
+    mut connect: impl FnMut() -> Result<S, Box<dyn std::error::Error>>,
+    email_account: &EmailAccountBuilder,
+    context: &WatchContext,
+    sleep: &mut dyn FnMut(Duration) -> bool,
+) {
+    while let Some(mut session) = with_backoff(inbox_name, sleep, &mut connect) {
+        let result = watch_session(inbox_name, &mut session, email_account, context, sleep);
+        match result {
+            Ok(()) => return,
+            Err(e) => eprintln!(
+                "Lost connection to inbox {}: {}. Reconnecting",
+                inbox_name, e
+            ),
+        }
+    }
+}
+
+/// Sleep for real, the watch never stops on its own.
+fn sleep(duration: Duration) -> bool {
+    thread::sleep(duration);
+    true
+}
+
+pub async fn watch_emails(clock: SystemClock) -> Result<(), Box<dyn std::error::Error>> {
+    let config = Config::load(Path::new(CONFIG_PATH.as_str()))?;
+    let options = ProcessOptions {
+        explain: false,
+        attachments: config.attachments.clone(),
+        rules: load_rules(Path::new(RULES_PATH.as_str()))?,
+        retry: config.retry,
+        timezone: clock.timezone(),
+        ..Default::default()
+    };
+    let context = WatchContext {
+        uid_store: Arc::new(Mutex::new(UidStore::load(Path::new(STATE_PATH.as_str()))?)),
+        dedupe_index: Arc::new(Mutex::new(DedupeIndex::load(Path::new(
+            INDEX_PATH.as_str(),
+        ))?)),
+        multi_progress: MultiProgress::new(),
+        options,
+        clock: Arc::new(clock),
+    };
+    let mut handles = Vec::new();
+    for (name, account) in config.accounts.clone() {
+        // NOTE: IDLE only reports changes of the selected mailbox, so every mailbox gets its own
+        // session once the patterns are resolved. An account that cannot be reached yet is
+        // retried with backoff while the others are already being watched.
+        let secrets = config.secrets.clone();
+        let account_name = name.clone();
+        let resolved = tokio::task::spawn_blocking(move || {
+            let email_account = match account.builder(&account_name, secrets.as_ref()) {
+                Ok(email_account) => email_account,
+                Err(e) => {
+                    eprintln!("❌ Not watching {}: {}", account_name, e);
+                    return None;
+                }
+            };
+            let mailboxes = with_backoff(&account_name, &mut sleep, || {
+                let mut imap_session = connect(&email_account)?;
+                let mailboxes = resolve_mailboxes(&mut imap_session, &email_account.mailboxes)?;
+                imap_session.logout()?;
+                Ok(mailboxes)
+            })?;
+            Some((email_account, mailboxes))
+        });
+        let context = context.clone();
+        handles.push(tokio::spawn(async move {
+            let mut watchers = Vec::new();
+            let Some((email_account, mailboxes)) = resolved.await.ok().flatten() else {
+                return;
+            };
+            for mailbox in mailboxes {
+                let inbox_name = format!("{}/{}", name, mailbox.decoded);
+                let email_account = email_account.clone().mailbox(&mailbox.raw);
+                let context = context.clone();
+                watchers.push(tokio::task::spawn_blocking(move || {
+                    watch_inbox(
+                        &inbox_name,
+                        || connect(&email_account),
+                        &email_account,
+                        &context,
+                        &mut sleep,
+                    )
+                }));
+            }
+            for watcher in watchers {
+                let _ = watcher.await;
+            }
+        }));
+    }
+    for handle in handles {
+        handle.await?;
+    }
+    Ok(())
+}
@@ -1,5 +1,4 @@
-pub mod sender;
 pub mod attachment;
+pub mod sender;
 #[cfg(test)]
 mod tests;
-
@@ -1,26 +1,14 @@
-use std::fs;
+use std::{fs, path::Path};
 
 use lettre::message::{header::ContentType, Attachment, SinglePart};
 
-use crate::io::files::get_saved_files;
+use crate::error::AntworkerError;
 
-pub fn add_attachment(filepath: &String) -> SinglePart {
-    let filename = filepath
-        .split("/")
-        .collect::<Vec<&str>>()
-        .last()
-        .unwrap()
-        .to_string();
-    let filebody = fs::read(filepath).unwrap();
-    let content_type = ContentType::parse("application/pdf").unwrap();
-    Attachment::new(filename).body(filebody, content_type)
-}
-
-pub fn add_attachments() -> Vec<SinglePart> {
-    let save_location = get_saved_files();
-    let attachments = save_location
-        .iter()
-        .map(|filepath| add_attachment(filepath))
-        .collect();
-    attachments
+pub fn add_attachment(filepath: &str) -> Result<SinglePart, AntworkerError> {
+    let filename = Path::new(filepath)
+        .file_name()
+        .map(|filename| filename.to_string_lossy().to_string())
+        .ok_or_else(|| AntworkerError::io(filepath, "not a file"))?;
+    let filebody = fs::read(filepath).map_err(|e| AntworkerError::io(filepath, e))?;
+    Ok(Attachment::new(filename).body(filebody, ContentType::parse("application/pdf").unwrap()))
 }
@@ -1,31 +1,136 @@
 use indicatif::{ProgressBar, ProgressIterator, ProgressStyle};
 use lazy_static::lazy_static;
 use lettre::{
-    message::{header::ContentType, MultiPart, SinglePart},
-    transport::smtp::authentication::Credentials,
+    message::{header::ContentType, Mailbox, MultiPart, SinglePart},
+    transport::smtp::authentication::{Credentials, Mechanism},
     Message, SmtpTransport, Transport,
 };
-use std::env::var;
+use std::{env::var, path::Path};
 
-use crate::{io::files::get_saved_files, COMPANY_EMAIL, COMPANY_EMAIL_PASSWORD};
+use crate::{
+    auth::{oauth::access_token, tokens::TOKENS_PATH},
+    config::{
+        accounts::{Config, ConfigError, CONFIG_PATH},
+        env::required_var,
+    },
+    datemath::{
+        clock::SystemClock,
+        date::{get_current_month_range, DateRange},
+        period::Period,
+    },
+    error::{summarize_failures, AntworkerError, Failure},
+    io::files::get_saved_files,
+    retry::{retry, RetryPolicy},
+};
 
-use super::attachment::add_attachments;
+use super::attachment::add_attachment;
 
 lazy_static! {
-    pub static ref TARGET_EMAIL: String = var("TARGET_EMAIL").expect("TARGET_EMAIL must be set.");
+    /// Config account whose login and password or OAuth2 token authenticate to the SMTP server,
+    /// instead of `COMPANY_EMAIL` and `COMPANY_EMAIL_PASSWORD`.
+    pub static ref SMTP_ACCOUNT: Option<String> = var("SMTP_ACCOUNT").ok();
 }
 
-lazy_static! {
-    pub static ref FROM_EMAIL: String = var("FROM_EMAIL").expect("FROM_EMAIL must be set.");
+/// Addresses, subject and server of the invoice emails, read from the environment before
+/// anything is sent.
+#[derive(Debug, Clone)]
+pub struct SendSettings {
+    pub target_email: String,
+    pub from_email: String,
+    pub subject: String,
+    pub smtp_server: String,
 }
 
-lazy_static! {
-    pub static ref SUBJECT: String = var("SUBJECT").expect("SUBJECT must be set.");
+impl SendSettings {
+    pub fn from_env() -> Result<Self, ConfigError> {
+        Ok(SendSettings {
+            target_email: required_var("TARGET_EMAIL")?,
+            from_email: required_var("FROM_EMAIL")?,
+            subject: required_var("SUBJECT")?,
+            smtp_server: required_var("SMTP_TARGET_SERVER")?,
+        })
+    }
 }
 
-lazy_static! {
-    pub static ref SMTP_TARGET_SERVER: String =
-        var("SMTP_TARGET_SERVER").expect("SMTP_TARGET_SERVER must be set.");
+fn smtp_credentials() -> Result<(Credentials, Vec<Mechanism>), AntworkerError> {
+    let password_mechanisms = vec![Mechanism::Plain, Mechanism::Login];
+    let Some(account) = SMTP_ACCOUNT.as_deref() else {
+        return Ok((
+            Credentials::new(
+                required_var("COMPANY_EMAIL")?,
+                required_var("COMPANY_EMAIL_PASSWORD")?,
+            ),
+            password_mechanisms,
+        ));
+    };
+    let config = Config::load(Path::new(CONFIG_PATH.as_str()))?;
+    let account_config = config.accounts.get(account).ok_or_else(|| {
+        ConfigError::Invalid(format!(
+            "SMTP_ACCOUNT '{}' is not in the config file",
+            account
+        ))
+    })?;
+    match &account_config.oauth2 {
+        Some(oauth2) => {
+            let token =
+                access_token(account, oauth2, Path::new(TOKENS_PATH.as_str())).map_err(|e| {
+                    AntworkerError::Smtp(format!(
+                        "could not authenticate to the SMTP server: {}",
+                        e
+                    ))
+                })?;
+            Ok((
+                Credentials::new(account_config.login.clone(), token),
+                vec![Mechanism::Xoauth2],
+            ))
+        }
+        None => Ok((
+            Credentials::new(
+                account_config.login.clone(),
+                account_config.resolve_password(account, config.secrets.as_ref())?,
+            ),
+            password_mechanisms,
+        )),
+    }
+}
+
+/// Polish month names, without diacritics like the rest of the email text.
+const MONTH_NAMES: [&str; 12] = [
+    "styczen",
+    "luty",
+    "marzec",
+    "kwiecien",
+    "maj",
+    "czerwiec",
+    "lipiec",
+    "sierpien",
+    "wrzesien",
+    "pazdziernik",
+    "listopad",
+    "grudzien",
+];
+
+fn month_name(period: Period) -> String {
+    format!(
+        "{} {}",
+        MONTH_NAMES[period.month() as usize - 1],
+        period.format("%Y")
+    )
+}
+
+/// Text of every invoice email sent for `range`, e.g. "W zalaczeniu faktura za miesiac styczen
+/// 2024." for a single month.
+pub fn email_body(range: &DateRange) -> String {
+    let first = Period::of(range.from);
+    let last = Period::of(range.to);
+    match first == last {
+        true => format!("W zalaczeniu faktura za miesiac {}.", month_name(first)),
+        false => format!(
+            "W zalaczeniu faktura za okres {} - {}.",
+            month_name(first),
+            month_name(last)
+        ),
+    }
 }
 
 fn format_email(email: &str) -> String {
@@ -33,61 +138,118 @@ fn format_email(email: &str) -> String {
     format!("{} <{}>", name, email)
 }
 
-pub fn send_emails(is_dry_run: bool) {
-    let attachments = add_attachments();
-    let n_attachments = attachments.len();
-    let pb = ProgressBar::new(n_attachments as u64);
+/// Send every invoice saved for the months `range` touches, the current month without one, each
+/// in its own email. A file that cannot be read or sent is reported at the end and does not stop
+/// the others.
+pub fn send_emails(
+    is_dry_run: bool,
+    range: Option<DateRange>,
+    clock: SystemClock,
+) -> Result<(), AntworkerError> {
+    let settings = SendSettings::from_env()?;
+    let range = range.unwrap_or_else(|| get_current_month_range(&clock));
+    let files = get_saved_files(&range)?;
+    if is_dry_run {
+        dry_run(&files, &settings);
+        return Ok(());
+    }
+
+    let body = email_body(&range);
+    let policy = retry_policy()?;
+    let (credentials, mechanisms) = smtp_credentials()?;
+    let pb = ProgressBar::new(files.len() as u64);
     pb.set_style(
         ProgressStyle::with_template("{spinner:.green} [{bar:40.red}] ({pos}/{len})").unwrap(),
     );
-
-    if is_dry_run {
-        return dry_run(n_attachments);
+    let mut failures = Vec::new();
+    for filepath in files.iter().progress_with(pb) {
+        let sent = add_attachment(filepath).and_then(|attachment| {
+            send_email(
+                attachment,
+                &body,
+                &settings,
+                &credentials,
+                &mechanisms,
+                &policy,
+            )
+        });
+        if let Err(error) = sent {
+            failures.push(Failure {
+                item: filepath.clone(),
+                error,
+            });
+        }
     }
-
-    attachments.iter().progress_with(pb).for_each(|attachment| {
-        send_email(attachment.clone());
-    });
+    summarize_failures(&failures)
 }
 
-fn dry_run(n_attachments: usize) -> () {
-    let att = get_saved_files()
-        .iter()
-        .map(|filepath| filepath.to_string())
-        .collect::<Vec<String>>()
-        .join("\n     ");
+fn dry_run(files: &[String], settings: &SendSettings) {
     println!(
         "The total {} emails will be sent to {} with the following attachments: \n    {}",
-        n_attachments,
-        TARGET_EMAIL.to_string(),
-        att
+        files.len(),
+        settings.target_email,
+        files.join("\n     ")
     );
-    return;
 }
 
-fn send_email(attachment: SinglePart) -> () {
+fn mailbox(email: &str) -> Result<Mailbox, AntworkerError> {
+    format_email(email).parse().map_err(|e| {
+        AntworkerError::Config(ConfigError::Invalid(format!(
+            "'{}' is not an email address: {}",
+            email, e
+        )))
+    })
+}
+
+/// Retry policy from the config file, the default one when there is no config file.
+fn retry_policy() -> Result<RetryPolicy, ConfigError> {
+    match Path::new(CONFIG_PATH.as_str()).exists() {
+        true => Ok(Config::load(Path::new(CONFIG_PATH.as_str()))?.retry),
+        false => Ok(RetryPolicy::default()),
+    }
+}
+
+/// Send one message, again after 4xx replies but never after a permanent 5xx rejection.
+pub fn deliver(
+    mailer: &SmtpTransport,
+    email: &Message,
+    policy: &RetryPolicy,
+) -> Result<(), AntworkerError> {
+    retry(
+        policy,
+        "Sending the email",
+        |e: &lettre::transport::smtp::Error| e.is_transient(),
+        || mailer.send(email),
+    )?;
+    Ok(())
+}
+
+fn send_email(
+    attachment: SinglePart,
+    body: &str,
+    settings: &SendSettings,
+    credentials: &Credentials,
+    mechanisms: &[Mechanism],
+    policy: &RetryPolicy,
+) -> Result<(), AntworkerError> {
     let email = Message::builder()
-        .to(format_email(TARGET_EMAIL.as_str()).parse().unwrap())
-        .from(format_email(FROM_EMAIL.as_str()).parse().unwrap())
-        .subject(SUBJECT.as_str())
+        .to(mailbox(&settings.target_email)?)
+        .from(mailbox(&settings.from_email)?)
+        .subject(settings.subject.as_str())
         .multipart(
             MultiPart::mixed()
-                .singlepart(SinglePart::builder().header(ContentType::TEXT_HTML).body(
-                    String::from("W zalaczeniu faktura za miesiac styczen 2024."),
-                ))
+                .singlepart(
+                    SinglePart::builder()
+                        .header(ContentType::TEXT_HTML)
+                        .body(body.to_string()),
+                )
                 .singlepart(attachment),
-        )
-        .unwrap();
-
-    let from = Credentials::new(COMPANY_EMAIL.to_owned(), COMPANY_EMAIL_PASSWORD.to_owned());
+        )?;
 
-    let mailer = SmtpTransport::relay(SMTP_TARGET_SERVER.as_str())
-        .unwrap()
-        .credentials(from)
+    let mailer = SmtpTransport::relay(&settings.smtp_server)?
+        .credentials(credentials.clone())
+        .authentication(mechanisms.to_vec())
         .build();
 
-    match mailer.send(&email) {
-        Ok(_) => (),
-        Err(e) => panic!("Could not send email: {e:?}"),
-    }
+    deliver(&mailer, &email, policy)
 }
@@ -1,3 +1,22 @@
+use chrono::NaiveDate;
+use lettre::{Message, SmtpTransport};
+use std::{
+    io::{BufRead, BufReader, Write},
+    net::TcpListener,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    thread,
+};
+
+use crate::{
+    datemath::date::DateRange,
+    email_sender::sender::{deliver, email_body},
+    error::AntworkerError,
+    retry::RetryPolicy,
+};
+
 // use std::path::Path;
 // use std::{fs, path::PathBuf};
 //
@@ -64,3 +83,108 @@
 //
 //     std::env::remove_var("ROOT_SAVE_LOCATION_PATH");
 // }
+
+/// Minimal SMTP server on loopback that answers `MAIL FROM` with `replies` in turn, then with
+/// 250. Counts every `MAIL FROM` it got.
+fn serve_smtp(replies: &'static [&'static str]) -> (u16, Arc<AtomicUsize>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let attempts = Arc::new(AtomicUsize::new(0));
+    let counter = Arc::clone(&attempts);
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            stream.write_all(b"220 localhost ESMTP\r\n").unwrap();
+            let mut in_data = false;
+            let mut line = String::new();
+            while reader.read_line(&mut line).unwrap_or(0) > 0 {
+                let command = line.to_uppercase();
+                let reply = match () {
+                    _ if in_data => match line.as_str() {
+                        ".\r\n" => {
+                            in_data = false;
+                            "250 queued"
+                        }
+                        _ => "",
+                    },
+                    _ if command.starts_with("MAIL") => {
+                        let attempt = counter.fetch_add(1, Ordering::SeqCst);
+                        replies.get(attempt).copied().unwrap_or("250 ok")
+                    }
+                    _ if command.starts_with("DATA") => {
+                        in_data = true;
+                        "354 go ahead"
+                    }
+                    _ if command.starts_with("QUIT") => "221 bye",
+                    _ => "250 ok",
+                };
+                if !reply.is_empty() && write!(stream, "{}\r\n", reply).is_err() {
+                    break;
+                }
+                line.clear();
+            }
+        }
+    });
+    (port, attempts)
+}
+
+fn quick_retry() -> RetryPolicy {
+    RetryPolicy {
+        max_attempts: 3,
+        initial_backoff_ms: 1,
+        max_backoff_ms: 1,
+        jitter: false,
+    }
+}
+
+fn invoice_email() -> Message {
+    Message::builder()
+        .to("me <me@example.com>".parse().unwrap())
+        .from("books <books@example.com>".parse().unwrap())
+        .subject("Faktura")
+        .body("W zalaczeniu faktura.".to_string())
+        .unwrap()
+}
+
+#[test]
+fn test_deliver_retries_transient_replies() {
+    let (port, attempts) = serve_smtp(&["451 4.3.0 Try again later"]);
+    let mailer = SmtpTransport::builder_dangerous("127.0.0.1")
+        .port(port)
+        .build();
+    deliver(&mailer, &invoice_email(), &quick_retry()).unwrap();
+    assert_eq!(attempts.load(Ordering::SeqCst), 2);
+}
+
+#[test]
+fn test_deliver_gives_up_on_permanent_replies() {
+    let (port, attempts) = serve_smtp(&["550 5.7.1 Relaying denied"]);
+    let mailer = SmtpTransport::builder_dangerous("127.0.0.1")
+        .port(port)
+        .build();
+    let error = deliver(&mailer, &invoice_email(), &quick_retry()).unwrap_err();
+    assert!(matches!(error, AntworkerError::Smtp(_)), "{}", error);
+    assert_eq!(attempts.load(Ordering::SeqCst), 1);
+}
+
+#[test]
+fn test_email_body() {
+    assert_eq!(
+        email_body(&DateRange::month(2024, 1).unwrap()),
+        "W zalaczeniu faktura za miesiac styczen 2024."
+    );
+    assert_eq!(
+        email_body(&DateRange::month(2023, 10).unwrap()),
+        "W zalaczeniu faktura za miesiac pazdziernik 2023."
+    );
+    let quarter = DateRange::new(
+        NaiveDate::from_ymd_opt(2023, 12, 10).unwrap(),
+        NaiveDate::from_ymd_opt(2024, 2, 15).unwrap(),
+    )
+    .unwrap();
+    assert_eq!(
+        email_body(&quarter),
+        "W zalaczeniu faktura za okres grudzien 2023 - luty 2024."
+    );
+}
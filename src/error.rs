@@ -0,0 +1,104 @@
+use std::fmt::{self, Display, Formatter};
+
+use crate::{config::accounts::ConfigError, rules::file::RulesError};
+
+/// Everything that can go wrong while fetching, saving or sending invoices.
+#[derive(Debug)]
+pub enum AntworkerError {
+    Config(ConfigError),
+    Rules(RulesError),
+    Imap(String),
+    /// A message that could not be parsed as MIME.
+    Mime(String),
+    Io(String),
+    Smtp(String),
+    /// The command finished, but this many messages, files or accounts failed on the way.
+    Incomplete(usize),
+}
+
+impl AntworkerError {
+    /// Wrap an error reported by a mail source.
+    pub fn imap(error: impl Display) -> Self {
+        AntworkerError::Imap(error.to_string())
+    }
+
+    /// Wrap an error about the file or directory at `path`.
+    pub fn io(path: impl AsRef<std::path::Path>, error: impl Display) -> Self {
+        AntworkerError::Io(format!("{}: {}", path.as_ref().display(), error))
+    }
+}
+
+impl Display for AntworkerError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            AntworkerError::Config(e) => write!(f, "{}", e),
+            AntworkerError::Rules(e) => write!(f, "{}", e),
+            AntworkerError::Imap(msg) => write!(f, "IMAP error: {}", msg),
+            AntworkerError::Mime(msg) => write!(f, "Malformed message: {}", msg),
+            AntworkerError::Io(msg) => write!(f, "File error: {}", msg),
+            AntworkerError::Smtp(msg) => write!(f, "SMTP error: {}", msg),
+            AntworkerError::Incomplete(count) => {
+                write!(f, "{} item(s) failed, see the summary above", count)
+            }
+        }
+    }
+}
+
+impl std::error::Error for AntworkerError {}
+
+impl From<ConfigError> for AntworkerError {
+    fn from(e: ConfigError) -> Self {
+        AntworkerError::Config(e)
+    }
+}
+
+impl From<RulesError> for AntworkerError {
+    fn from(e: RulesError) -> Self {
+        AntworkerError::Rules(e)
+    }
+}
+
+impl From<mailparse::MailParseError> for AntworkerError {
+    fn from(e: mailparse::MailParseError) -> Self {
+        AntworkerError::Mime(e.to_string())
+    }
+}
+
+impl From<lettre::transport::smtp::Error> for AntworkerError {
+    fn from(e: lettre::transport::smtp::Error) -> Self {
+        AntworkerError::Smtp(e.to_string())
+    }
+}
+
+impl From<lettre::error::Error> for AntworkerError {
+    fn from(e: lettre::error::Error) -> Self {
+        AntworkerError::Smtp(e.to_string())
+    }
+}
+
+/// A message or file that could not be handled, the run went on without it.
+#[derive(Debug)]
+pub struct Failure {
+    /// What failed, e.g. `work/INBOX UID 42` or a file path.
+    pub item: String,
+    pub error: AntworkerError,
+}
+
+impl Display for Failure {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.item, self.error)
+    }
+}
+
+/// Print every failure and turn them into the error a command exits with, `Ok` when nothing
+/// failed.
+pub fn summarize_failures(failures: &[Failure]) -> Result<(), AntworkerError> {
+    if failures.is_empty() {
+        return Ok(());
+    }
+    eprintln!("❌ {} failed:", failures.len());
+    for failure in failures {
+        eprintln!("  {}", failure);
+    }
+    Err(AntworkerError::Incomplete(failures.len()))
+}
@@ -1,4 +1,3 @@
 pub mod credentials;
 #[cfg(test)]
 mod tests;
-
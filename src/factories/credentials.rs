@@ -1,35 +1,93 @@
+use crate::{
+    auth::oauth::OAuth2Config,
+    email_parser::{actions::PostActions, connection::ConnectionSettings},
+};
+
 #[derive(Debug, Default, Clone, PartialEq)]
 pub struct EmailAccountBuilder {
+    pub account: String,
     pub server: String,
     pub port: u16,
     pub email: String,
     pub password: String,
+    pub mailboxes: Vec<String>,
+    pub mailbox: String,
     pub uid_set: String,
+    pub post_actions: PostActions,
+    pub connection: ConnectionSettings,
+    /// Log in with an OAuth2 access token instead of the password.
+    pub oauth2: Option<OAuth2Config>,
 }
 
 impl EmailAccountBuilder {
     pub fn new(server: &str, port: u16, email: &str, password: &str) -> Self {
         EmailAccountBuilder {
+            account: email.to_string(),
             server: server.to_string(),
             port,
             email: email.to_string(),
             password: password.to_string(),
+            mailboxes: vec!["INBOX".to_string()],
+            mailbox: "INBOX".to_string(),
             uid_set: "1:*".to_string(), // Retrieve all emails by default
+            post_actions: PostActions::default(),
+            connection: ConnectionSettings::default(),
+            oauth2: None,
         }
     }
 
+    /// Name the account goes by in the config file and in rules.
+    pub fn account(mut self, account: &str) -> Self {
+        self.account = account.to_string();
+        self
+    }
+
+    /// Mailbox names or glob patterns to process, resolved against the server's `LIST`.
+    pub fn mailboxes(mut self, mailboxes: &[String]) -> Self {
+        self.mailboxes = mailboxes.to_vec();
+        self
+    }
+
+    /// The single mailbox a session works on.
+    pub fn mailbox(mut self, mailbox: &str) -> Self {
+        self.mailbox = mailbox.to_string();
+        self
+    }
+
     pub fn uid_set(mut self, uid_set: &str) -> Self {
         self.uid_set = uid_set.to_string();
         self
     }
 
+    pub fn post_actions(mut self, post_actions: &PostActions) -> Self {
+        self.post_actions = post_actions.clone();
+        self
+    }
+
+    /// How the IMAP connection is secured.
+    pub fn connection(mut self, connection: &ConnectionSettings) -> Self {
+        self.connection = connection.clone();
+        self
+    }
+
+    pub fn oauth2(mut self, oauth2: &OAuth2Config) -> Self {
+        self.oauth2 = Some(oauth2.clone());
+        self
+    }
+
     pub fn build(self) -> EmailAccountBuilder {
         EmailAccountBuilder {
+            account: self.account,
             server: self.server,
             port: self.port,
             email: self.email,
             password: self.password,
+            mailboxes: self.mailboxes,
+            mailbox: self.mailbox,
             uid_set: self.uid_set,
+            post_actions: self.post_actions,
+            connection: self.connection,
+            oauth2: self.oauth2,
         }
     }
 }
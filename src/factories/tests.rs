@@ -1,14 +1,42 @@
-use crate::factories::credentials::EmailAccountBuilder;
+use crate::{email_parser::actions::PostActions, factories::credentials::EmailAccountBuilder};
 
 #[test]
 fn test_new() {
     let builder = EmailAccountBuilder::new("smtp.example.com", 587, "user@example.com", "password");
 
+    assert_eq!(builder.account, "user@example.com");
     assert_eq!(builder.server, "smtp.example.com");
     assert_eq!(builder.port, 587);
     assert_eq!(builder.email, "user@example.com");
     assert_eq!(builder.password, "password");
+    assert_eq!(builder.mailboxes, vec!["INBOX"]);
+    assert_eq!(builder.mailbox, "INBOX");
     assert_eq!(builder.uid_set, "1:*");
+    assert!(builder.post_actions.is_empty());
+}
+
+#[test]
+fn test_account() {
+    let builder = EmailAccountBuilder::new("smtp.example.com", 587, "user@example.com", "password")
+        .account("work");
+
+    assert_eq!(builder.account, "work");
+}
+
+#[test]
+fn test_mailboxes() {
+    let builder = EmailAccountBuilder::new("smtp.example.com", 587, "user@example.com", "password")
+        .mailboxes(&["INBOX".to_string(), "Faktury/*".to_string()]);
+
+    assert_eq!(builder.mailboxes, vec!["INBOX", "Faktury/*"]);
+}
+
+#[test]
+fn test_mailbox() {
+    let builder = EmailAccountBuilder::new("smtp.example.com", 587, "user@example.com", "password")
+        .mailbox("Faktury");
+
+    assert_eq!(builder.mailbox, "Faktury");
 }
 
 #[test]
@@ -19,6 +47,18 @@ fn test_uid_set() {
     assert_eq!(builder.uid_set, "100:200");
 }
 
+#[test]
+fn test_post_actions() {
+    let post_actions = PostActions {
+        flag: Some("$AntworkerSaved".to_string()),
+        ..PostActions::default()
+    };
+    let builder = EmailAccountBuilder::new("smtp.example.com", 587, "user@example.com", "password")
+        .post_actions(&post_actions);
+
+    assert_eq!(builder.post_actions, post_actions);
+}
+
 #[test]
 fn test_build() {
     let original =
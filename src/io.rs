@@ -1,5 +1,6 @@
-pub mod save_location;
+pub mod dedupe;
 pub mod files;
+pub mod save_location;
 #[cfg(test)]
 mod tests;
-
+pub mod writer;
@@ -0,0 +1,155 @@
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    env::var,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use super::{
+    files::IGNORE_LIST,
+    writer::{sha256_hex, unique_temp_path},
+};
+use crate::rules::action::Category;
+
+lazy_static! {
+    pub static ref INDEX_PATH: String = var("ANTWORKER_INDEX_PATH").unwrap_or_else(|_| {
+        format!(
+            "{}/.config/antworker/index.json",
+            var("HOME").expect("HOME must be set.")
+        )
+    });
+}
+
+/// What to do with an attachment whose content was already saved somewhere else.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DuplicatePolicy {
+    /// Do not write it again.
+    #[default]
+    Skip,
+    /// Hard-link the existing file into the new location.
+    Link,
+    /// Write it again, as if there was no index.
+    Keep,
+}
+
+/// Where an attachment came from and where it ended up.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct IndexEntry {
+    pub account: String,
+    pub mailbox: String,
+    pub uid: u32,
+    pub message_id: Option<String>,
+    #[serde(default)]
+    pub category: Category,
+    pub path: PathBuf,
+}
+
+/// Content-addressed record of every saved attachment, keyed by SHA-256.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct DedupeIndex {
+    #[serde(skip)]
+    path: PathBuf,
+    attachments: BTreeMap<String, Vec<IndexEntry>>,
+}
+
+impl DedupeIndex {
+    pub fn load(path: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut index = match fs::read_to_string(path) {
+            Ok(content) => serde_json::from_str::<DedupeIndex>(&content)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => DedupeIndex::default(),
+            Err(e) => return Err(e.into()),
+        };
+        index.path = path.to_path_buf();
+        Ok(index)
+    }
+
+    pub fn save(&self) -> Result<(), Box<dyn std::error::Error>> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let tmp_path = self.path.with_extension("json.tmp");
+        fs::write(&tmp_path, serde_json::to_string_pretty(self)?)?;
+        fs::rename(&tmp_path, &self.path)?;
+        Ok(())
+    }
+
+    /// First recorded copy of the content that still exists on disk.
+    pub fn find(&self, sha256: &str) -> Option<&IndexEntry> {
+        self.attachments
+            .get(sha256)?
+            .iter()
+            .find(|entry| entry.path.exists())
+    }
+
+    pub fn insert(&mut self, sha256: &str, entry: IndexEntry) {
+        let entries = self.attachments.entry(sha256.to_string()).or_default();
+        if !entries.contains(&entry) {
+            entries.push(entry);
+        }
+    }
+}
+
+fn collect_files(dir: &Path, files: &mut Vec<PathBuf>) -> std::io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+        if IGNORE_LIST.contains(&file_name.as_ref()) || file_name.starts_with('.') {
+            continue;
+        }
+        if path.is_dir() {
+            collect_files(&path, files)?;
+        } else {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// Groups of files under `root` with identical content, each sorted by path.
+pub fn find_duplicates(root: &Path) -> std::io::Result<Vec<Vec<PathBuf>>> {
+    let mut files = Vec::new();
+    collect_files(root, &mut files)?;
+    let mut by_hash: BTreeMap<String, Vec<PathBuf>> = BTreeMap::new();
+    for file in files {
+        by_hash
+            .entry(sha256_hex(&fs::read(&file)?))
+            .or_default()
+            .push(file);
+    }
+    let mut duplicates: Vec<Vec<PathBuf>> = by_hash
+        .into_values()
+        .filter(|paths| paths.len() > 1)
+        .map(|mut paths| {
+            paths.sort();
+            paths
+        })
+        .collect();
+    duplicates.sort();
+    Ok(duplicates)
+}
+
+#[cfg(unix)]
+fn is_same_file(a: &Path, b: &Path) -> std::io::Result<bool> {
+    use std::os::unix::fs::MetadataExt;
+    let (a, b) = (fs::metadata(a)?, fs::metadata(b)?);
+    Ok(a.dev() == b.dev() && a.ino() == b.ino())
+}
+
+#[cfg(not(unix))]
+fn is_same_file(_a: &Path, _b: &Path) -> std::io::Result<bool> {
+    Ok(false)
+}
+
+/// Replace `duplicate` with a hard link to `original`, keeping the directory layout intact.
+pub fn link_duplicate(original: &Path, duplicate: &Path) -> std::io::Result<bool> {
+    if is_same_file(original, duplicate)? {
+        return Ok(false);
+    }
+    let tmp_path = unique_temp_path(duplicate);
+    fs::hard_link(original, &tmp_path)?;
+    fs::rename(&tmp_path, duplicate)?;
+    Ok(true)
+}
@@ -1,21 +1,37 @@
-use std::fs;
+use std::{fs, io::ErrorKind};
 
-use super::save_location::get_save_location_outcome_invoices;
+use super::save_location::get_save_location_outcome_invoices_for;
+use crate::{datemath::date::DateRange, error::AntworkerError};
 
-const IGNORE_LIST: [&'static str; 3] = [".", "..", ".DS_Store"];
+pub const IGNORE_LIST: [&str; 3] = [".", "..", ".DS_Store"];
 
-pub fn get_saved_files() -> Vec<String> {
-    let save_location = get_save_location_outcome_invoices();
-    let paths = fs::read_dir(save_location).unwrap();
+/// Outcome invoices saved for every month the range touches. Months without a directory have
+/// nothing saved.
+pub fn get_saved_files(range: &DateRange) -> Result<Vec<String>, AntworkerError> {
     let mut files = Vec::new();
-
-    for path in paths {
-        let path_buf = path.unwrap().path();
-        let file_name = path_buf.file_name().unwrap().to_string_lossy().to_string();
-        if !IGNORE_LIST.contains(&file_name.as_str()) {
-            let path_str = path_buf.to_str().unwrap().to_string();
-            files.push(path_str);
+    for period in range.months() {
+        let save_location = get_save_location_outcome_invoices_for(period)?;
+        let paths = match fs::read_dir(&save_location) {
+            Ok(paths) => paths,
+            Err(e) if e.kind() == ErrorKind::NotFound => continue,
+            Err(e) => return Err(AntworkerError::io(&save_location, e)),
+        };
+        let mut month_files = Vec::new();
+        for path in paths {
+            let path_buf = path
+                .map_err(|e| AntworkerError::io(&save_location, e))?
+                .path();
+            let file_name = path_buf
+                .file_name()
+                .unwrap_or_default()
+                .to_string_lossy()
+                .to_string();
+            if !IGNORE_LIST.contains(&file_name.as_str()) {
+                month_files.push(path_buf.to_string_lossy().to_string());
+            }
         }
+        month_files.sort();
+        files.extend(month_files);
     }
-    files
+    Ok(files)
 }
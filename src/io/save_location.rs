@@ -1,77 +1,47 @@
-use lazy_static::lazy_static;
-use std::{env::var, fs};
-
-use crate::datemath::date::{
-    get_current_year_month_str, get_current_year_str, get_previous_month_year_str,
+use crate::{
+    config::{accounts::ConfigError, env::required_var},
+    datemath::{
+        clock::Clock,
+        date::{get_current_year_month_str, get_current_year_str},
+        period::Period,
+    },
 };
 
-lazy_static! {
-    pub static ref ROOT_SAVE_LOCATION_OUTCOME_INVOICES: String =
-        var("ROOT_SAVE_LOCATION_OUTCOME_INVOICES")
-            .expect("ROOT_SAVE_LOCATION_OUTCOME_INVOICES must be set.");
-}
-lazy_static! {
-    pub static ref ROOT_SAVE_LOCATION_INCOME_INVOICES: String =
-        var("ROOT_SAVE_LOCATION_INCOME_INVOICES")
-            .expect("ROOT_SAVE_LOCATION_INCOME_INVOICES must be set.");
+pub fn root_save_location_outcome_invoices() -> Result<String, ConfigError> {
+    required_var("ROOT_SAVE_LOCATION_OUTCOME_INVOICES")
 }
-lazy_static! {
-    pub static ref ROOT_MONTHLY_SUMMARY_BALANCE: String =
-        var("ROOT_MONTHLY_SUMMARY_BALANCE").expect("ROOT_MONTHLY_SUMMARY_BALANCE must be set.");
-}
-lazy_static! {
-    pub static ref MONTHLY_BALANCE_SUBJECT: String =
-        var("MONTHLY_BALANCE_SUBJECT").expect("MONTHLY_BALANCE_SUBJECT must be set.");
+
+pub fn root_save_location_income_invoices() -> Result<String, ConfigError> {
+    required_var("ROOT_SAVE_LOCATION_INCOME_INVOICES")
 }
 
-pub fn get_save_location_outcome_invoices() -> String {
-    let current_year = get_current_year_str();
-    let current_year_month = get_current_year_month_str();
+pub fn get_save_location_outcome_invoices(clock: &dyn Clock) -> Result<String, ConfigError> {
+    let current_year = get_current_year_str(clock);
+    let current_year_month = get_current_year_month_str(clock);
     let save_location = format!(
         "{}/{}/{}",
-        ROOT_SAVE_LOCATION_OUTCOME_INVOICES.as_str(),
+        root_save_location_outcome_invoices()?,
         current_year.as_str(),
         current_year_month.as_str(),
     );
-    save_location
+    Ok(save_location)
 }
 
-pub fn get_save_location_income_invoices() -> String {
-    let current_year = get_current_year_str();
-    let save_location = format!(
+/// Outcome invoices directory of any month, e.g. `{root}/2024/2024_01`.
+pub fn get_save_location_outcome_invoices_for(period: Period) -> Result<String, ConfigError> {
+    Ok(format!(
         "{}/{}",
-        ROOT_SAVE_LOCATION_INCOME_INVOICES.as_str(),
-        current_year.as_str(),
-    );
-    save_location
+        root_save_location_outcome_invoices()?,
+        period.format("%Y/%Y_%m"),
+    ))
 }
 
-pub fn get_save_location_monthly_balance() -> String {
-    let (previous_month, previous_year) = get_previous_month_year_str();
+pub fn get_save_location_income_invoices(clock: &dyn Clock) -> Result<String, ConfigError> {
+    let current_year = get_current_year_str(clock);
     let save_location = format!(
-        "{}/{}/{}",
-        ROOT_MONTHLY_SUMMARY_BALANCE.as_str(),
-        previous_year.as_str(),
-        previous_month.as_str(),
+        "{}/{}",
+        root_save_location_income_invoices()?,
+        current_year.as_str(),
     );
-    save_location
-}
-
-pub fn maybe_create_save_location(save_location: &String) -> Result<(), std::io::Error> {
-    if !fs::metadata(&save_location).is_ok() {
-        fs::create_dir_all(&save_location)?;
-    }
-    Ok(())
-}
-
-pub fn setup_save_location(subject: &String) -> Result<String, std::io::Error> {
-    if subject.contains(MONTHLY_BALANCE_SUBJECT.as_str()) {
-        let save_location = get_save_location_monthly_balance();
-        maybe_create_save_location(&save_location).unwrap();
-        return Ok(save_location);
-    } else {
-        let save_location = get_save_location_outcome_invoices();
-        maybe_create_save_location(&save_location).unwrap();
-        return Ok(save_location);
-    }
+    Ok(save_location)
 }
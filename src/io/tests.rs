@@ -1,79 +1,75 @@
 use std::fs;
 
+use chrono::{NaiveDate, TimeZone};
 use lazy_static::lazy_static;
 
 use crate::{
-    datemath::date::{get_current_month_str, get_current_year_str, get_previous_month_year_str},
+    datemath::{
+        clock::FixedClock,
+        date::{get_current_month_range, DateRange},
+    },
+    error::{AntworkerError, Failure},
     io::{
+        dedupe::{find_duplicates, link_duplicate, DedupeIndex, IndexEntry},
         files::get_saved_files,
-        save_location::{get_save_location_income_invoices, get_save_location_monthly_balance, get_save_location_outcome_invoices},
+        save_location::{get_save_location_income_invoices, get_save_location_outcome_invoices},
+        writer::{
+            link_without_collision, save_without_collision, write_atomically, CollisionPolicy,
+            SaveOutcome, SaveReport,
+        },
     },
+    rules::action::Category,
 };
 
 lazy_static! {
     static ref TEMP_DIR: tempfile::TempDir = tempfile::tempdir().unwrap();
 }
 
+/// 00:30 on New Year's Day in Warsaw, still the old year in UTC.
+fn new_year_clock() -> FixedClock {
+    FixedClock(
+        chrono_tz::Europe::Warsaw
+            .with_ymd_and_hms(2024, 1, 1, 0, 30, 0)
+            .unwrap(),
+    )
+}
+
 #[test]
 fn test_get_saved_files() {
     let temp_dir_path = TEMP_DIR.path().to_str().unwrap().to_string();
-    // std::env::set_var(
-    //     "ROOT_SAVE_LOCATION_PATH",
-    //     Path::new("temp_dir_attachments")
-    //         .to_str()
-    //         .unwrap()
-    //         .to_string(),
-    // );
-    let current_year = get_current_year_str();
-    let current_month = get_current_month_str();
-    let save_location = format!(
-        "{}/{}/{}_{}",
-        temp_dir_path.to_string().as_str(),
-        current_year.as_str(),
-        current_year.as_str(),
-        current_month.as_str()
-    );
+    let save_location = format!("{}/2024/2024_01", temp_dir_path);
 
     // Set the ROOT_SAVE_LOCATION_OUTCOME_INVOICES  environment variable for testing
-    std::env::set_var("ROOT_SAVE_LOCATION_OUTCOME_INVOICES", temp_dir_path.to_string());
+    std::env::set_var("ROOT_SAVE_LOCATION_OUTCOME_INVOICES", &temp_dir_path);
 
     // Create some files in the temporary directory
-    fs::create_dir_all(save_location.to_string()).unwrap();
+    fs::create_dir_all(&save_location).unwrap();
     fs::write(format!("{}/file1.txt", save_location), "content1").unwrap();
     fs::write(format!("{}/file2.txt", save_location), "content2").unwrap();
 
     // Test the function
-    let saved_files = get_saved_files();
+    let saved_files = get_saved_files(&get_current_month_range(&new_year_clock())).unwrap();
 
     // Assert that the files are present in the result
     assert!(saved_files.contains(&format!("{}/file1.txt", save_location)));
     assert!(saved_files.contains(&format!("{}/file2.txt", save_location)));
+    // NOTE: A range over several months reads every month directory, missing ones included
+    let quarter = DateRange::new(
+        NaiveDate::from_ymd_opt(2023, 12, 1).unwrap(),
+        NaiveDate::from_ymd_opt(2024, 2, 29).unwrap(),
+    )
+    .unwrap();
+    assert_eq!(get_saved_files(&quarter).unwrap().len(), 2);
 }
 
 #[test]
 fn test_get_save_location_outcome_invoices() {
     std::env::set_var(
         "ROOT_SAVE_LOCATION_OUTCOME_INVOICES",
-        TEMP_DIR.path().to_str().unwrap().to_string(),
-    );
-    // std::env::set_var(
-    //     "ROOT_SAVE_LOCATION_PATH",
-    //     Path::new("temp_dir_attachments")
-    //         .to_str()
-    //         .unwrap()
-    //         .to_string(),
-    // );
-    //
-    let current_year = get_current_year_str();
-    let current_month = get_current_month_str();
-    let expected_save_location = format!(
-        "{}/{}/{}_{}",
         TEMP_DIR.path().to_str().unwrap(),
-        current_year.as_str(),
-        current_year.as_str(),
-        current_month.as_str()
     );
-    let save_location = get_save_location_outcome_invoices();
+    let expected_save_location = format!("{}/2024/2024_01", TEMP_DIR.path().to_str().unwrap());
+    let save_location = get_save_location_outcome_invoices(&new_year_clock()).unwrap();
     assert_eq!(save_location, expected_save_location);
 }
 
@@ -81,28 +77,260 @@ fn test_get_save_location_outcome_invoices() {
 fn test_get_save_location_income_invoices() {
     std::env::set_var(
         "ROOT_SAVE_LOCATION_INCOME_INVOICES",
-        TEMP_DIR.path().to_str().unwrap().to_string(),
-    );
-    let current_year = get_current_year_str();
-    let expected_save_location = format!(
-        "{}/{}",
         TEMP_DIR.path().to_str().unwrap(),
-        current_year.as_str(),
     );
-    let save_location = get_save_location_income_invoices();
+    let expected_save_location = format!("{}/2024", TEMP_DIR.path().to_str().unwrap());
+    let save_location = get_save_location_income_invoices(&new_year_clock()).unwrap();
     assert_eq!(save_location, expected_save_location);
 }
 
 #[test]
-fn test_get_save_location_monthly_balance() {
-    std::env::set_var("ROOT_MONTHLY_SUMMARY_BALANCE", "/root/monthly/balance");
-    let save_location = get_save_location_monthly_balance();
-    let (previous_month, previous_year) = get_previous_month_year_str();
-    let expected_save_location = format!(
-        "{}/{}/{}",
-        "/root/monthly/balance".to_string(),
-        previous_year.as_str(),
-        previous_month.as_str()
+fn test_save_without_collision_new_and_duplicate() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let outcome = save_without_collision(
+        temp_dir.path(),
+        "faktura.pdf",
+        b"invoice",
+        CollisionPolicy::Suffix,
+        "",
+    )
+    .unwrap();
+    assert_eq!(
+        outcome,
+        SaveOutcome::New(temp_dir.path().join("faktura.pdf"))
     );
-    assert_eq!(save_location, expected_save_location);
+
+    let outcome = save_without_collision(
+        temp_dir.path(),
+        "faktura.pdf",
+        b"invoice",
+        CollisionPolicy::Suffix,
+        "",
+    )
+    .unwrap();
+    assert_eq!(
+        outcome,
+        SaveOutcome::Duplicate(temp_dir.path().join("faktura.pdf"))
+    );
+}
+
+#[test]
+fn test_save_without_collision_suffix() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    fs::write(temp_dir.path().join("faktura.pdf"), "vendor a").unwrap();
+    fs::write(temp_dir.path().join("faktura-1.pdf"), "vendor b").unwrap();
+
+    let outcome = save_without_collision(
+        temp_dir.path(),
+        "faktura.pdf",
+        b"vendor c",
+        CollisionPolicy::Suffix,
+        "",
+    )
+    .unwrap();
+    let expected_path = temp_dir.path().join("faktura-2.pdf");
+    assert_eq!(outcome, SaveOutcome::Renamed(expected_path.clone()));
+    assert_eq!(fs::read(expected_path).unwrap(), b"vendor c");
+    assert_eq!(
+        fs::read(temp_dir.path().join("faktura.pdf")).unwrap(),
+        b"vendor a"
+    );
+
+    // Content already saved under a suffixed name is recognised as a duplicate
+    let outcome = save_without_collision(
+        temp_dir.path(),
+        "faktura.pdf",
+        b"vendor b",
+        CollisionPolicy::Suffix,
+        "",
+    )
+    .unwrap();
+    assert_eq!(
+        outcome,
+        SaveOutcome::Duplicate(temp_dir.path().join("faktura-1.pdf"))
+    );
+}
+
+#[test]
+fn test_save_without_collision_prefix() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    fs::write(temp_dir.path().join("faktura.pdf"), "vendor a").unwrap();
+    fs::write(temp_dir.path().join("vendor.com_faktura.pdf"), "older").unwrap();
+
+    let outcome = save_without_collision(
+        temp_dir.path(),
+        "faktura.pdf",
+        b"vendor b",
+        CollisionPolicy::PrefixDate,
+        "2024-01-15",
+    )
+    .unwrap();
+    assert_eq!(
+        outcome,
+        SaveOutcome::Renamed(temp_dir.path().join("2024-01-15_faktura.pdf"))
+    );
+
+    let outcome = save_without_collision(
+        temp_dir.path(),
+        "faktura.pdf",
+        b"vendor c",
+        CollisionPolicy::PrefixSender,
+        "vendor.com",
+    )
+    .unwrap();
+    assert_eq!(
+        outcome,
+        SaveOutcome::Renamed(temp_dir.path().join("vendor.com_faktura-1.pdf"))
+    );
+}
+
+#[test]
+fn test_write_atomically_leaves_no_temp_file() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    write_atomically(&temp_dir.path().join("faktura.pdf"), b"invoice").unwrap();
+    let names: Vec<String> = fs::read_dir(temp_dir.path())
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+        .collect();
+    assert_eq!(names, vec!["faktura.pdf"]);
+}
+
+#[test]
+fn test_save_without_collision_concurrent_same_name() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let contents: Vec<Vec<u8>> = (0..8)
+        .map(|i| format!("invoice {}", i).repeat(10_000).into_bytes())
+        .collect();
+    let barrier = std::sync::Barrier::new(contents.len());
+    let outcomes: Vec<SaveOutcome> = std::thread::scope(|scope| {
+        let handles: Vec<_> = contents
+            .iter()
+            .map(|content| {
+                let (barrier, save_location) = (&barrier, temp_dir.path());
+                scope.spawn(move || {
+                    barrier.wait();
+                    save_without_collision(
+                        save_location,
+                        "faktura.pdf",
+                        content,
+                        CollisionPolicy::Suffix,
+                        "",
+                    )
+                    .unwrap()
+                })
+            })
+            .collect();
+        handles.into_iter().map(|h| h.join().unwrap()).collect()
+    });
+    // NOTE: Every attachment got its own file, none of them was overwritten by another
+    for (content, outcome) in contents.iter().zip(outcomes.iter()) {
+        assert!(matches!(
+            outcome,
+            SaveOutcome::New(_) | SaveOutcome::Renamed(_)
+        ));
+        assert_eq!(&fs::read(outcome.path()).unwrap(), content);
+    }
+    assert_eq!(
+        outcomes
+            .iter()
+            .filter(|outcome| matches!(outcome, SaveOutcome::New(_)))
+            .count(),
+        1
+    );
+    assert_eq!(
+        fs::read_dir(temp_dir.path()).unwrap().count(),
+        contents.len()
+    );
+}
+
+#[test]
+fn test_save_report() {
+    let report = SaveReport {
+        outcomes: vec![
+            SaveOutcome::New("/a.pdf".into()),
+            SaveOutcome::Duplicate("/b.pdf".into()),
+            SaveOutcome::Renamed("/c-1.pdf".into()),
+            SaveOutcome::New("/d.pdf".into()),
+        ],
+        failures: vec![Failure {
+            item: "work/INBOX UID 7".to_string(),
+            error: AntworkerError::Mime("unterminated header".to_string()),
+        }],
+    };
+    let summary = report.to_string();
+    assert!(summary.contains("failed     work/INBOX UID 7: Malformed message: unterminated header"));
+    assert!(summary.starts_with("🗂️  Attachments: 2 new, 1 duplicate, 1 renamed, 0 linked"));
+    assert!(summary.contains("renamed    /c-1.pdf"));
+}
+
+#[test]
+fn test_link_without_collision() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let original = temp_dir.path().join("original.pdf");
+    fs::write(&original, b"invoice").unwrap();
+    let outcome = link_without_collision(
+        &original,
+        temp_dir.path(),
+        "faktura.pdf",
+        b"invoice",
+        CollisionPolicy::Suffix,
+        "",
+    )
+    .unwrap();
+    assert_eq!(
+        outcome,
+        SaveOutcome::Linked(temp_dir.path().join("faktura.pdf"))
+    );
+    assert_eq!(
+        fs::read(temp_dir.path().join("faktura.pdf")).unwrap(),
+        b"invoice"
+    );
+}
+
+#[test]
+fn test_dedupe_index_roundtrip() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let saved = temp_dir.path().join("faktura.pdf");
+    fs::write(&saved, b"invoice").unwrap();
+    let index_path = temp_dir.path().join("index.json");
+    let mut index = DedupeIndex::load(&index_path).unwrap();
+    assert!(index.find("abc").is_none());
+    let entry = IndexEntry {
+        account: "me@example.com".to_string(),
+        mailbox: "INBOX".to_string(),
+        uid: 7,
+        message_id: Some("<1@example.com>".to_string()),
+        category: Category::OutcomeInvoice,
+        path: saved.clone(),
+    };
+    index.insert("abc", entry.clone());
+    index.insert("abc", entry.clone());
+    index.save().unwrap();
+
+    let index = DedupeIndex::load(&index_path).unwrap();
+    assert_eq!(index.find("abc"), Some(&entry));
+    fs::remove_file(&saved).unwrap();
+    assert!(index.find("abc").is_none());
+}
+
+#[test]
+fn test_find_and_link_duplicates() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let january = temp_dir.path().join("2024/2024_01");
+    let february = temp_dir.path().join("2024/2024_02");
+    fs::create_dir_all(&january).unwrap();
+    fs::create_dir_all(&february).unwrap();
+    fs::write(january.join("a.pdf"), b"invoice").unwrap();
+    fs::write(february.join("b.pdf"), b"invoice").unwrap();
+    fs::write(february.join("c.pdf"), b"other").unwrap();
+
+    let duplicates = find_duplicates(temp_dir.path()).unwrap();
+    assert_eq!(
+        duplicates,
+        vec![vec![january.join("a.pdf"), february.join("b.pdf")]]
+    );
+    assert!(link_duplicate(&january.join("a.pdf"), &february.join("b.pdf")).unwrap());
+    // NOTE: Already linked files are left alone
+    assert!(!link_duplicate(&january.join("a.pdf"), &february.join("b.pdf")).unwrap());
+    assert_eq!(fs::read(february.join("b.pdf")).unwrap(), b"invoice");
 }
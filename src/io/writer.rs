@@ -0,0 +1,236 @@
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::{
+    fmt::{self, Display, Formatter},
+    fs::{self, OpenOptions},
+    io::{ErrorKind, Write},
+    path::{Path, PathBuf},
+    process,
+};
+
+use crate::error::Failure;
+
+/// How to name an attachment whose filename is already taken by different content.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CollisionPolicy {
+    /// `faktura.pdf` -> `faktura-1.pdf`, `faktura-2.pdf`, ...
+    #[default]
+    Suffix,
+    /// `faktura.pdf` -> `2024-01-15_faktura.pdf`
+    PrefixDate,
+    /// `faktura.pdf` -> `vendor.com_faktura.pdf`
+    PrefixSender,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SaveOutcome {
+    New(PathBuf),
+    /// The same content was already saved at this path, nothing was written.
+    Duplicate(PathBuf),
+    /// The filename was taken by different content, saved under this path instead.
+    Renamed(PathBuf),
+    /// The content was saved elsewhere before and got hard-linked to this path.
+    Linked(PathBuf),
+}
+
+impl SaveOutcome {
+    pub fn path(&self) -> &Path {
+        match self {
+            SaveOutcome::New(path)
+            | SaveOutcome::Duplicate(path)
+            | SaveOutcome::Renamed(path)
+            | SaveOutcome::Linked(path) => path,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct SaveReport {
+    pub outcomes: Vec<SaveOutcome>,
+    /// Messages or attachments that could not be saved, the run went on without them.
+    pub failures: Vec<Failure>,
+}
+
+impl SaveReport {
+    pub fn extend(&mut self, other: SaveReport) {
+        self.outcomes.extend(other.outcomes);
+        self.failures.extend(other.failures);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.outcomes.is_empty()
+    }
+
+    fn count(&self, matches: fn(&SaveOutcome) -> bool) -> usize {
+        self.outcomes
+            .iter()
+            .filter(|outcome| matches(outcome))
+            .count()
+    }
+}
+
+impl Display for SaveReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "🗂️  Attachments: {} new, {} duplicate, {} renamed, {} linked",
+            self.count(|outcome| matches!(outcome, SaveOutcome::New(_))),
+            self.count(|outcome| matches!(outcome, SaveOutcome::Duplicate(_))),
+            self.count(|outcome| matches!(outcome, SaveOutcome::Renamed(_))),
+            self.count(|outcome| matches!(outcome, SaveOutcome::Linked(_))),
+        )?;
+        for outcome in self.outcomes.iter() {
+            let label = match outcome {
+                SaveOutcome::New(_) => "new",
+                SaveOutcome::Duplicate(_) => "duplicate",
+                SaveOutcome::Renamed(_) => "renamed",
+                SaveOutcome::Linked(_) => "linked",
+            };
+            write!(f, "\n  {:<10} {}", label, outcome.path().display())?;
+        }
+        for failure in self.failures.iter() {
+            write!(f, "\n  {:<10} {}", "failed", failure)?;
+        }
+        Ok(())
+    }
+}
+
+pub fn sha256_hex(content: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(content))
+}
+
+fn file_sha256_hex(path: &Path) -> std::io::Result<String> {
+    Ok(sha256_hex(&fs::read(path)?))
+}
+
+/// A temporary file name next to `path` that no other writer, in this process or another one,
+/// picks at the same time.
+pub fn unique_temp_path(path: &Path) -> PathBuf {
+    let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+    path.with_file_name(format!(
+        ".{}.{}.{:08x}.antworker-tmp",
+        file_name,
+        process::id(),
+        rand::random::<u32>()
+    ))
+}
+
+fn write_temp_file(path: &Path, content: &[u8]) -> std::io::Result<PathBuf> {
+    let tmp_path = unique_temp_path(path);
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&tmp_path)?;
+    file.write_all(content)
+        .and_then(|_| file.sync_all())
+        .inspect_err(|_| {
+            let _ = fs::remove_file(&tmp_path);
+        })?;
+    Ok(tmp_path)
+}
+
+/// Write through a temporary file in the same directory and rename it into place, so readers
+/// never see a half-written attachment.
+pub fn write_atomically(path: &Path, content: &[u8]) -> std::io::Result<()> {
+    let tmp_path = write_temp_file(path, content)?;
+    fs::rename(&tmp_path, path).inspect_err(|_| {
+        let _ = fs::remove_file(&tmp_path);
+    })
+}
+
+/// Like `write_atomically`, but fails with `AlreadyExists` instead of replacing a file that is
+/// already at `path`.
+fn write_new_atomically(path: &Path, content: &[u8]) -> std::io::Result<()> {
+    let tmp_path = write_temp_file(path, content)?;
+    let linked = fs::hard_link(&tmp_path, path);
+    fs::remove_file(&tmp_path)?;
+    linked
+}
+
+fn with_suffix(filename: &str, index: usize) -> String {
+    match filename.rsplit_once('.') {
+        Some((stem, extension)) if !stem.is_empty() => {
+            format!("{}-{}.{}", stem, index, extension)
+        }
+        _ => format!("{}-{}", filename, index),
+    }
+}
+
+/// Save `content` as `filename` inside `save_location` without ever overwriting different
+/// content. `prefix` is what `PrefixDate`/`PrefixSender` put in front of a taken filename.
+pub fn save_without_collision(
+    save_location: &Path,
+    filename: &str,
+    content: &[u8],
+    policy: CollisionPolicy,
+    prefix: &str,
+) -> std::io::Result<SaveOutcome> {
+    place_without_collision(save_location, filename, content, policy, prefix, |path| {
+        write_new_atomically(path, content)
+    })
+}
+
+/// Like `save_without_collision`, but hard-links the already saved `original` instead of writing
+/// `content` again.
+pub fn link_without_collision(
+    original: &Path,
+    save_location: &Path,
+    filename: &str,
+    content: &[u8],
+    policy: CollisionPolicy,
+    prefix: &str,
+) -> std::io::Result<SaveOutcome> {
+    let outcome =
+        place_without_collision(save_location, filename, content, policy, prefix, |path| {
+            fs::hard_link(original, path)
+        })?;
+    Ok(match outcome {
+        SaveOutcome::New(path) | SaveOutcome::Renamed(path) => SaveOutcome::Linked(path),
+        outcome => outcome,
+    })
+}
+
+/// Try `filename` and then the names `policy` falls back to, until `place` succeeds or one of
+/// them already holds `content`. `place` must fail with `AlreadyExists` rather than replace a
+/// file, so a concurrent save of the same name moves on to the next one.
+fn place_without_collision(
+    save_location: &Path,
+    filename: &str,
+    content: &[u8],
+    policy: CollisionPolicy,
+    prefix: &str,
+    place: impl Fn(&Path) -> std::io::Result<()>,
+) -> std::io::Result<SaveOutcome> {
+    let content_hash = sha256_hex(content);
+    let base_name = match policy {
+        CollisionPolicy::Suffix => filename.to_string(),
+        CollisionPolicy::PrefixDate | CollisionPolicy::PrefixSender => {
+            format!("{}_{}", prefix, filename)
+        }
+    };
+    let candidates = std::iter::once(save_location.join(filename)).chain(
+        // NOTE: A prefixed name can be taken as well, keep suffixing it until a free one turns up
+        (match policy {
+            CollisionPolicy::Suffix => 1,
+            _ => 0,
+        }..)
+            .map(|index| match index {
+                0 => save_location.join(&base_name),
+                _ => save_location.join(with_suffix(&base_name, index)),
+            }),
+    );
+    for (attempt, path) in candidates.enumerate() {
+        match place(&path) {
+            Ok(()) if attempt == 0 => return Ok(SaveOutcome::New(path)),
+            Ok(()) => return Ok(SaveOutcome::Renamed(path)),
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => {
+                if file_sha256_hex(&path)? == content_hash {
+                    return Ok(SaveOutcome::Duplicate(path));
+                }
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    unreachable!("the suffixed names never run out")
+}
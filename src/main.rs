@@ -1,57 +1,38 @@
-use clap::{Parser, Subcommand};
-use command::open::open_save_location_invoices;
+use chrono::NaiveDate;
+use clap::{Args, Parser, Subcommand};
+use command::{
+    dedupe::dedupe_invoices, login::login_account, open::open_save_location_invoices,
+    rules_test::test_rules,
+};
+use datemath::{
+    clock::{Clock, SystemClock},
+    date::{resolve_backfill_range, resolve_date_range, DateRange},
+};
 use dotenv::dotenv;
-use email_parser::main::process_emails;
+use email_parser::{
+    main::{import_emails, process_emails},
+    offline::IMPORT_ACCOUNT,
+    watch::watch_emails,
+};
 use email_sender::sender::send_emails;
 use enums::OpenCommand;
-use lazy_static::lazy_static;
-
-use std::env::var;
+use std::path::PathBuf;
 extern crate imap;
 extern crate native_tls;
 
+pub mod auth;
 pub mod command;
+pub mod config;
 pub mod datemath;
 pub mod email_parser;
 pub mod email_sender;
 pub mod enums;
+pub mod error;
 pub mod factories;
 pub mod io;
+pub mod retry;
 pub mod rules;
-
-lazy_static! {
-    pub static ref COMPANY_EMAIL_SERVER: String =
-        var("COMPANY_EMAIL_SERVER").expect("COMPANY_EMAIL_SERVER must be set.");
-}
-lazy_static! {
-    pub static ref COMPANY_EMAIL_PORT: u16 = var("COMPANY_EMAIL_PORT")
-        .expect("COMPANY_EMAIL_PORT must be set.")
-        .parse()
-        .expect("COMPANY_EMAIL_PORT must be a valid u16.");
-}
-lazy_static! {
-    pub static ref COMPANY_EMAIL: String =
-        var("COMPANY_EMAIL").expect("COMPANY_EMAIL must be set.");
-}
-lazy_static! {
-    pub static ref COMPANY_EMAIL_PASSWORD: String =
-        var("COMPANY_EMAIL_PASSWORD").expect("COMPANY_EMAIL_PASSWORD must be set.");
-}
-lazy_static! {
-    pub static ref PRIVATE_EMAIL: String =
-        var("PRIVATE_EMAIL").expect("COMPANY_PRIVATE must be set.");
-}
-lazy_static! {
-    pub static ref PRIVATE_EMAIL_PASSWORD: String =
-        var("PRIVATE_EMAIL_PASSWORD").expect("PRIVATE_EMAIL_PASSWORD must be set.");
-}
-lazy_static! {
-    pub static ref S_EMAIL: String = var("S_EMAIL").expect("COMPANY_PRIVATE must be set.");
-}
-lazy_static! {
-    pub static ref S_EMAIL_PASSWORD: String =
-        var("S_EMAIL_PASSWORD").expect("PRIVATE_EMAIL_PASSWORD must be set.");
-}
+pub mod state;
 
 #[derive(Debug, Parser)]
 #[command(name="antworker",version="0.1.0", about = "🐜 Your daily assistant that manages common tasks", author="Maciej Gierada, @mgierada, maciek.gierada@gmail.com", long_about = None, help_template("\
@@ -66,16 +47,93 @@ struct Cli {
     command: Commands,
 }
 
+/// Which days `emails` and `send` work on, the current month when none is given.
+#[derive(Debug, Args)]
+struct PeriodArgs {
+    #[arg(
+        long,
+        conflicts_with_all = ["previous", "from", "to"],
+        help = "Month to work on, e.g. 2024-01."
+    )]
+    month: Option<String>,
+    #[arg(
+        long,
+        action,
+        conflicts_with_all = ["from", "to"],
+        help = "Work on the previous month."
+    )]
+    previous: bool,
+    #[arg(
+        long,
+        help = "First day to work on, e.g. 2024-01-01. Defaults to the start of the month of --to."
+    )]
+    from: Option<NaiveDate>,
+    #[arg(
+        long,
+        help = "Last day to work on, e.g. 2024-03-31. Defaults to today."
+    )]
+    to: Option<NaiveDate>,
+}
+
+impl PeriodArgs {
+    fn date_range(&self, today: NaiveDate) -> Result<Option<DateRange>, String> {
+        resolve_date_range(
+            self.month.as_deref(),
+            self.previous,
+            self.from,
+            self.to,
+            today,
+        )
+    }
+}
+
 #[derive(Debug, Subcommand)]
 enum Commands {
     #[command(
-        about = "Fetch all emails and save attachments in designated location for the current month."
+        about = "Fetch all emails and save attachments in designated location for the current month, or the given period."
+    )]
+    Emails {
+        #[arg(
+            long,
+            action,
+            help = "Print every MIME part of matching emails and why it was saved or skipped."
+        )]
+        explain: bool,
+        #[command(flatten)]
+        period: PeriodArgs,
+    },
+    #[command(
+        about = "Save attachments of every email of a past year or date range into the folder of the month it was sent in."
+    )]
+    Backfill {
+        #[arg(
+            long,
+            conflicts_with_all = ["from", "to"],
+            required_unless_present = "from",
+            help = "Year to backfill, e.g. 2023."
+        )]
+        year: Option<i32>,
+        #[arg(long, help = "First day to backfill, e.g. 2023-04-01.")]
+        from: Option<NaiveDate>,
+        #[arg(
+            long,
+            requires = "from",
+            help = "Last day to backfill. Defaults to today."
+        )]
+        to: Option<NaiveDate>,
+    },
+    #[command(
+        about = "Keep watching all inboxes and save attachments of new emails as they arrive."
+    )]
+    Watch,
+    #[command(
+        about = "Send all invoices for the current month, or the given period, to the designated email address."
     )]
-    Emails,
-    #[command(about = "Send all invoices for the current month to the designated email address.")]
     Send {
         #[arg(short, long, action, help = "Dry run, do not send emails.")]
         dry_run: bool,
+        #[command(flatten)]
+        period: PeriodArgs,
     },
     #[command(about = "Open the designated location for the current month.")]
     Open {
@@ -89,22 +147,122 @@ enum Commands {
         #[arg(help = "Specify the type of invoices (income/outcome)")]
         invoice_type: String,
     },
+    #[command(about = "Find attachments saved more than once in the outcome invoices tree.")]
+    Dedupe {
+        #[arg(
+            long,
+            action,
+            help = "Replace every duplicate with a hard link to the first copy."
+        )]
+        collapse: bool,
+    },
+    #[command(
+        about = "Save attachments from an exported .eml directory, mbox file or Maildir, whichever month they are from."
+    )]
+    Import {
+        #[arg(help = "Path to a directory of .eml files, an mbox file or a Maildir.")]
+        path: PathBuf,
+        #[arg(
+            long,
+            default_value = IMPORT_ACCOUNT,
+            help = "Account name the rules see for these emails."
+        )]
+        account: String,
+    },
+    #[command(about = "Sign in to an account that uses OAuth2 and store its tokens.")]
+    Login {
+        #[arg(help = "Account name from the config file.")]
+        account: String,
+    },
+    #[command(about = "Inspect the rules from the rules file.")]
+    Rules {
+        #[command(subcommand)]
+        command: RulesCommand,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+enum RulesCommand {
+    #[command(
+        about = "Show which rule every email would match and where its attachments would go, without saving anything."
+    )]
+    Test {
+        #[arg(
+            long,
+            help = "First day to check, e.g. 2024-01-01. Defaults to the start of the current month."
+        )]
+        from: Option<NaiveDate>,
+        #[arg(long, help = "Last day to check, e.g. 2024-01-31. Defaults to today.")]
+        to: Option<NaiveDate>,
+        #[arg(
+            long,
+            help = "Check a directory of .eml files, an mbox file or a Maildir instead of the accounts."
+        )]
+        eml_dir: Option<PathBuf>,
+        #[arg(
+            long,
+            help = "Only check this account, or the account .eml files are treated as from, import by default."
+        )]
+        account: Option<String>,
+    },
 }
 
 #[tokio::main]
 async fn main() {
     dotenv().ok();
     let args = Cli::parse();
+    // NOTE: Checked before any command runs, a mistyped zone must not file invoices under the
+    // wrong month
+    let clock = match SystemClock::from_env() {
+        Ok(clock) => clock,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            std::process::exit(1);
+        }
+    };
     match args.command {
-        Commands::Emails {} => {
-            process_emails().await.unwrap();
+        Commands::Emails { explain, period } => {
+            let result = match period.date_range(clock.today()) {
+                Ok(timeframe) => process_emails(explain, timeframe, clock)
+                    .await
+                    .map_err(|e| e.to_string()),
+                Err(e) => Err(e),
+            };
+            if let Err(e) = result {
+                eprintln!("Error: {}", e);
+                std::process::exit(1);
+            }
+        }
+        Commands::Backfill { year, from, to } => {
+            let result = match resolve_backfill_range(year, from, to, clock.today()) {
+                Ok(range) => process_emails(false, Some(range), clock)
+                    .await
+                    .map_err(|e| e.to_string()),
+                Err(e) => Err(e),
+            };
+            if let Err(e) = result {
+                eprintln!("Error: {}", e);
+                std::process::exit(1);
+            }
         }
-        Commands::Send { dry_run } => {
+        Commands::Watch => {
+            if let Err(e) = watch_emails(clock).await {
+                eprintln!("Error: {}", e);
+                std::process::exit(1);
+            }
+        }
+        Commands::Send { dry_run, period } => {
             if dry_run {
                 println!("Dry run, not sending emails.");
-                return send_emails(true);
             }
-            send_emails(false)
+            let result = match period.date_range(clock.today()) {
+                Ok(range) => send_emails(dry_run, range, clock).map_err(|e| e.to_string()),
+                Err(e) => Err(e),
+            };
+            if let Err(e) = result {
+                eprintln!("Error: {}", e);
+                std::process::exit(1);
+            }
         }
         Commands::Open {
             year_month_or_year,
@@ -118,14 +276,53 @@ async fn main() {
                     return;
                 }
             };
-            match command {
-                OpenCommand::Income => {
-                    open_save_location_invoices(&year_month_or_year.unwrap_or("".to_string()), true)
-                }
+            let result = match command {
+                OpenCommand::Income => open_save_location_invoices(
+                    &year_month_or_year.unwrap_or("".to_string()),
+                    true,
+                    clock,
+                ),
                 OpenCommand::Outcome => open_save_location_invoices(
                     &year_month_or_year.unwrap_or("".to_string()),
                     false,
+                    clock,
                 ),
+            };
+            if let Err(e) = result {
+                eprintln!("Error: {}", e);
+                std::process::exit(1);
+            }
+        }
+        Commands::Dedupe { collapse } => {
+            if let Err(e) = dedupe_invoices(collapse) {
+                eprintln!("Error: {}", e);
+                std::process::exit(1);
+            }
+        }
+        Commands::Import { path, account } => {
+            if let Err(e) = import_emails(&path, &account, clock) {
+                eprintln!("Error: {}", e);
+                std::process::exit(1);
+            }
+        }
+        Commands::Login { account } => {
+            if let Err(e) = login_account(&account) {
+                eprintln!("Error: {}", e);
+                std::process::exit(1);
+            }
+        }
+        Commands::Rules {
+            command:
+                RulesCommand::Test {
+                    from,
+                    to,
+                    eml_dir,
+                    account,
+                },
+        } => {
+            if let Err(e) = test_rules(from, to, eml_dir, account, clock) {
+                eprintln!("Error: {}", e);
+                std::process::exit(1);
             }
         }
     }
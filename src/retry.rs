@@ -0,0 +1,90 @@
+use rand::Rng;
+use serde::Deserialize;
+use std::{fmt::Display, thread, time::Duration};
+
+/// How often and how patiently a failed network operation is tried again.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetryPolicy {
+    /// Attempts including the first one, 1 disables retrying.
+    pub max_attempts: u32,
+    /// Delay before the first retry, doubled for every further one.
+    pub initial_backoff_ms: u64,
+    pub max_backoff_ms: u64,
+    /// Wait a random part of every delay, so accounts that failed together do not retry together.
+    pub jitter: bool,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 4,
+            initial_backoff_ms: 1000,
+            max_backoff_ms: 30_000,
+            jitter: true,
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.max_attempts == 0 {
+            return Err("max_attempts must be at least 1".to_string());
+        }
+        if self.initial_backoff_ms > self.max_backoff_ms {
+            return Err("initial_backoff_ms must not be above max_backoff_ms".to_string());
+        }
+        Ok(())
+    }
+
+    /// Delay before retry number `retry`, counting from 1, without jitter.
+    pub fn backoff(&self, retry: u32) -> Duration {
+        let factor = 2u64.saturating_pow(retry.saturating_sub(1));
+        Duration::from_millis(
+            self.initial_backoff_ms
+                .saturating_mul(factor)
+                .min(self.max_backoff_ms),
+        )
+    }
+
+    fn delay(&self, retry: u32) -> Duration {
+        let backoff = self.backoff(retry);
+        match self.jitter {
+            // NOTE: "Equal jitter", at least half the backoff so retries still slow down
+            true => backoff / 2 + backoff.mul_f64(rand::rng().random_range(0.0..0.5)),
+            false => backoff,
+        }
+    }
+}
+
+/// Run `operation` until it succeeds, fails with an error `is_transient` rejects or runs out of
+/// attempts. Every retry is logged with `what` was attempted.
+pub fn retry<T, E: Display>(
+    policy: &RetryPolicy,
+    what: &str,
+    is_transient: impl Fn(&E) -> bool,
+    mut operation: impl FnMut() -> Result<T, E>,
+) -> Result<T, E> {
+    let mut attempt = 1;
+    loop {
+        match operation() {
+            Err(e) if attempt < policy.max_attempts && is_transient(&e) => {
+                let delay = policy.delay(attempt);
+                eprintln!(
+                    "🔁 {} failed: {}. Retrying in {:.1}s ({}/{})",
+                    what,
+                    e,
+                    delay.as_secs_f64(),
+                    attempt + 1,
+                    policy.max_attempts
+                );
+                thread::sleep(delay);
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests;
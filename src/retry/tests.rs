@@ -0,0 +1,83 @@
+use std::time::Duration;
+
+use crate::retry::{retry, RetryPolicy};
+
+fn policy(max_attempts: u32) -> RetryPolicy {
+    RetryPolicy {
+        max_attempts,
+        initial_backoff_ms: 1,
+        max_backoff_ms: 4,
+        jitter: true,
+    }
+}
+
+#[test]
+fn test_backoff() {
+    let policy = RetryPolicy {
+        jitter: false,
+        ..Default::default()
+    };
+    assert_eq!(policy.backoff(1), Duration::from_secs(1));
+    assert_eq!(policy.backoff(3), Duration::from_secs(4));
+    assert_eq!(policy.backoff(10), Duration::from_secs(30));
+    assert_eq!(policy.backoff(u32::MAX), Duration::from_secs(30));
+}
+
+#[test]
+fn test_retry_transient() {
+    let mut calls = 0;
+    let result: Result<u32, String> = retry(
+        &policy(3),
+        "Testing",
+        |_| true,
+        || {
+            calls += 1;
+            match calls {
+                3 => Ok(calls),
+                _ => Err("dropped".to_string()),
+            }
+        },
+    );
+    assert_eq!(result, Ok(3));
+
+    calls = 0;
+    let result: Result<(), String> = retry(
+        &policy(2),
+        "Testing",
+        |_| true,
+        || {
+            calls += 1;
+            Err("dropped".to_string())
+        },
+    );
+    assert!(result.is_err());
+    assert_eq!(calls, 2);
+}
+
+#[test]
+fn test_retry_permanent() {
+    let mut calls = 0;
+    let result: Result<(), String> = retry(
+        &policy(5),
+        "Testing",
+        |e: &String| e.starts_with('4'),
+        || {
+            calls += 1;
+            Err("550 rejected".to_string())
+        },
+    );
+    assert!(result.is_err());
+    assert_eq!(calls, 1);
+}
+
+#[test]
+fn test_validate() {
+    assert!(RetryPolicy::default().validate().is_ok());
+    assert!(policy(0).validate().is_err());
+    let backwards = RetryPolicy {
+        initial_backoff_ms: 10,
+        max_backoff_ms: 5,
+        ..Default::default()
+    };
+    assert!(backwards.validate().is_err());
+}
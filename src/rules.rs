@@ -1 +1,7 @@
+pub mod action;
 pub mod define;
+pub mod file;
+pub mod matcher;
+pub mod search;
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,236 @@
+use chrono::{DateTime, Datelike, Duration};
+use chrono_tz::Tz;
+use serde::{Deserialize, Serialize};
+use std::{
+    env::var,
+    fmt::{self, Display, Formatter},
+    path::{Component, Path, PathBuf},
+};
+
+use crate::{
+    datemath::clock::local,
+    email_parser::{filename::sanitize_filename, parser::EmailDetails},
+};
+
+/// What kind of document a rule saves, which also picks the default destination.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Category {
+    #[default]
+    OutcomeInvoice,
+    IncomeInvoice,
+    BankStatement,
+    Receipt,
+}
+
+impl Display for Category {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Category::OutcomeInvoice => "outcome_invoice",
+            Category::IncomeInvoice => "income_invoice",
+            Category::BankStatement => "bank_statement",
+            Category::Receipt => "receipt",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl Category {
+    /// Environment variable holding the destination root when a rule does not set one.
+    fn root_env(&self) -> &'static str {
+        match self {
+            Category::IncomeInvoice => "ROOT_SAVE_LOCATION_INCOME_INVOICES",
+            Category::BankStatement => "ROOT_MONTHLY_SUMMARY_BALANCE",
+            Category::OutcomeInvoice | Category::Receipt => "ROOT_SAVE_LOCATION_OUTCOME_INVOICES",
+        }
+    }
+
+    fn default_path(&self) -> &'static str {
+        match self {
+            Category::IncomeInvoice => "{year}",
+            // NOTE: Statements arrive once the month they cover is over
+            Category::BankStatement => "{prev_year}/{prev_month}",
+            Category::OutcomeInvoice | Category::Receipt => "{year}/{year}_{month}",
+        }
+    }
+}
+
+/// Where and under which name a rule saves attachments, as written in the rules file.
+#[derive(Debug, Default, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ActionConfig {
+    #[serde(default)]
+    pub category: Category,
+    /// Destination root, defaults to the root of the category.
+    pub destination: Option<String>,
+    /// Directory below the destination root, e.g. `{year}/{year}_{month}/{vendor}`.
+    pub path: Option<String>,
+    /// Saved filename, e.g. `{date}_{vendor}_{original}`.
+    pub filename: Option<String>,
+}
+
+/// A validated action with every template default filled in.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RuleAction {
+    pub category: Category,
+    pub destination: Option<String>,
+    pub path: String,
+    pub filename: String,
+}
+
+impl Default for RuleAction {
+    fn default() -> Self {
+        ActionConfig::default().compile().unwrap()
+    }
+}
+
+const PATH_PLACEHOLDERS: [&str; 9] = [
+    "year",
+    "month",
+    "day",
+    "prev_year",
+    "prev_month",
+    "date",
+    "vendor",
+    "category",
+    "rule",
+];
+
+/// Replace every `{name}` in `template` by `value(name)`, failing on unknown names.
+fn render(template: &str, value: impl Fn(&str) -> Option<String>) -> Result<String, String> {
+    let mut rendered = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        rendered.push_str(&rest[..start]);
+        let end = rest[start..]
+            .find('}')
+            .ok_or_else(|| format!("unclosed '{{' in '{}'", template))?;
+        let name = &rest[start + 1..start + end];
+        rendered.push_str(
+            &value(name)
+                .ok_or_else(|| format!("unknown placeholder {{{}}} in '{}'", name, template))?,
+        );
+        rest = &rest[start + end + 1..];
+    }
+    rendered.push_str(rest);
+    Ok(rendered)
+}
+
+impl ActionConfig {
+    pub fn compile(&self) -> Result<RuleAction, String> {
+        let path = self
+            .path
+            .clone()
+            .unwrap_or_else(|| self.category.default_path().to_string());
+        let filename = self
+            .filename
+            .clone()
+            .unwrap_or_else(|| "{original}".to_string());
+        // NOTE: Render with dummy values once so mistakes surface at startup
+        let dummy = |name: &str| PATH_PLACEHOLDERS.contains(&name).then(|| "x".to_string());
+        let rendered_path = render(&path, dummy)?;
+        if Path::new(&rendered_path)
+            .components()
+            .any(|component| !matches!(component, Component::Normal(_)))
+        {
+            return Err(format!("path '{}' must be relative and without '..'", path));
+        }
+        render(&filename, |name| match name {
+            "original" => Some("x".to_string()),
+            name => dummy(name),
+        })?;
+        if filename.contains(['/', '\\']) {
+            return Err(format!("filename '{}' must not contain '/'", filename));
+        }
+        Ok(RuleAction {
+            category: self.category,
+            destination: self.destination.clone(),
+            path,
+            filename,
+        })
+    }
+}
+
+/// Values the path and filename templates are filled with for one message.
+#[derive(Debug, Clone)]
+pub struct TemplateValues {
+    /// When the email was sent, in the local time zone.
+    pub date: DateTime<Tz>,
+    pub vendor: String,
+    pub category: Category,
+    pub rule: String,
+}
+
+impl TemplateValues {
+    pub fn new(
+        email: &EmailDetails,
+        rule: &str,
+        category: Category,
+        timezone: Tz,
+        transliterate: bool,
+    ) -> Self {
+        // NOTE: The display name reads best, the sender domain is the fallback
+        let vendor = email
+            .sender_name
+            .as_ref()
+            .and_then(|name| sanitize_filename(name, transliterate))
+            .or_else(|| {
+                email
+                    .from
+                    .first()
+                    .and_then(|sender| sender.rsplit_once('@'))
+                    .and_then(|(_, domain)| sanitize_filename(domain, transliterate))
+            })
+            .unwrap_or_else(|| "unknown".to_string());
+        TemplateValues {
+            date: local(&email.date, timezone),
+            vendor,
+            category,
+            rule: sanitize_filename(rule, transliterate).unwrap_or_default(),
+        }
+    }
+
+    fn get(&self, name: &str) -> Option<String> {
+        let previous_month = self.date.with_day(1).unwrap_or(self.date) - Duration::days(1);
+        Some(match name {
+            "year" => self.date.format("%Y").to_string(),
+            "month" => self.date.format("%m").to_string(),
+            "day" => self.date.format("%d").to_string(),
+            "prev_year" => previous_month.format("%Y").to_string(),
+            "prev_month" => previous_month.format("%m").to_string(),
+            "date" => self.date.format("%Y-%m-%d").to_string(),
+            "vendor" => self.vendor.clone(),
+            "category" => self.category.to_string(),
+            "rule" => self.rule.clone(),
+            _ => return None,
+        })
+    }
+}
+
+impl RuleAction {
+    /// Destination root of the rule, or of its category when the rule does not set one.
+    pub fn destination_root(&self) -> Result<String, String> {
+        match &self.destination {
+            Some(destination) => Ok(destination.clone()),
+            None => var(self.category.root_env()).map_err(|_| {
+                format!(
+                    "no destination set and {} is not set",
+                    self.category.root_env()
+                )
+            }),
+        }
+    }
+
+    pub fn save_location(&self, values: &TemplateValues) -> Result<PathBuf, String> {
+        let path = render(&self.path, |name| values.get(name))?;
+        Ok(Path::new(&self.destination_root()?).join(path))
+    }
+
+    /// Filename for an attachment whose sanitized original name is `original`.
+    pub fn filename(&self, values: &TemplateValues, original: &str) -> Result<String, String> {
+        render(&self.filename, |name| match name {
+            "original" => Some(original.to_string()),
+            name => values.get(name),
+        })
+    }
+}
@@ -1,67 +1,72 @@
-use chrono::Datelike;
-use imap::types::Fetch;
-use lazy_static::lazy_static;
-use std::env::var;
+use chrono::{DateTime, Utc};
+use chrono_tz::Tz;
 
-use crate::{datemath::date::get_current_month_year, email_parser::parser::parse_date};
+use crate::datemath::{clock::local, date::DateRange};
 
-lazy_static! {
-    #[derive(Debug)]
-    pub static ref OBSERVED_SENDERS: Vec<String> = {
-        let observed_senders_str = var("OBSERVED_SENDERS").expect("OBSERVED_SENDERS must be set.");
-        observed_senders_str
-            .split(',')
-            .map(|s| s.trim_matches('"').trim().to_string())
-            .collect()
-    };
-}
+use super::{file::Rule, matcher::MessageFacts};
 
-pub fn define_rules() -> FilterRules {
-    return FilterRules {
-        allowed_senders: OBSERVED_SENDERS.to_vec(),
-        timeframe: get_current_month_year(),
-    };
+/// Rules limited to messages sent within `timeframe` on the calendar of `timezone`, or sent any
+/// time without one.
+pub fn define_rules(rules: &[Rule], timeframe: Option<DateRange>, timezone: Tz) -> FilterRules {
+    // NOTE: Narrow the IMAP search down by sender only if every rule pins the sender down
+    let allowed_senders = rules
+        .iter()
+        .map(|rule| rule.matcher.search_senders())
+        .collect::<Option<Vec<Vec<String>>>>()
+        .map(|senders| senders.concat())
+        .unwrap_or_default();
+    FilterRules {
+        allowed_senders,
+        timeframe,
+        timezone,
+        rules: rules.to_vec(),
+    }
 }
 
 pub struct FilterRules {
     pub allowed_senders: Vec<String>,
-    pub timeframe: Option<(i32, u32)>, // (year, month)
+    pub timeframe: Option<DateRange>,
+    /// Decides which day of the timeframe a message was sent on.
+    pub timezone: Tz,
+    pub rules: Vec<Rule>,
 }
 
 impl FilterRules {
     pub fn is_empty(&self) -> bool {
-        self.allowed_senders.is_empty()
+        self.rules.is_empty()
     }
 
-    pub fn matches(&self, msg: &Fetch) -> bool {
-        // Check whether the sender of the given email message is in the list of allowed senders
-        // specified by the FilterRules struct. If the sender is allowed, the method returns true;
-        // otherwise, it returns false.
-        let envelope = msg.envelope().expect("message did not have an envelope!");
-        // Check if the sender is allowed
-        let sender_allowed = envelope.from.as_ref().map_or(false, |from_addresses| {
-            from_addresses.iter().any(|address| {
-                let sender = format!(
-                    "{}@{}",
-                    String::from_utf8_lossy(address.mailbox.unwrap_or_default()).to_string(),
-                    String::from_utf8_lossy(address.host.unwrap_or_default()).to_string()
-                );
-                self.allowed_senders.contains(&sender)
-            })
-        });
-        // Check if the date is within the specified timeframe
-        let date_allowed = match self.timeframe {
-            Some((year, month)) => {
-                let email_date_raw = envelope
-                    .date
-                    .map(|date_bytes| String::from_utf8_lossy(&date_bytes).to_string());
-                let email_date = parse_date(&email_date_raw.unwrap_or_else(|| String::new()));
-                // let email_date = envelope.date.expect("message did not have a date!");
-                let email_month_year = (email_date.year(), email_date.month());
-                email_month_year == (year, month)
-            }
+    /// Check if the date is within the specified timeframe, on the local calendar
+    pub fn in_timeframe(&self, date: &DateTime<Utc>) -> bool {
+        match self.timeframe {
+            Some(timeframe) => timeframe.contains(local(date, self.timezone).date_naive()),
             None => true, // No timeframe specified, consider all dates
-        };
-        sender_allowed && date_allowed
+        }
+    }
+
+    /// Whether some rule can still match once the facts missing so far are known.
+    pub fn may_match(&self, facts: &MessageFacts) -> bool {
+        self.rules
+            .iter()
+            .any(|rule| rule.matcher.evaluate(facts) != Some(false))
+    }
+
+    /// The first rule, in file order, that matches the message.
+    pub fn matching_rule(&self, facts: &MessageFacts) -> Option<&Rule> {
+        self.rules
+            .iter()
+            .find(|rule| rule.matcher.evaluate(facts) == Some(true))
+    }
+
+    /// Why no rule matches, one `rule: condition` entry per rule.
+    pub fn why_none(&self, facts: &MessageFacts) -> Vec<String> {
+        self.rules
+            .iter()
+            .filter_map(|rule| {
+                rule.matcher
+                    .why_not(facts)
+                    .map(|reason| format!("{}: {}", rule.name, reason))
+            })
+            .collect()
     }
 }
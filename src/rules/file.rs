@@ -0,0 +1,171 @@
+use lazy_static::lazy_static;
+use serde::Deserialize;
+use std::{
+    collections::BTreeSet,
+    env::var,
+    fmt::{self, Display, Formatter},
+    fs,
+    path::Path,
+};
+
+use regex::Regex;
+
+use super::{
+    action::{ActionConfig, Category, RuleAction},
+    matcher::{Matcher, MatcherConfig},
+};
+
+lazy_static! {
+    pub static ref RULES_PATH: String = var("ANTWORKER_RULES").unwrap_or_else(|_| {
+        format!(
+            "{}/.config/antworker/rules.toml",
+            var("HOME").expect("HOME must be set.")
+        )
+    });
+}
+
+#[derive(Debug)]
+pub enum RulesError {
+    Io(String, std::io::Error),
+    Parse(String, toml::de::Error),
+    Invalid(String),
+}
+
+impl Display for RulesError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            RulesError::Io(path, e) => write!(f, "Could not read rules file {}: {}", path, e),
+            RulesError::Parse(path, e) => write!(f, "Invalid rules file {}: {}", path, e),
+            RulesError::Invalid(msg) => write!(f, "Invalid rules: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for RulesError {}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RuleConfig {
+    pub name: String,
+    #[serde(rename = "match")]
+    pub matcher: MatcherConfig,
+    /// Where matching attachments go, defaults to the outcome invoices of the month the email was sent.
+    #[serde(default)]
+    pub action: ActionConfig,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RulesFile {
+    #[serde(default)]
+    rule: Vec<RuleConfig>,
+}
+
+/// A named rule ready to be evaluated. Rules are tried in file order.
+#[derive(Debug, Clone)]
+pub struct Rule {
+    pub name: String,
+    pub matcher: Matcher,
+    pub action: RuleAction,
+}
+
+impl RuleConfig {
+    pub fn compile(&self) -> Result<Rule, RulesError> {
+        let invalid = |e| RulesError::Invalid(format!("rule '{}': {}", self.name, e));
+        Ok(Rule {
+            name: self.name.clone(),
+            matcher: self.matcher.compile().map_err(invalid)?,
+            action: self.action.compile().map_err(invalid)?,
+        })
+    }
+}
+
+pub fn parse_rules(content: &str) -> Result<Vec<Rule>, RulesError> {
+    let file: RulesFile =
+        toml::from_str(content).map_err(|e| RulesError::Parse(String::new(), e))?;
+    if file.rule.is_empty() {
+        return Err(RulesError::Invalid(
+            "at least one [[rule]] section is required".to_string(),
+        ));
+    }
+    let mut names = BTreeSet::new();
+    for rule in file.rule.iter() {
+        if rule.name.trim().is_empty() {
+            return Err(RulesError::Invalid(
+                "rule names must not be empty".to_string(),
+            ));
+        }
+        if !names.insert(rule.name.as_str()) {
+            return Err(RulesError::Invalid(format!(
+                "rule '{}' is defined more than once",
+                rule.name
+            )));
+        }
+    }
+    file.rule.iter().map(|rule| rule.compile()).collect()
+}
+
+/// Rules from `OBSERVED_SENDERS` and `MONTHLY_BALANCE_SUBJECT`, kept for setups without a
+/// rules file.
+fn observed_senders_rules(observed_senders: &str, balance_subject: Option<&str>) -> Vec<Rule> {
+    let senders: Vec<Matcher> = observed_senders
+        .split(',')
+        .map(|s| s.trim_matches('"').trim())
+        .filter(|s| !s.is_empty())
+        .map(|s| Matcher::Sender(s.to_lowercase()))
+        .collect();
+    if senders.is_empty() {
+        return vec![];
+    }
+    let mut rules = Vec::new();
+    if let Some(balance_subject) = balance_subject.filter(|subject| !subject.is_empty()) {
+        rules.push(Rule {
+            name: "monthly balance".to_string(),
+            matcher: Matcher::All(vec![
+                Matcher::Any(senders.clone()),
+                Matcher::Subject(Regex::new(&regex::escape(balance_subject)).unwrap()),
+            ]),
+            action: ActionConfig {
+                category: Category::BankStatement,
+                ..Default::default()
+            }
+            .compile()
+            .unwrap(),
+        });
+    }
+    rules.push(Rule {
+        name: "observed senders".to_string(),
+        matcher: Matcher::Any(senders),
+        action: RuleAction::default(),
+    });
+    rules
+}
+
+/// Load and validate the rules file, falling back to `OBSERVED_SENDERS` when there is none.
+pub fn load_rules(path: &Path) -> Result<Vec<Rule>, RulesError> {
+    let path_str = path.to_string_lossy().to_string();
+    let rules = match fs::read_to_string(path) {
+        Ok(content) => parse_rules(&content).map_err(|e| match e {
+            RulesError::Parse(_, e) => RulesError::Parse(path_str.clone(), e),
+            e => e,
+        })?,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => observed_senders_rules(
+            &var("OBSERVED_SENDERS").unwrap_or_default(),
+            var("MONTHLY_BALANCE_SUBJECT").ok().as_deref(),
+        ),
+        Err(e) => return Err(RulesError::Io(path_str, e)),
+    };
+    if rules.is_empty() {
+        return Err(RulesError::Invalid(format!(
+            "no rules file at {} and OBSERVED_SENDERS is not set",
+            path_str
+        )));
+    }
+    // NOTE: Destination roots may come from the environment, check them before any download
+    for rule in rules.iter() {
+        rule.action
+            .destination_root()
+            .map_err(|e| RulesError::Invalid(format!("rule '{}': {}", rule.name, e)))?;
+    }
+    Ok(rules)
+}
@@ -0,0 +1,215 @@
+use regex::{Regex, RegexBuilder};
+use serde::Deserialize;
+use std::fmt::{self, Display, Formatter};
+
+/// A single condition of a rule as written in the rules file, e.g. `{ subject = "(?i)faktura" }`.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MatcherConfig {
+    /// Exact sender address, case-insensitive.
+    Sender(String),
+    /// Sender domain, `*` matches any run of characters, e.g. `*.vendor.com`.
+    SenderDomain(String),
+    /// Regex over the sender display name.
+    DisplayName(String),
+    /// Regex over the decoded subject.
+    Subject(String),
+    /// Regex over the filenames of the message's attachments.
+    Attachment(String),
+    /// Exact `To`/`Cc` address, case-insensitive.
+    Recipient(String),
+    /// Name of the account from the config file.
+    Account(String),
+    All(Vec<MatcherConfig>),
+    Any(Vec<MatcherConfig>),
+    Not(Box<MatcherConfig>),
+}
+
+/// A validated matcher with every regex compiled.
+#[derive(Debug, Clone)]
+pub enum Matcher {
+    Sender(String),
+    SenderDomain(String, Regex),
+    DisplayName(Regex),
+    Subject(Regex),
+    Attachment(Regex),
+    Recipient(String),
+    Account(String),
+    All(Vec<Matcher>),
+    Any(Vec<Matcher>),
+    Not(Box<Matcher>),
+}
+
+/// Everything a rule can look at. `attachments` stays `None` until the body was fetched.
+#[derive(Debug, Default, Clone)]
+pub struct MessageFacts {
+    pub account: String,
+    pub from: Vec<String>,
+    pub display_name: Option<String>,
+    pub subject: String,
+    pub recipients: Vec<String>,
+    pub attachments: Option<Vec<String>>,
+}
+
+fn compile_regex(kind: &str, pattern: &str) -> Result<Regex, String> {
+    Regex::new(pattern).map_err(|e| format!("invalid {} regex '{}': {}", kind, pattern, e))
+}
+
+fn compile_domain(pattern: &str) -> Result<Regex, String> {
+    let pattern = pattern.trim().trim_start_matches('@');
+    if pattern.is_empty() || pattern.contains('@') {
+        return Err(format!("invalid sender_domain '{}'", pattern));
+    }
+    let escaped: Vec<String> = pattern.split('*').map(regex::escape).collect();
+    RegexBuilder::new(&format!("^{}$", escaped.join(".*")))
+        .case_insensitive(true)
+        .build()
+        .map_err(|e| format!("invalid sender_domain '{}': {}", pattern, e))
+}
+
+impl MatcherConfig {
+    pub fn compile(&self) -> Result<Matcher, String> {
+        let compile_all = |matchers: &[MatcherConfig], kind: &str| {
+            if matchers.is_empty() {
+                return Err(format!("'{}' needs at least one condition", kind));
+            }
+            matchers.iter().map(|matcher| matcher.compile()).collect()
+        };
+        Ok(match self {
+            MatcherConfig::Sender(sender) => Matcher::Sender(sender.to_lowercase()),
+            MatcherConfig::SenderDomain(domain) => {
+                Matcher::SenderDomain(domain.clone(), compile_domain(domain)?)
+            }
+            MatcherConfig::DisplayName(pattern) => {
+                Matcher::DisplayName(compile_regex("display_name", pattern)?)
+            }
+            MatcherConfig::Subject(pattern) => Matcher::Subject(compile_regex("subject", pattern)?),
+            MatcherConfig::Attachment(pattern) => {
+                Matcher::Attachment(compile_regex("attachment", pattern)?)
+            }
+            MatcherConfig::Recipient(recipient) => Matcher::Recipient(recipient.to_lowercase()),
+            MatcherConfig::Account(account) => Matcher::Account(account.clone()),
+            MatcherConfig::All(matchers) => Matcher::All(compile_all(matchers, "all")?),
+            MatcherConfig::Any(matchers) => Matcher::Any(compile_all(matchers, "any")?),
+            MatcherConfig::Not(matcher) => Matcher::Not(Box::new(matcher.compile()?)),
+        })
+    }
+}
+
+impl Matcher {
+    /// Three-valued evaluation: `None` means the answer depends on facts not known yet, i.e. the
+    /// attachments of a message whose body was not fetched.
+    pub fn evaluate(&self, facts: &MessageFacts) -> Option<bool> {
+        match self {
+            Matcher::Sender(sender) => {
+                Some(facts.from.iter().any(|from| from.to_lowercase() == *sender))
+            }
+            Matcher::SenderDomain(_, domain) => Some(facts.from.iter().any(|from| {
+                from.rsplit_once('@')
+                    .is_some_and(|(_, host)| domain.is_match(host))
+            })),
+            Matcher::DisplayName(pattern) => Some(
+                facts
+                    .display_name
+                    .as_ref()
+                    .is_some_and(|name| pattern.is_match(name)),
+            ),
+            Matcher::Subject(pattern) => Some(pattern.is_match(&facts.subject)),
+            Matcher::Attachment(pattern) => facts
+                .attachments
+                .as_ref()
+                .map(|attachments| attachments.iter().any(|name| pattern.is_match(name))),
+            Matcher::Recipient(recipient) => Some(
+                facts
+                    .recipients
+                    .iter()
+                    .any(|to| to.to_lowercase() == *recipient),
+            ),
+            Matcher::Account(account) => Some(facts.account == *account),
+            Matcher::All(matchers) => {
+                let mut result = Some(true);
+                for matcher in matchers {
+                    match matcher.evaluate(facts) {
+                        Some(false) => return Some(false),
+                        None => result = None,
+                        Some(true) => {}
+                    }
+                }
+                result
+            }
+            Matcher::Any(matchers) => {
+                let mut result = Some(false);
+                for matcher in matchers {
+                    match matcher.evaluate(facts) {
+                        Some(true) => return Some(true),
+                        None => result = None,
+                        Some(false) => {}
+                    }
+                }
+                result
+            }
+            Matcher::Not(matcher) => matcher.evaluate(facts).map(|result| !result),
+        }
+    }
+
+    /// Strings every matching message must have in its `From`, usable as IMAP `FROM` keys.
+    /// `None` when the matcher does not pin the sender down.
+    pub fn search_senders(&self) -> Option<Vec<String>> {
+        match self {
+            Matcher::Sender(sender) => Some(vec![sender.clone()]),
+            // NOTE: FROM is a substring search, so the longest literal part of the pattern works
+            Matcher::SenderDomain(domain, _) => domain
+                .split('*')
+                .map(|part| part.trim_matches('.'))
+                .max_by_key(|part| part.len())
+                .filter(|part| !part.is_empty())
+                .map(|part| vec![part.to_string()]),
+            Matcher::All(matchers) => matchers.iter().find_map(|matcher| matcher.search_senders()),
+            Matcher::Any(matchers) => {
+                let mut senders = Vec::new();
+                for matcher in matchers {
+                    senders.extend(matcher.search_senders()?);
+                }
+                Some(senders)
+            }
+            _ => None,
+        }
+    }
+
+    /// The condition that keeps the matcher from matching, written like in the rules file.
+    /// `None` when it matches or cannot be decided yet.
+    pub fn why_not(&self, facts: &MessageFacts) -> Option<String> {
+        if self.evaluate(facts) != Some(false) {
+            return None;
+        }
+        match self {
+            Matcher::All(matchers) => matchers.iter().find_map(|matcher| matcher.why_not(facts)),
+            _ => Some(self.to_string()),
+        }
+    }
+}
+
+fn join(matchers: &[Matcher]) -> String {
+    matchers
+        .iter()
+        .map(|matcher| matcher.to_string())
+        .collect::<Vec<String>>()
+        .join(", ")
+}
+
+impl Display for Matcher {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Matcher::Sender(sender) => write!(f, "sender = {:?}", sender),
+            Matcher::SenderDomain(domain, _) => write!(f, "sender_domain = {:?}", domain),
+            Matcher::DisplayName(pattern) => write!(f, "display_name = {:?}", pattern.as_str()),
+            Matcher::Subject(pattern) => write!(f, "subject = {:?}", pattern.as_str()),
+            Matcher::Attachment(pattern) => write!(f, "attachment = {:?}", pattern.as_str()),
+            Matcher::Recipient(recipient) => write!(f, "recipient = {:?}", recipient),
+            Matcher::Account(account) => write!(f, "account = {:?}", account),
+            Matcher::All(matchers) => write!(f, "all [{}]", join(matchers)),
+            Matcher::Any(matchers) => write!(f, "any [{}]", join(matchers)),
+            Matcher::Not(matcher) => write!(f, "not {}", matcher),
+        }
+    }
+}
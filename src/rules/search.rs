@@ -0,0 +1,76 @@
+use chrono::{Duration, NaiveDate};
+
+use super::define::FilterRules;
+
+fn quote(value: &str) -> String {
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+fn imap_date(date: NaiveDate) -> String {
+    date.format("%-d-%b-%Y").to_string()
+}
+
+/// Nest FROM keys into the prefix `OR a OR b c` form IMAP expects for more than two operands.
+fn senders_query(senders: &[String]) -> Option<String> {
+    match senders {
+        [] => None,
+        [sender] => Some(format!("FROM {}", quote(sender))),
+        [sender, rest @ ..] => Some(format!(
+            "OR FROM {} {}",
+            quote(sender),
+            senders_query(rest)?
+        )),
+    }
+}
+
+/// Messages sent on `since` up to, but not including, `before`.
+pub fn date_range_query(since: NaiveDate, before: NaiveDate) -> String {
+    // NOTE: SENTSINCE/SENTBEFORE compare calendar dates in the sender's timezone while we bucket
+    // months in the local time zone, so widen the window by a day and let the rules trim the edges.
+    format!(
+        "SENTSINCE {} SENTBEFORE {}",
+        imap_date(since - Duration::days(1)),
+        imap_date(before + Duration::days(1))
+    )
+}
+
+impl FilterRules {
+    /// Translate the rules into an IMAP `UID SEARCH` query restricted to `uid_set`. The server
+    /// only narrows down candidates, `may_match` and `matching_rule` still have the final say on
+    /// every fetched message.
+    pub fn search_query(&self, uid_set: &str) -> String {
+        let mut criteria = vec![format!("UID {}", uid_set)];
+        if let Some(senders) = senders_query(&self.allowed_senders) {
+            criteria.push(senders);
+        }
+        if let Some(timeframe) = self.timeframe {
+            criteria.push(date_range_query(
+                timeframe.from,
+                timeframe.to + Duration::days(1),
+            ));
+        }
+        criteria.join(" ")
+    }
+}
+
+/// Collapse UIDs into a compact IMAP sequence set, e.g. `1:3,7,9:10`.
+pub fn compress_uid_set(uids: &[u32]) -> String {
+    let mut uids = uids.to_vec();
+    uids.sort_unstable();
+    uids.dedup();
+    let mut ranges: Vec<(u32, u32)> = Vec::new();
+    for uid in uids {
+        match ranges.last_mut() {
+            Some((_, end)) if *end + 1 == uid => *end = uid,
+            _ => ranges.push((uid, uid)),
+        }
+    }
+    ranges
+        .iter()
+        .map(|(start, end)| match start == end {
+            true => start.to_string(),
+            false => format!("{}:{}", start, end),
+        })
+        .collect::<Vec<String>>()
+        .join(",")
+}
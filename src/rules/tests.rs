@@ -0,0 +1,387 @@
+use chrono::{NaiveDate, TimeZone, Utc};
+use chrono_tz::{Europe::Warsaw, Tz};
+use std::path::PathBuf;
+
+use crate::{
+    datemath::date::DateRange,
+    email_parser::parser::EmailDetails,
+    rules::{
+        action::{Category, TemplateValues},
+        define::{define_rules, FilterRules},
+        file::{parse_rules, RulesError},
+        matcher::MessageFacts,
+        search::{compress_uid_set, date_range_query},
+    },
+};
+
+const RULES: &str = r#"
+[[rule]]
+name = "vendor invoices"
+match = { all = [
+    { sender_domain = "*.vendor.com" },
+    { subject = "(?i)faktura" },
+    { not = { attachment = "(?i)proforma" } },
+] }
+
+[[rule]]
+name = "accountant"
+match = { any = [
+    { sender = "Biuro@Accountant.pl" },
+    { display_name = "^Biuro Rachunkowe" },
+] }
+
+[[rule]]
+name = "shared mailbox"
+match = { all = [{ account = "work" }, { recipient = "invoices@company.pl" }] }
+"#;
+
+fn facts(from: &str, subject: &str) -> MessageFacts {
+    MessageFacts {
+        account: "personal".to_string(),
+        from: vec![from.to_string()],
+        subject: subject.to_string(),
+        ..Default::default()
+    }
+}
+
+#[test]
+fn test_search_query_without_rules() {
+    let rules = FilterRules {
+        allowed_senders: vec![],
+        timeframe: None,
+        timezone: Warsaw,
+        rules: vec![],
+    };
+    assert_eq!(rules.search_query("1:*"), "UID 1:*");
+}
+
+#[test]
+fn test_search_query_single_sender() {
+    let rules = FilterRules {
+        allowed_senders: vec!["billing@vendor.com".to_string()],
+        timeframe: None,
+        timezone: Warsaw,
+        rules: vec![],
+    };
+    assert_eq!(
+        rules.search_query("5:*"),
+        "UID 5:* FROM \"billing@vendor.com\""
+    );
+}
+
+#[test]
+fn test_search_query_multiple_senders() {
+    let rules = FilterRules {
+        allowed_senders: vec![
+            "a@vendor.com".to_string(),
+            "b@vendor.com".to_string(),
+            "c@vendor.com".to_string(),
+        ],
+        timeframe: None,
+        timezone: Warsaw,
+        rules: vec![],
+    };
+    assert_eq!(
+        rules.search_query("1:*"),
+        "UID 1:* OR FROM \"a@vendor.com\" OR FROM \"b@vendor.com\" FROM \"c@vendor.com\""
+    );
+}
+
+#[test]
+fn test_search_query_timeframe() {
+    let rules = FilterRules {
+        allowed_senders: vec![],
+        timeframe: DateRange::month(2023, 12),
+        timezone: Warsaw,
+        rules: vec![],
+    };
+    assert_eq!(
+        rules.search_query("1:*"),
+        "UID 1:* SENTSINCE 30-Nov-2023 SENTBEFORE 2-Jan-2024"
+    );
+}
+
+#[test]
+fn test_in_timeframe() {
+    let rules = define_rules(
+        &[],
+        Some(
+            DateRange::new(
+                NaiveDate::from_ymd_opt(2024, 1, 15).unwrap(),
+                NaiveDate::from_ymd_opt(2024, 2, 10).unwrap(),
+            )
+            .unwrap(),
+        ),
+        Warsaw,
+    );
+    // NOTE: The edges are days in Warsaw, an hour ahead of UTC in winter
+    assert!(rules.in_timeframe(&Utc.with_ymd_and_hms(2024, 1, 14, 23, 30, 0).unwrap()));
+    assert!(rules.in_timeframe(&Utc.with_ymd_and_hms(2024, 2, 10, 22, 59, 0).unwrap()));
+    assert!(!rules.in_timeframe(&Utc.with_ymd_and_hms(2024, 2, 10, 23, 30, 0).unwrap()));
+    let utc_rules = define_rules(&[], rules.timeframe, Tz::UTC);
+    assert!(!utc_rules.in_timeframe(&Utc.with_ymd_and_hms(2024, 1, 14, 23, 30, 0).unwrap()));
+    assert!(utc_rules.in_timeframe(&Utc.with_ymd_and_hms(2024, 2, 10, 23, 30, 0).unwrap()));
+    assert_eq!(
+        rules.search_query("1:*"),
+        "UID 1:* SENTSINCE 14-Jan-2024 SENTBEFORE 12-Feb-2024"
+    );
+}
+
+#[test]
+fn test_search_query_escapes_quotes() {
+    let rules = FilterRules {
+        allowed_senders: vec!["we\"ird@vendor.com".to_string()],
+        timeframe: None,
+        timezone: Warsaw,
+        rules: vec![],
+    };
+    assert_eq!(
+        rules.search_query("1:*"),
+        "UID 1:* FROM \"we\\\"ird@vendor.com\""
+    );
+}
+
+#[test]
+fn test_compress_uid_set() {
+    assert_eq!(compress_uid_set(&[]), "");
+    assert_eq!(compress_uid_set(&[4]), "4");
+    assert_eq!(compress_uid_set(&[9, 1, 2, 3, 7, 10, 2]), "1:3,7,9:10");
+}
+
+#[test]
+fn test_parse_rules() {
+    let rules = parse_rules(RULES).unwrap();
+    let names: Vec<&str> = rules.iter().map(|rule| rule.name.as_str()).collect();
+    assert_eq!(
+        names,
+        vec!["vendor invoices", "accountant", "shared mailbox"]
+    );
+}
+
+#[test]
+fn test_matching_rule() {
+    let rules = define_rules(&parse_rules(RULES).unwrap(), None, Warsaw);
+    let invoice = facts("billing@eu.vendor.com", "Faktura 01/2024");
+    assert!(rules.may_match(&invoice));
+    // NOTE: Attachments decide, so no rule matches for sure until they are known
+    assert!(rules.matching_rule(&invoice).is_none());
+    let invoice = MessageFacts {
+        attachments: Some(vec!["FV_01.pdf".to_string()]),
+        ..invoice
+    };
+    assert_eq!(
+        rules.matching_rule(&invoice).unwrap().name,
+        "vendor invoices"
+    );
+    let proforma = MessageFacts {
+        attachments: Some(vec!["Proforma_01.pdf".to_string()]),
+        ..invoice
+    };
+    assert!(rules.matching_rule(&proforma).is_none());
+
+    let accountant = facts("biuro@accountant.pl", "Rozliczenie");
+    assert_eq!(rules.matching_rule(&accountant).unwrap().name, "accountant");
+    let accountant = MessageFacts {
+        from: vec!["jan@accountant.pl".to_string()],
+        display_name: Some("Biuro Rachunkowe Kowalski".to_string()),
+        ..accountant
+    };
+    assert_eq!(rules.matching_rule(&accountant).unwrap().name, "accountant");
+
+    let shared = MessageFacts {
+        account: "work".to_string(),
+        recipients: vec!["Invoices@Company.pl".to_string()],
+        ..facts("someone@else.com", "Hello")
+    };
+    assert_eq!(rules.matching_rule(&shared).unwrap().name, "shared mailbox");
+    let unrelated = facts("someone@else.com", "Hello");
+    assert!(!rules.may_match(&unrelated));
+}
+
+#[test]
+fn test_sender_domain_is_not_a_suffix_match() {
+    let rules = define_rules(&parse_rules(RULES).unwrap(), None, Warsaw);
+    let lookalike = facts("billing@eu.vendor.com.evil.io", "Faktura");
+    assert!(!rules.may_match(&lookalike));
+}
+
+#[test]
+fn test_define_rules_search_senders() {
+    let rules = define_rules(
+        &parse_rules(
+            r#"
+[[rule]]
+name = "a"
+match = { all = [{ sender_domain = "*.vendor.com" }, { subject = "x" }] }
+
+[[rule]]
+name = "b"
+match = { any = [{ sender = "a@b.pl" }, { sender = "c@d.pl" }] }
+"#,
+        )
+        .unwrap(),
+        None,
+        Warsaw,
+    );
+    assert_eq!(
+        rules.allowed_senders,
+        vec!["vendor.com", "a@b.pl", "c@d.pl"]
+    );
+    // NOTE: A single rule without a sender means the server cannot narrow the search down
+    let rules = define_rules(&parse_rules(RULES).unwrap(), None, Warsaw);
+    assert!(rules.allowed_senders.is_empty());
+}
+
+#[test]
+fn test_invalid_rules() {
+    let error = |content: &str| parse_rules(content).unwrap_err().to_string();
+    assert_eq!(
+        error(""),
+        "Invalid rules: at least one [[rule]] section is required"
+    );
+    assert!(error("[[rule]]\nname = \"a\"\nmatch = { subject = \"(\" }")
+        .starts_with("Invalid rules: rule 'a': invalid subject regex '('"));
+    assert_eq!(
+        error("[[rule]]\nname = \"a\"\nmatch = { any = [] }"),
+        "Invalid rules: rule 'a': 'any' needs at least one condition"
+    );
+    assert_eq!(
+        error(
+            "[[rule]]\nname = \"a\"\nmatch = { sender = \"x@y.pl\" }\n\
+             [[rule]]\nname = \"a\"\nmatch = { sender = \"x@y.pl\" }"
+        ),
+        "Invalid rules: rule 'a' is defined more than once"
+    );
+    assert!(matches!(
+        parse_rules("[[rule]]\nname = \"a\"\nmatch = { color = \"red\" }"),
+        Err(RulesError::Parse(_, _))
+    ));
+}
+
+const ACTION_RULES: &str = r#"
+[[rule]]
+name = "vendor"
+match = { sender_domain = "vendor.com" }
+action = { destination = "/invoices", path = "{year}/{year}_{month}/{vendor}", filename = "{date}_{vendor}_{original}" }
+
+[[rule]]
+name = "bank"
+match = { sender = "statements@bank.pl" }
+action = { category = "bank_statement", destination = "/balance" }
+"#;
+
+fn email() -> EmailDetails {
+    EmailDetails {
+        from: vec!["billing@vendor.com".to_string()],
+        sender_name: Some("Vendor Sp. z o.o.".to_string()),
+        date: Utc.with_ymd_and_hms(2024, 1, 15, 10, 0, 0).unwrap(),
+        ..Default::default()
+    }
+}
+
+#[test]
+fn test_rule_action_templates() {
+    let rules = parse_rules(ACTION_RULES).unwrap();
+    let action = &rules[0].action;
+    assert_eq!(action.category, Category::OutcomeInvoice);
+    let values = TemplateValues::new(&email(), &rules[0].name, action.category, Warsaw, false);
+    assert_eq!(
+        action.save_location(&values).unwrap(),
+        PathBuf::from("/invoices/2024/2024_01/Vendor_Sp._z_o.o")
+    );
+    assert_eq!(
+        action.filename(&values, "FV_1.pdf").unwrap(),
+        "2024-01-15_Vendor_Sp._z_o.o_FV_1.pdf"
+    );
+}
+
+#[test]
+fn test_rule_action_templates_use_local_month() {
+    let rules = parse_rules(ACTION_RULES).unwrap();
+    let action = &rules[0].action;
+    // NOTE: Sent at 00:30 on the 1st in Warsaw, still January in UTC
+    let email = EmailDetails {
+        date: Utc.with_ymd_and_hms(2024, 1, 31, 23, 30, 0).unwrap(),
+        ..email()
+    };
+    let values = TemplateValues::new(&email, &rules[0].name, action.category, Warsaw, false);
+    assert_eq!(
+        action.save_location(&values).unwrap(),
+        PathBuf::from("/invoices/2024/2024_02/Vendor_Sp._z_o.o")
+    );
+}
+
+#[test]
+fn test_bank_statement_goes_to_previous_month() {
+    let rules = parse_rules(ACTION_RULES).unwrap();
+    let action = &rules[1].action;
+    let values = TemplateValues::new(&email(), &rules[1].name, action.category, Warsaw, false);
+    assert_eq!(
+        action.save_location(&values).unwrap(),
+        PathBuf::from("/balance/2023/12")
+    );
+    assert_eq!(
+        action.filename(&values, "wyciag.pdf").unwrap(),
+        "wyciag.pdf"
+    );
+}
+
+#[test]
+fn test_invalid_rule_actions() {
+    let error = |action: &str| {
+        parse_rules(&format!(
+            "[[rule]]\nname = \"a\"\nmatch = {{ sender = \"x@y.pl\" }}\naction = {}",
+            action
+        ))
+        .unwrap_err()
+        .to_string()
+    };
+    assert_eq!(
+        error("{ path = \"{year}/{colour}\" }"),
+        "Invalid rules: rule 'a': unknown placeholder {colour} in '{year}/{colour}'"
+    );
+    assert_eq!(
+        error("{ path = \"../{year}\" }"),
+        "Invalid rules: rule 'a': path '../{year}' must be relative and without '..'"
+    );
+    assert_eq!(
+        error("{ filename = \"{vendor}/{original}\" }"),
+        "Invalid rules: rule 'a': filename '{vendor}/{original}' must not contain '/'"
+    );
+    assert!(error("{ category = \"invoice\" }").starts_with("Invalid rules file"));
+}
+
+#[test]
+fn test_why_none() {
+    let rules = define_rules(&parse_rules(RULES).unwrap(), None, Warsaw);
+    let unrelated = MessageFacts {
+        attachments: Some(vec![]),
+        ..facts("billing@eu.vendor.com", "Newsletter")
+    };
+    assert_eq!(
+        rules.why_none(&unrelated),
+        vec![
+            "vendor invoices: subject = \"(?i)faktura\"",
+            "accountant: any [sender = \"biuro@accountant.pl\", display_name = \"^Biuro Rachunkowe\"]",
+            "shared mailbox: account = \"work\"",
+        ]
+    );
+    let proforma = MessageFacts {
+        attachments: Some(vec!["Proforma.pdf".to_string()]),
+        ..facts("billing@eu.vendor.com", "Faktura")
+    };
+    assert_eq!(
+        rules.why_none(&proforma)[0],
+        "vendor invoices: not attachment = \"(?i)proforma\""
+    );
+}
+
+#[test]
+fn test_date_range_query() {
+    let since = chrono::NaiveDate::from_ymd_opt(2024, 1, 10).unwrap();
+    let before = chrono::NaiveDate::from_ymd_opt(2024, 2, 1).unwrap();
+    assert_eq!(
+        date_range_query(since, before),
+        "SENTSINCE 9-Jan-2024 SENTBEFORE 2-Feb-2024"
+    );
+}
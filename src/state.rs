@@ -0,0 +1,3 @@
+pub mod store;
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,124 @@
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    env::var,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use crate::rules::search::compress_uid_set;
+
+lazy_static! {
+    pub static ref STATE_PATH: String = var("ANTWORKER_STATE_PATH").unwrap_or_else(|_| {
+        format!(
+            "{}/.config/antworker/state.json",
+            var("HOME").expect("HOME must be set.")
+        )
+    });
+}
+
+/// Highest UID processed in a mailbox, valid only as long as the server keeps the same
+/// UIDVALIDITY for it.
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MailboxState {
+    pub uid_validity: u32,
+    pub last_uid: u32,
+    /// Messages up to `last_uid` that failed and are tried again on the next run.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub failed_uids: Vec<u32>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct UidStore {
+    #[serde(skip)]
+    path: PathBuf,
+    mailboxes: BTreeMap<String, MailboxState>,
+}
+
+pub fn state_key(server: &str, login: &str, mailbox: &str) -> String {
+    format!("{}|{}|{}", server, login, mailbox)
+}
+
+impl UidStore {
+    pub fn load(path: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut store = match fs::read_to_string(path) {
+            Ok(content) => serde_json::from_str::<UidStore>(&content)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => UidStore::default(),
+            Err(e) => return Err(e.into()),
+        };
+        store.path = path.to_path_buf();
+        Ok(store)
+    }
+
+    pub fn save(&self) -> Result<(), Box<dyn std::error::Error>> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        // Write next to the target and rename so an interrupted run never leaves half a file
+        let tmp_path = self.path.with_extension("json.tmp");
+        fs::write(&tmp_path, serde_json::to_string_pretty(self)?)?;
+        fs::rename(&tmp_path, &self.path)?;
+        Ok(())
+    }
+
+    pub fn get(&self, key: &str) -> Option<&MailboxState> {
+        self.mailboxes.get(key)
+    }
+
+    /// UID set that continues right after the last processed message and takes the failed ones
+    /// along, or everything when the mailbox has never been seen.
+    pub fn uid_set(&self, key: &str) -> String {
+        match self.get(key) {
+            Some(state) if !state.failed_uids.is_empty() => format!(
+                "{},{}:*",
+                compress_uid_set(&state.failed_uids),
+                state.last_uid + 1
+            ),
+            Some(state) => format!("{}:*", state.last_uid + 1),
+            None => "1:*".to_string(),
+        }
+    }
+
+    /// Last processed UID for the mailbox, or 0 when the server reports a different
+    /// UIDVALIDITY than the one we stored and a full resync is needed.
+    pub fn last_uid(&self, key: &str, uid_validity: u32) -> u32 {
+        match self.get(key) {
+            Some(state) if state.uid_validity == uid_validity => state.last_uid,
+            _ => 0,
+        }
+    }
+
+    /// UIDs that failed in an earlier run and have not been retried since, empty on a
+    /// UIDVALIDITY change like `last_uid`.
+    pub fn failed_uids(&self, key: &str, uid_validity: u32) -> Vec<u32> {
+        match self.get(key) {
+            Some(state) if state.uid_validity == uid_validity => state.failed_uids.clone(),
+            _ => Vec::new(),
+        }
+    }
+
+    /// Move the marker to `last_uid` and replace the failed UIDs with the ones that failed in
+    /// this run.
+    pub fn record(&mut self, key: &str, uid_validity: u32, last_uid: u32, failed_uids: &[u32]) {
+        let last_uid = match self.get(key) {
+            Some(state) if state.uid_validity == uid_validity => state.last_uid.max(last_uid),
+            _ => last_uid,
+        };
+        let mut failed_uids: Vec<u32> = failed_uids
+            .iter()
+            .copied()
+            .filter(|uid| *uid <= last_uid)
+            .collect();
+        failed_uids.sort_unstable();
+        failed_uids.dedup();
+        self.mailboxes.insert(
+            key.to_string(),
+            MailboxState {
+                uid_validity,
+                last_uid,
+                failed_uids,
+            },
+        );
+    }
+}
@@ -0,0 +1,78 @@
+use tempfile::tempdir;
+
+use crate::state::store::{state_key, MailboxState, UidStore};
+
+#[test]
+fn test_load_missing_file() {
+    let temp_dir = tempdir().unwrap();
+    let store = UidStore::load(&temp_dir.path().join("state.json")).unwrap();
+    let key = state_key("imap.example.com", "user@example.com", "INBOX");
+    assert_eq!(store.get(&key), None);
+    assert_eq!(store.uid_set(&key), "1:*");
+}
+
+#[test]
+fn test_save_and_load() {
+    let temp_dir = tempdir().unwrap();
+    let path = temp_dir.path().join("nested").join("state.json");
+    let key = state_key("imap.example.com", "user@example.com", "INBOX");
+    let mut store = UidStore::load(&path).unwrap();
+    store.record(&key, 42, 1000, &[]);
+    store.save().unwrap();
+
+    let loaded = UidStore::load(&path).unwrap();
+    assert_eq!(
+        loaded.get(&key),
+        Some(&MailboxState {
+            uid_validity: 42,
+            last_uid: 1000,
+            failed_uids: Vec::new(),
+        })
+    );
+    assert_eq!(loaded.uid_set(&key), "1001:*");
+}
+
+#[test]
+fn test_last_uid_resets_on_uid_validity_change() {
+    let temp_dir = tempdir().unwrap();
+    let key = state_key("imap.example.com", "user@example.com", "INBOX");
+    let mut store = UidStore::load(&temp_dir.path().join("state.json")).unwrap();
+    store.record(&key, 42, 1000, &[]);
+    assert_eq!(store.last_uid(&key, 42), 1000);
+    assert_eq!(store.last_uid(&key, 43), 0);
+}
+
+#[test]
+fn test_record() {
+    let temp_dir = tempdir().unwrap();
+    let key = state_key("imap.example.com", "user@example.com", "INBOX");
+    let mut store = UidStore::load(&temp_dir.path().join("state.json")).unwrap();
+    store.record(&key, 42, 1000, &[]);
+    // A lower UID with the same UIDVALIDITY never moves the marker back
+    store.record(&key, 42, 10, &[]);
+    assert_eq!(store.last_uid(&key, 42), 1000);
+    // A new UIDVALIDITY replaces the stored state
+    store.record(&key, 43, 10, &[]);
+    assert_eq!(store.last_uid(&key, 43), 10);
+}
+
+#[test]
+fn test_record_failed_uids() {
+    let temp_dir = tempdir().unwrap();
+    let path = temp_dir.path().join("state.json");
+    let key = state_key("imap.example.com", "user@example.com", "INBOX");
+    let mut store = UidStore::load(&path).unwrap();
+    store.record(&key, 42, 10, &[7, 3, 4]);
+    store.save().unwrap();
+    let store = UidStore::load(&path).unwrap();
+    assert_eq!(store.failed_uids(&key, 42), vec![3, 4, 7]);
+    assert_eq!(store.uid_set(&key), "3:4,7,11:*");
+    assert!(store.failed_uids(&key, 43).is_empty());
+
+    // The next run replaces them with whatever failed again
+    let mut store = store;
+    store.record(&key, 42, 12, &[4]);
+    assert_eq!(store.failed_uids(&key, 42), vec![4]);
+    store.record(&key, 42, 12, &[]);
+    assert_eq!(store.uid_set(&key), "13:*");
+}